@@ -0,0 +1,394 @@
+// export.rs
+// 問題文や結果を画像(PNG/SVG)に書き出す
+// 画面とは別の egui::Context で描き、PNG は CPU で三角形を塗り、SVG は図形をそのまま書き出す (GPU を使わない)
+
+use std::fmt::Write;
+use crate::model::{ResultModel, TypingStatus};
+use crate::parser::Content;
+use crate::textrender::{RenderLineWithRuby, RenderTypingLine, CharOrientation, Wrap};
+use crate::theme::Theme;
+
+/// 書き出しの余白
+const MARGIN: f32 = 40.0;
+/// PNG の解像度 (1ポイントあたりのピクセル数)
+const PIXELS_PER_POINT: f32 = 2.0;
+/// 本文の文字の大きさ
+const FONT_SIZE: f32 = 40.0;
+/// 1行に並べる文字数の目安 (これを超えると折り返す)
+const LINE_LENGTH: f32 = 30.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    Png,
+    Svg,
+}
+
+impl ExportFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Png => "png",
+            ExportFormat::Svg => "svg",
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            ExportFormat::Png => "PNG Image",
+            ExportFormat::Svg => "SVG Image",
+        }
+    }
+}
+
+/// 書き出すときの見た目 (画面のフォントと配色を引き継ぐ)
+#[derive(Clone)]
+pub struct ExportStyle {
+    pub fonts: egui::FontDefinitions,
+    pub theme: Theme,
+    pub orientation: CharOrientation,
+    pub ruby_scale: f32,
+}
+
+impl ExportStyle {
+    /// 画面で使っているフォントと配色から作る
+    pub fn from_context(ctx: &egui::Context, theme: &Theme, orientation: CharOrientation, ruby_scale: f32) -> Self {
+        Self {
+            fonts: ctx.fonts(|fonts| fonts.lock().fonts.definitions().clone()),
+            theme: theme.clone(),
+            orientation,
+            ruby_scale,
+        }
+    }
+
+    fn font(&self) -> egui::FontId {
+        egui::FontId::new(FONT_SIZE, egui::FontFamily::Proportional)
+    }
+
+    fn wrap(&self) -> Wrap {
+        Wrap { length: FONT_SIZE*LINE_LENGTH, rows: usize::MAX }
+    }
+}
+
+/// 問題文(タイトルと全ての行、読み付き)を書き出す
+pub fn export_content(content: &Content, style: &ExportStyle, format: ExportFormat) -> Result<Vec<u8>, String> {
+    render(style, format, |ui| {
+        let mut title_font = style.font();
+        title_font.size *= 1.5;
+        let title = RenderLineWithRuby::new(content.title.clone(), style.orientation.clone()).with_font(title_font).with_max(f32::INFINITY).with_theme(&style.theme).with_ruby_scale(style.ruby_scale);
+        let lines = content.lines.iter()
+            .map(|line| RenderLineWithRuby::new(line.clone(), style.orientation.clone()).with_font(style.font()).with_wrap(style.wrap()).with_max(f32::INFINITY).with_theme(&style.theme).with_ruby_scale(style.ruby_scale))
+            .collect::<Vec<_>>();
+        match style.orientation {
+            CharOrientation::Horizontal => {
+                ui.add(title);
+                ui.add_space(FONT_SIZE);
+                for line in lines {
+                    ui.add(line);
+                }
+            },
+            // 縦書きは右の行から読むので、最後の行から左に並べる
+            CharOrientation::Vertical => {
+                ui.with_layout(egui::Layout::left_to_right(egui::Align::Min), |ui| {
+                    for line in lines.into_iter().rev() {
+                        ui.add(line);
+                    }
+                    ui.add_space(FONT_SIZE);
+                    ui.add(title);
+                });
+            },
+        }
+    })
+}
+
+/// 結果(タイトル・成績・入力した行の正誤)を書き出す
+/// `metrics` は項目名と値の組
+pub fn export_result(result: &ResultModel, metrics: &[(String, String)], style: &ExportStyle, format: ExportFormat) -> Result<Vec<u8>, String> {
+    let model = &result.typing_model;
+    render(style, format, |ui| {
+        let mut title_font = style.font();
+        title_font.size *= 1.2;
+        ui.add(RenderLineWithRuby::new(model.content.title.clone(), CharOrientation::Horizontal).with_font(title_font).with_max(f32::INFINITY).with_theme(&style.theme).with_ruby_scale(style.ruby_scale));
+        ui.add_space(FONT_SIZE*0.5);
+        egui::Grid::new("export_metrics").num_columns(2).spacing([FONT_SIZE, FONT_SIZE*0.2]).show(ui, |ui| {
+            for (label, value) in metrics {
+                ui.label(egui::RichText::new(label).size(FONT_SIZE*0.5));
+                ui.label(egui::RichText::new(value).size(FONT_SIZE*0.5).color(style.theme.text));
+                ui.end_row();
+            }
+        });
+        ui.add_space(FONT_SIZE);
+        // 入力し終えた行と、途中まで入力した行
+        let typed_lines = model.content.lines.len().min(model.status.line.max(0) as usize + 1);
+        let lines = model.content.lines.iter().zip(model.typing_correctness.lines.iter()).take(typed_lines).enumerate()
+            .map(|(index, (line, correctness))| {
+                let status = if (index as i32) < model.status.line {
+                    TypingStatus { line: index as i32, segment: line.segments.len() as i32, char_: 0, unconfirmed: Vec::new(), last_wrong_keydown: None, segment_misses: 0 }
+                } else {
+                    TypingStatus { unconfirmed: Vec::new(), last_wrong_keydown: None, ..model.status.clone() }
+                };
+                RenderTypingLine::new(line.clone(), correctness.clone(), status, style.orientation.clone())
+                    .with_font(style.font())
+                    .with_wrap(style.wrap())
+                    .with_cursor(false)
+                    .with_theme(&style.theme).with_ruby_scale(style.ruby_scale)
+            })
+            .collect::<Vec<_>>();
+        match style.orientation {
+            CharOrientation::Horizontal => {
+                for line in lines {
+                    ui.add(line);
+                }
+            },
+            CharOrientation::Vertical => {
+                ui.with_layout(egui::Layout::left_to_right(egui::Align::Min), |ui| {
+                    for line in lines.into_iter().rev() {
+                        ui.add(line);
+                    }
+                });
+            },
+        }
+    })
+}
+
+/// 画面とは別の Context で `add_contents` を描き、指定の形式で書き出す
+fn render(style: &ExportStyle, format: ExportFormat, add_contents: impl Fn(&mut egui::Ui)) -> Result<Vec<u8>, String> {
+    let ctx = egui::Context::default();
+    ctx.set_fonts(style.fonts.clone());
+    ctx.set_visuals(style.theme.visuals());
+    ctx.style_mut(|s| {
+        s.animation_time = 0.0;
+        s.wrap_mode = Some(egui::TextWrapMode::Extend);
+    });
+    ctx.set_pixels_per_point(PIXELS_PER_POINT);
+    // 1回目でフォントと大きさが決まり、2回目で Area が表示される
+    let mut size = egui::vec2(16384.0, 16384.0);
+    let mut output = None;
+    for _ in 0..3 {
+        let input = egui::RawInput {
+            screen_rect: Some(egui::Rect::from_min_size(egui::Pos2::ZERO, size)),
+            max_texture_side: Some(8192),
+            ..Default::default()
+        };
+        let mut content_rect = egui::Rect::NOTHING;
+        let full_output = ctx.run(input, |ctx| {
+            ctx.layer_painter(egui::LayerId::background()).rect_filled(ctx.screen_rect(), 0.0, style.theme.background);
+            content_rect = egui::Area::new(egui::Id::new("export"))
+                .fixed_pos(egui::pos2(MARGIN, MARGIN))
+                .constrain(false)
+                .fade_in(false)
+                .show(ctx, |ui| ui.vertical(|ui| add_contents(ui)))
+                .response.rect;
+        });
+        size = (content_rect.max.to_vec2()+egui::vec2(MARGIN, MARGIN)).max(egui::vec2(1.0, 1.0));
+        output = Some(full_output);
+    }
+    let output = output.ok_or("Nothing was rendered")?;
+    match format {
+        ExportFormat::Png => {
+            let primitives = ctx.tessellate(output.shapes, output.pixels_per_point);
+            let atlas = ctx.fonts(|fonts| fonts.image());
+            encode_png(&rasterize(&primitives, &atlas, size, output.pixels_per_point))
+        },
+        ExportFormat::Svg => Ok(to_svg(&output.shapes, size, &style.fonts).into_bytes()),
+    }
+}
+
+//
+// PNG
+//
+
+/// 描画結果 (乗算済みアルファの色)
+struct Canvas {
+    width: usize,
+    height: usize,
+    pixels: Vec<egui::Color32>,
+}
+
+/// フォントのテクスチャ (乗算済みアルファの色)
+struct Texture {
+    size: [usize; 2],
+    pixels: Vec<egui::Color32>,
+}
+
+impl Texture {
+    /// UV座標(0〜1)の色を周りの4つの画素から補間して取る
+    fn sample(&self, uv: egui::Pos2) -> [f32; 4] {
+        let [width, height] = self.size;
+        let x = (uv.x*width as f32-0.5).clamp(0.0, (width-1) as f32);
+        let y = (uv.y*height as f32-0.5).clamp(0.0, (height-1) as f32);
+        let (x0, y0) = (x.floor() as usize, y.floor() as usize);
+        let (x1, y1) = ((x0+1).min(width-1), (y0+1).min(height-1));
+        let (fx, fy) = (x-x0 as f32, y-y0 as f32);
+        let texel = |x: usize, y: usize| self.pixels[y*width+x].to_array().map(|c| c as f32);
+        let lerp = |a: [f32; 4], b: [f32; 4], t: f32| [0, 1, 2, 3].map(|i| a[i]+(b[i]-a[i])*t);
+        lerp(lerp(texel(x0, y0), texel(x1, y0), fx), lerp(texel(x0, y1), texel(x1, y1), fx), fy)
+    }
+}
+
+/// 三角形の辺 a→b に対して p がどちら側にあるか (符号付き面積の2倍)
+fn edge(a: egui::Pos2, b: egui::Pos2, p: egui::Pos2) -> f32 {
+    (b.x-a.x)*(p.y-a.y)-(b.y-a.y)*(p.x-a.x)
+}
+
+/// 三角形メッシュを塗る (egui のシェーダーと同じく、頂点色とテクスチャを掛けて乗算済みアルファで重ねる)
+fn rasterize(primitives: &[egui::ClippedPrimitive], atlas: &egui::FontImage, size: egui::Vec2, pixels_per_point: f32) -> Canvas {
+    let width = (size.x*pixels_per_point).ceil() as usize;
+    let height = (size.y*pixels_per_point).ceil() as usize;
+    let mut canvas = Canvas { width, height, pixels: vec![egui::Color32::TRANSPARENT; width*height] };
+    let font = Texture { size: atlas.size, pixels: atlas.srgba_pixels(None).collect() };
+    for primitive in primitives {
+        let egui::epaint::Primitive::Mesh(mesh) = &primitive.primitive else {
+            continue;
+        };
+        let clip = primitive.clip_rect*pixels_per_point;
+        let textured = mesh.texture_id == egui::TextureId::default();
+        for triangle in mesh.indices.chunks_exact(3) {
+            let [a, b, c] = [0, 1, 2].map(|i| &mesh.vertices[triangle[i] as usize]);
+            let [pa, pb, pc] = [a, b, c].map(|v| (v.pos.to_vec2()*pixels_per_point).to_pos2());
+            let area = edge(pa, pb, pc);
+            if area.abs() < f32::EPSILON {
+                continue;
+            }
+            let bounds = egui::Rect::from_points(&[pa, pb, pc]).intersect(clip);
+            let x_range = (bounds.min.x.floor().max(0.0) as usize)..(bounds.max.x.ceil().min(width as f32).max(0.0) as usize);
+            let y_range = (bounds.min.y.floor().max(0.0) as usize)..(bounds.max.y.ceil().min(height as f32).max(0.0) as usize);
+            for y in y_range {
+                for x in x_range.clone() {
+                    let p = egui::pos2(x as f32+0.5, y as f32+0.5);
+                    let (wa, wb, wc) = (edge(pb, pc, p)/area, edge(pc, pa, p)/area, edge(pa, pb, p)/area);
+                    if wa < 0.0 || wb < 0.0 || wc < 0.0 {
+                        continue;
+                    }
+                    let color = [0, 1, 2, 3].map(|i| {
+                        a.color.to_array()[i] as f32*wa+b.color.to_array()[i] as f32*wb+c.color.to_array()[i] as f32*wc
+                    });
+                    let texel = if textured {
+                        font.sample((a.uv.to_vec2()*wa+b.uv.to_vec2()*wb+c.uv.to_vec2()*wc).to_pos2())
+                    } else {
+                        [255.0; 4]
+                    };
+                    let source = [0, 1, 2, 3].map(|i| color[i]*texel[i]/255.0);
+                    let target = &mut canvas.pixels[y*width+x];
+                    let dest = target.to_array();
+                    let blended = [0, 1, 2, 3].map(|i| (source[i]+dest[i] as f32*(1.0-source[3]/255.0)).round().clamp(0.0, 255.0) as u8);
+                    *target = egui::Color32::from_rgba_premultiplied(blended[0], blended[1], blended[2], blended[3]);
+                }
+            }
+        }
+    }
+    canvas
+}
+
+fn encode_png(canvas: &Canvas) -> Result<Vec<u8>, String> {
+    let pixels = canvas.pixels.iter().flat_map(|color| color.to_srgba_unmultiplied()).collect::<Vec<_>>();
+    let image = image::RgbaImage::from_raw(canvas.width as u32, canvas.height as u32, pixels).ok_or("Invalid image size")?;
+    let mut bytes = std::io::Cursor::new(Vec::new());
+    image.write_to(&mut bytes, image::ImageFormat::Png).map_err(|e| e.to_string())?;
+    Ok(bytes.into_inner())
+}
+
+//
+// SVG
+//
+
+/// 図形を SVG にする (文字は1文字ずつ text 要素にし、フォントは同じ名前のものを使う)
+fn to_svg(shapes: &[egui::epaint::ClippedShape], size: egui::Vec2, fonts: &egui::FontDefinitions) -> String {
+    let mut svg = String::new();
+    let _ = writeln!(svg, r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}">"#, w = size.x.ceil(), h = size.y.ceil());
+    for clipped in shapes {
+        write_shape(&mut svg, &clipped.shape, fonts);
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+fn write_shape(svg: &mut String, shape: &egui::Shape, fonts: &egui::FontDefinitions) {
+    match shape {
+        egui::Shape::Vec(shapes) => {
+            for shape in shapes {
+                write_shape(svg, shape, fonts);
+            }
+        },
+        egui::Shape::Rect(rect) => {
+            let _ = writeln!(svg, r#"<rect x="{}" y="{}" width="{}" height="{}" rx="{}" {} {}/>"#,
+                rect.rect.min.x, rect.rect.min.y, rect.rect.width(), rect.rect.height(), rect.corner_radius.nw,
+                paint("fill", rect.fill), stroke(rect.stroke.width, rect.stroke.color));
+        },
+        egui::Shape::Circle(circle) => {
+            let _ = writeln!(svg, r#"<circle cx="{}" cy="{}" r="{}" {} {}/>"#,
+                circle.center.x, circle.center.y, circle.radius, paint("fill", circle.fill), stroke(circle.stroke.width, circle.stroke.color));
+        },
+        egui::Shape::LineSegment { points, stroke: line } => {
+            let _ = writeln!(svg, r#"<line x1="{}" y1="{}" x2="{}" y2="{}" {}/>"#,
+                points[0].x, points[0].y, points[1].x, points[1].y, stroke(line.width, line.color));
+        },
+        egui::Shape::Path(path) => {
+            let points = path.points.iter().map(|p| format!("{},{}", p.x, p.y)).collect::<Vec<_>>().join(" ");
+            let color = match path.stroke.color {
+                egui::epaint::ColorMode::Solid(color) => color,
+                egui::epaint::ColorMode::UV(_) => egui::Color32::TRANSPARENT,
+            };
+            let _ = writeln!(svg, r#"<{} points="{}" {} {}/>"#,
+                if path.closed { "polygon" } else { "polyline" }, points,
+                paint("fill", if path.closed { path.fill } else { egui::Color32::TRANSPARENT }), stroke(path.stroke.width, color));
+        },
+        egui::Shape::Text(text) => write_text(svg, text, fonts),
+        _ => {},
+    }
+}
+
+fn write_text(svg: &mut String, text: &egui::epaint::TextShape, fonts: &egui::FontDefinitions) {
+    let transform = if text.angle != 0.0 {
+        format!(r#" transform="rotate({} {} {})""#, text.angle.to_degrees(), text.pos.x, text.pos.y)
+    } else {
+        String::new()
+    };
+    for glyph in text.galley.rows.iter().flat_map(|row| row.glyphs.iter()) {
+        if glyph.chr.is_whitespace() {
+            continue;
+        }
+        let format = &text.galley.job.sections[glyph.section_index as usize].format;
+        let mut color = text.override_text_color.unwrap_or(format.color);
+        if color == egui::Color32::PLACEHOLDER {
+            color = text.fallback_color;
+        }
+        let _ = writeln!(svg, r#"<text x="{}" y="{}" font-size="{}" font-family="{}" {}{}>{}</text>"#,
+            text.pos.x+glyph.pos.x, text.pos.y+glyph.pos.y, format.font_id.size, font_family(&format.font_id.family, fonts),
+            paint("fill", color.gamma_multiply(text.opacity_factor)), transform, escape(glyph.chr));
+    }
+}
+
+/// フォントファミリーに登録したフォント名を並べる
+fn font_family(family: &egui::FontFamily, fonts: &egui::FontDefinitions) -> String {
+    let mut names = fonts.families.get(family).cloned().unwrap_or_default().iter().map(|name| format!("'{}'", name)).collect::<Vec<_>>();
+    names.push("serif".to_string());
+    names.join(", ")
+}
+
+/// 色の属性 (透明なら none)
+fn paint(attribute: &str, color: egui::Color32) -> String {
+    if color.a() == 0 {
+        return format!(r#"{}="none""#, attribute);
+    }
+    let [r, g, b, a] = color.to_srgba_unmultiplied();
+    if a == 255 {
+        format!(r##"{}="#{:02x}{:02x}{:02x}""##, attribute, r, g, b)
+    } else {
+        format!(r##"{}="#{:02x}{:02x}{:02x}" {}-opacity="{:.3}""##, attribute, r, g, b, attribute, a as f32/255.0)
+    }
+}
+
+fn stroke(width: f32, color: egui::Color32) -> String {
+    if width <= 0.0 {
+        return r#"stroke="none""#.to_string();
+    }
+    format!(r#"{} stroke-width="{}""#, paint("stroke", color), width)
+}
+
+fn escape(ch: char) -> String {
+    match ch {
+        '&' => "&amp;".to_string(),
+        '<' => "&lt;".to_string(),
+        '>' => "&gt;".to_string(),
+        _ => ch.to_string(),
+    }
+}
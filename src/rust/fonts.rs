@@ -0,0 +1,156 @@
+// fonts.rs
+// フォントの設定 (ファミリーごとに使うフォント・文字の大きさ・読み込んだフォントファイル)
+
+use std::collections::BTreeMap;
+use serde::{Serialize, Deserialize};
+use crate::textrender::RUBY_SCALE;
+
+/// 同梱のフォント (名前と中身)
+pub const BUNDLED_FONTS: [(&str, &[u8]); 6] = [
+    ("KaiseiHarunoUmi", include_bytes!("../fonts/KaiseiHarunoUmi-Bold.ttf")),
+    ("Merienda", include_bytes!("../fonts/Merienda-Regular.ttf")),
+    ("Hurricane", include_bytes!("../fonts/Hurricane-Regular.ttf")),
+    ("YujiSyuku", include_bytes!("../fonts/YujiSyuku-Regular.ttf")),
+    ("ShipporiAntique", include_bytes!("../fonts/ShipporiAntique-Regular.ttf")),
+    ("NotoSerifJP", include_bytes!("../fonts/NotoSerifJP-VariableFont_wght.ttf")),
+];
+
+/// 設定で使うフォントを選べるファミリー
+pub const FAMILIES: [&str; 4] = ["main", "kana", "ruby", "app_title"];
+
+/// 設定に表示するファミリーの名前
+pub fn family_label(family: &str) -> &str {
+    match family {
+        "main" => "Text",
+        "kana" => "Kana",
+        "ruby" => "Ruby",
+        "app_title" => "App Title",
+        _ => family,
+    }
+}
+
+/// ファミリーごとの既定のフォント (前から順に使い、無い文字は後ろのフォントで表示する)
+fn default_family(family: &str) -> Vec<String> {
+    let names: &[&str] = match family {
+        "main" => &["Merienda", "YujiSyuku", "ShipporiAntique", "NotoSerifJP"],
+        "kana" => &["YujiSyuku"],
+        "ruby" => &["Merienda", "ShipporiAntique", "YujiSyuku", "KaiseiHarunoUmi", "NotoSerifJP"],
+        "app_title" => &["Hurricane"],
+        _ => &[],
+    };
+    names.iter().map(|name| name.to_string()).collect()
+}
+
+/// 読み込んだフォントファイル
+#[derive(Clone)]
+pub struct UserFont {
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+impl UserFont {
+    /// ファイル名(拡張子を除く)を名前にする
+    /// 同梱のフォントと同じ名前だと同梱のフォントを置き換えてしまうので、名前の後ろに印を付ける
+    /// TTF/OTF として読めないものは egui が描画時に止まってしまうので、ここで弾く
+    pub fn new(file_name: &str, data: Vec<u8>) -> Result<Self, String> {
+        ab_glyph::FontRef::try_from_slice(&data).map_err(|e| format!("{}: {}", file_name, e))?;
+        let mut name = std::path::Path::new(file_name).file_stem().and_then(|stem| stem.to_str()).unwrap_or(file_name).to_string();
+        if BUNDLED_FONTS.iter().any(|(bundled, _)| *bundled == name) {
+            name.push_str(" (User)");
+        }
+        Ok(UserFont { name, data })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FontSettings {
+    /// ファミリーごとに最初に使うフォントの名前 (指定の無いファミリーは既定のまま)
+    pub families: BTreeMap<String, String>,
+    /// 入力する文字の大きさの倍率
+    pub text_scale: f32,
+    /// 親文字に対する読みの大きさ
+    pub ruby_scale: f32,
+    /// 読み込んだフォントファイルの場所 (起動時に読み直す。Webではフォントの中身を "fonts.user" に保存する)
+    pub user_font_paths: Vec<String>,
+}
+
+impl Default for FontSettings {
+    fn default() -> Self {
+        FontSettings {
+            families: BTreeMap::new(),
+            text_scale: 1.0,
+            ruby_scale: RUBY_SCALE,
+            user_font_paths: Vec::new(),
+        }
+    }
+}
+
+/// 保存したフォントファイルを読み直す (読めなくなったものは飛ばす)
+#[cfg(not(target_arch = "wasm32"))]
+pub fn load_user_fonts(settings: &FontSettings) -> Vec<UserFont> {
+    settings.user_font_paths.iter()
+        .filter_map(|path| match std::fs::read(path).map_err(|e| e.to_string()).and_then(|data| UserFont::new(path, data)) {
+            Ok(font) => Some(font),
+            Err(e) => {
+                eprintln!("Font read error: {}", e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Webで保存したフォントを読み直す (ファイルの場所を覚えられないので、中身を保存している)
+#[cfg(target_arch = "wasm32")]
+pub fn load_stored_fonts() -> Vec<UserFont> {
+    crate::storage::load_json::<Vec<(String, String)>>("fonts.user").unwrap_or_default().into_iter()
+        .filter_map(|(name, data)| Some(UserFont { name, data: crate::storage::decode_base64(&data)? }))
+        .collect()
+}
+
+/// Webで読み込んだフォントの中身を保存する
+/// localStorage の容量を超えて保存できなかった場合はfalse
+#[cfg(target_arch = "wasm32")]
+pub fn store_fonts(fonts: &[UserFont]) -> bool {
+    let stored = fonts.iter()
+        .map(|font| (font.name.clone(), crate::storage::encode_base64(&font.data)))
+        .collect::<Vec<_>>();
+    let json = serde_json::to_string(&stored).unwrap_or_default();
+    crate::storage::save("fonts.user", &json);
+    crate::storage::load("fonts.user").as_deref() == Some(json.as_str())
+}
+
+/// 選べるフォントの名前 (同梱のものと読み込んだもの)
+pub fn font_names(user_fonts: &[UserFont]) -> Vec<String> {
+    BUNDLED_FONTS.iter().map(|(name, _)| name.to_string())
+        .chain(user_fonts.iter().map(|font| font.name.clone()))
+        .collect()
+}
+
+/// 設定からフォントの定義を作る
+pub fn font_definitions(settings: &FontSettings, user_fonts: &[UserFont]) -> egui::FontDefinitions {
+    let mut fonts = egui::FontDefinitions::default();
+    for (name, data) in BUNDLED_FONTS {
+        fonts.font_data.insert(name.to_owned(), egui::FontData::from_static(data).into());
+    }
+    for font in user_fonts {
+        fonts.font_data.insert(font.name.clone(), egui::FontData::from_owned(font.data.clone()).into());
+    }
+
+    // Configure the Proportional font family with NotoSerifJP
+    if let Some(proportional) = fonts.families.get_mut(&egui::FontFamily::Proportional) {
+        proportional.clear();
+        proportional.push("NotoSerifJP".to_owned());
+    }
+
+    // 選んだフォントを先頭にし、無い文字は既定のフォントで表示する
+    for family in FAMILIES {
+        let mut names = default_family(family);
+        if let Some(choice) = settings.families.get(family).filter(|name| fonts.font_data.contains_key(*name)) {
+            names.retain(|name| name != choice);
+            names.insert(0, choice.clone());
+        }
+        fonts.families.insert(egui::FontFamily::Name(family.into()), names);
+    }
+    fonts
+}
@@ -13,14 +13,21 @@ use rfd::AsyncFileDialog;
 
 use chrono::{Local, TimeZone};
 
-use crate::model::{Model, MenuModel, TypingStartModel, TypingModel, PauseModel, ResultModel, TypingStatus, TextConvert, ErrorMsg, KeyboardRemapping, TypingScroll,TypingSession};
-use crate::msg::{Msg, MenuMsg, TypingStartMsg, TypingMsg, PauseMsg, ResultMsg};
+use crate::model::{SpeedUnit, TypingOptions, SegmentDisplay, Dictation, TimeAttack, Survival, SessionOutcome, Model, MenuModel, TypingStartModel, TypingModel, PauseModel, ResultModel, TypingStatus, TextConvert, ErrorMsg, KeyboardRemapping, TypingScroll,TypingSession};
+use crate::msg::{Msg, MenuMsg, TypingStartMsg, TypingMsg, PauseMsg, ResultMsg, LayoutEditorMsg};
+use crate::layout::{parse_layout, layout_to_json};
 use crate::parser::{parse_problem, Content};
-use crate::typing::calculate_line_metrics;
 use crate::typing::calculate_total_metrics;
+use crate::typing::{calculate_line_rhythm, calculate_total_rhythm, line_latency};
+use crate::typing::{first_line, describe_selection, romaji_guide, mistake_lines, current_time, segment_display, line_display, memorization_score, time_remaining, lives_remaining};
+use crate::storage;
 use crate::update::update;
-use std::collections::HashMap;
-use crate::textrender::{RenderText, RenderLineWithRuby, RenderTypingLine, CharOrientation};
+use crate::textrender::{RenderText, RenderLineWithRuby, RenderTypingLine, CharOrientation, Wrap, CaretStyle};
+use crate::export::{ExportFormat, ExportStyle, export_content, export_result};
+use crate::theme::{Theme, builtin_themes, parse_theme};
+use crate::fonts::{FontSettings, UserFont, FAMILIES, family_label, font_names};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::fonts::load_user_fonts;
 #[cfg(target_arch = "wasm32")]
 use crate::jsapi;
 
@@ -29,10 +36,24 @@ use std::{sync::Mutex, sync::Once};
 // ファイル内容を一時的に保持するためのstatic変数
 #[cfg(target_arch = "wasm32")]
 static PENDING_CONTENTS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+// 読み込んだ配色ファイルの内容 (次のフレームで反映する)
+#[cfg(target_arch = "wasm32")]
+static PENDING_THEMES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+// 読み込んだフォントファイル
+#[cfg(target_arch = "wasm32")]
+static PENDING_FONTS: Mutex<Vec<UserFont>> = Mutex::new(Vec::new());
 #[cfg(target_arch = "wasm32")]
 static INIT: Once = Once::new();
 static FILEDIALOG: Mutex<bool> = Mutex::new(false); // フルスクリーンの時にフルスクリーン解除してからファイルダイアログを開く
 
+/// 滑らかにするとき、スクロールが入力位置との差を 1/e に縮めるまでの時間 (秒)
+const SCROLL_ANIMATION_TIME: f32 = 0.12;
+/// フォーカスモードで前後に表示できる行数の上限
+const FOCUS_MAX_CONTEXT: usize = 3;
+/// フォーカスモードで前後の行を表示する大きさ (入力中の行に対する倍率) と、隣の行の不透明度
+const FOCUS_FONT_SCALE: f32 = 0.6;
+const FOCUS_OPACITY: f32 = 0.5;
+
 use eframe::Frame;
 #[cfg(target_arch = "wasm32")]
 use wasm_bindgen::JsCast;
@@ -45,8 +66,22 @@ pub enum TextOrientation {
     Horizontal,
 }
 
+/// 結果画面の行ごとの表の並べ替え
+#[derive(Clone, Copy, PartialEq)]
+pub enum LineSortKey {
+    Line,
+    Speed,
+    Accuracy,
+    Misses,
+    Time,
+}
+
 pub struct TypingApp {
-    dark_mode: bool,
+    themes: Vec<Theme>, // 組み込みの配色と読み込んだ配色
+    theme: usize,
+    font_settings: FontSettings,
+    user_fonts: Vec<UserFont>, // 読み込んだフォントファイル
+    fonts_kept: bool, // 読み込んだフォントを次回の起動時にも使えるよう保存できたか
     init: bool,
     typing: Model,
     text_orientation: TextOrientation,
@@ -58,17 +93,49 @@ pub struct TypingApp {
     fps: f32,
     frame_count: u32,                  // Count of frames within the 1-second interval
     last_fps_update: Option<f64>, // Timestamp (in milliseconds) when the frame count was last reset
+    romaji_guide: bool,
+    speed_unit: SpeedUnit,
+    layout_editor_filter: String,
+    layout_editor_new_entry: String,
+    layout_editor_new_spelling: String,
+    line_sort: LineSortKey,
+    line_sort_descending: bool,
+    wrap_lines: bool,
+    caret_style: CaretStyle,
+    smooth_caret: bool,
+    scroll_line: i32, // スクロールを最後に合わせた行 (滑らかに動かすとき、行が変わったことを知るため)
+    focus_context: usize, // フォーカスモード: 入力中の行の前後に表示する行数 (0なら入力中の行だけ)
+    focus_hide_typed: bool, // フォーカスモードで入力を終えた行を表示しない
+    saved_review: u32, // 保存した復習の予定の記録回数
+    due_count: Option<(usize, usize)>, // メニューに入ったときに数えた (問題の数, 復習の時期が来た行数)
 }
 
 impl Default for TypingApp {
     fn default() -> Self {
-        let layout: Vec<(String, Vec<String>)> = serde_json::from_str::<HashMap<String, Vec<String>>>(&include_str!("../../layouts/japanese.json")).unwrap().into_iter().collect();
+        let layout = parse_layout(include_str!("../../layouts/japanese.json")).unwrap();
+        let mut themes = builtin_themes();
+        // 以前に組み込みの配色と同じ名前で保存されたものは選べないので除く
+        let user_themes = storage::load_json::<Vec<Theme>>("user_themes").unwrap_or_default().into_iter()
+            .filter(|user| themes.iter().all(|builtin| builtin.name != user.name))
+            .collect::<Vec<_>>();
+        themes.extend(user_themes);
+        let theme_name = storage::load_json::<String>("theme");
+        let theme = themes.iter().position(|theme| Some(&theme.name) == theme_name.as_ref()).unwrap_or(0);
+        let font_settings = storage::load_json::<FontSettings>("fonts").unwrap_or_default();
+        #[cfg(not(target_arch = "wasm32"))]
+        let user_fonts = load_user_fonts(&font_settings);
+        #[cfg(target_arch = "wasm32")]
+        let user_fonts = crate::fonts::load_stored_fonts();
         Self {
             init: false,
             text_orientation: TextOrientation::Vertical,
             // text_orientation: TextOrientation::Horizontal,
             selected_index: None,
-            dark_mode: true,
+            themes,
+            theme,
+            font_settings,
+            user_fonts,
+            fonts_kept: true,
             key_released: true,
             fullscreen: false,
             fullscreen_flag4filedialog: false,
@@ -76,6 +143,21 @@ impl Default for TypingApp {
             fps: 0.0,
             frame_count: 0,
             last_fps_update: None,   // Initialize with None.
+            romaji_guide: true,
+            speed_unit: SpeedUnit::KeysPerSecond,
+            layout_editor_filter: String::new(),
+            layout_editor_new_entry: String::new(),
+            layout_editor_new_spelling: String::new(),
+            line_sort: LineSortKey::Line,
+            line_sort_descending: false,
+            wrap_lines: false,
+            caret_style: CaretStyle::Bar,
+            smooth_caret: false,
+            scroll_line: 0,
+            focus_context: 0,
+            focus_hide_typed: false,
+            saved_review: 0,
+            due_count: None,
             typing: Model::Menu(
                 MenuModel {
                     available_contents: vec![
@@ -87,7 +169,10 @@ impl Default for TypingApp {
                     ],
                     selecting: 0,
                     error_messages: vec![],
-                    layout: TextConvert { mapping: layout },
+                    layout,
+                    preferences: storage::load_json("spelling_preferences").unwrap_or_default(),
+                    review: storage::load_json("review_schedule").unwrap_or_default(),
+                    options: TypingOptions::default(),
                 }
             ),
         }
@@ -96,6 +181,26 @@ impl Default for TypingApp {
 
 #[cfg(target_arch = "wasm32")]
 impl TypingApp {
+    fn handle_pending_fonts(&mut self, ctx: &egui::Context) {
+        let fonts = match PENDING_FONTS.try_lock() {
+            Ok(mut fonts) => fonts.drain(..).collect::<Vec<_>>(),
+            Err(_) => return,
+        };
+        for font in fonts {
+            self.add_font(ctx, font, None);
+        }
+    }
+
+    fn handle_pending_themes(&mut self, ctx: &egui::Context) {
+        let themes = match PENDING_THEMES.try_lock() {
+            Ok(mut themes) => themes.drain(..).collect::<Vec<_>>(),
+            Err(_) => return,
+        };
+        for json in themes {
+            self.add_theme(ctx, &json);
+        }
+    }
+
     fn handle_pending_contents(&mut self) {
         if let Ok(mut contents) = PENDING_CONTENTS.try_lock() {
             for content in contents.drain(..) {
@@ -125,10 +230,209 @@ impl TypingApp {
         }
         self.fullscreen = !self.fullscreen;
     }
+
+    /// 書き出しの見た目 (画面の文字の向きと配色を使う)
+    fn export_style(&self, ctx: &egui::Context) -> ExportStyle {
+        let orientation = match self.text_orientation {
+            TextOrientation::Vertical => CharOrientation::Vertical,
+            TextOrientation::Horizontal => CharOrientation::Horizontal,
+        };
+        ExportStyle::from_context(ctx, self.theme(), orientation, self.font_settings.ruby_scale)
+    }
+
+    /// 設定したフォントの定義
+    pub fn font_definitions(&self) -> egui::FontDefinitions {
+        crate::fonts::font_definitions(&self.font_settings, &self.user_fonts)
+    }
+
+    /// フォントの設定を反映する (保存は呼び出し側で値が決まったときに行う)
+    fn set_font_settings(&mut self, ctx: &egui::Context, settings: FontSettings) {
+        let families_changed = settings.families != self.font_settings.families;
+        self.font_settings = settings;
+        if families_changed {
+            ctx.set_fonts(self.font_definitions());
+        }
+    }
+
+    /// 読み込んだフォントを追加する (同じ名前のフォントは置き換える)
+    /// `path` はネイティブで次回の起動時に読み直すためのファイルの場所 (Webではフォントの中身を保存する)
+    fn add_font(&mut self, ctx: &egui::Context, font: UserFont, path: Option<String>) {
+        self.user_fonts.retain(|f| f.name != font.name);
+        self.user_fonts.push(font);
+        if let Some(path) = path {
+            if !self.font_settings.user_font_paths.contains(&path) {
+                self.font_settings.user_font_paths.push(path);
+            }
+        }
+        ctx.set_fonts(self.font_definitions());
+        storage::save_json("fonts", &self.font_settings);
+        #[cfg(target_arch = "wasm32")]
+        {
+            self.fonts_kept = crate::fonts::store_fonts(&self.user_fonts);
+        }
+    }
+
+    /// スクロールを入力位置 `target` へ近づける
+    /// 滑らかにするときは経過時間に応じた割合で近づけ、そうでなければ離れているときほど大きく動かす
+    fn scroll_toward(&self, now: f32, target: f32, dt: f32) -> f32 {
+        if self.smooth_caret {
+            now+(target-now)*(1.0-(-dt/SCROLL_ANIMATION_TIME).exp())
+        } else {
+            let d = target-now;
+            now+d* (d*d/(5000000.0+d*d))
+        }
+    }
+
+    /// フォーカスモード: 入力中の行の前後の行を小さく薄く表示する (離れた行ほど薄くする)
+    /// `edges` は入力中の行の表示の外側の端 (横書きでは上端と下端、縦書きでは右端と左端)
+    /// `start` は行の始まりを置く位置、`max` は行の進む方向の表示範囲
+    fn show_context_lines(&self, ctx: &egui::Context, scene: &TypingModel, font: &egui::FontId, edges: (f32, f32), start: f32, max: f32) {
+        let (before, after) = edges;
+        let theme = self.theme();
+        let ruby_scale = self.font_settings.ruby_scale;
+        let font = egui::FontId::new(font.size*FOCUS_FONT_SCALE, font.family.clone());
+        let step = font.size*(1.0+ruby_scale)*1.2;
+        let margin = font.size*0.3;
+        let orientation = match self.text_orientation {
+            TextOrientation::Horizontal => CharOrientation::Horizontal,
+            TextOrientation::Vertical => CharOrientation::Vertical,
+        };
+        for distance in 1..=self.focus_context {
+            let opacity = FOCUS_OPACITY/distance as f32;
+            let gap = margin+step*(distance-1) as f32;
+            // 入力を終えた行 (横書きでは上、縦書きでは右に並べる)
+            let line = scene.status.line-distance as i32;
+            if !self.focus_hide_typed && line >= 0 {
+                let index = line as usize;
+                let status = TypingStatus { line, segment: scene.content.lines[index].segments.len() as i32, char_: 0, unconfirmed: Vec::new(), last_wrong_keydown: None, segment_misses: 0 };
+                let (pivot, pos) = match self.text_orientation {
+                    TextOrientation::Horizontal => (egui::Align2::LEFT_BOTTOM, egui::pos2(0.0, before-gap)),
+                    TextOrientation::Vertical => (egui::Align2::LEFT_TOP, egui::pos2(before+gap, 0.0)),
+                };
+                egui::Area::new(egui::Id::new(("context_line", line))).pivot(pivot).fixed_pos(pos).interactable(false).show(ctx, |ui| {
+                    ui.add(RenderTypingLine::new(scene.content.lines[index].clone(), scene.typing_correctness.lines[index].clone(), status, orientation.clone()).with_theme(theme).with_ruby_scale(ruby_scale).with_font(font.clone()).with_dictation(scene.options.dictation).with_cursor(false).with_offset(-start).with_opacity(opacity));
+                });
+            }
+            // これから入力する行 (横書きでは下、縦書きでは左に並べる)
+            let line = scene.status.line+distance as i32;
+            if (line as usize) < scene.content.lines.len() {
+                let (pivot, pos) = match self.text_orientation {
+                    TextOrientation::Horizontal => (egui::Align2::LEFT_TOP, egui::pos2(0.0, after+gap)),
+                    TextOrientation::Vertical => (egui::Align2::RIGHT_TOP, egui::pos2(after-gap, 0.0)),
+                };
+                egui::Area::new(egui::Id::new(("context_line", line))).pivot(pivot).fixed_pos(pos).interactable(false).show(ctx, |ui| {
+                    ui.add(RenderLineWithRuby::new(scene.content.lines[line as usize].clone(), orientation.clone()).with_theme(theme).with_ruby_scale(ruby_scale).with_font(font.clone()).with_display(segment_display(scene, line)).with_offset(-start).with_max(max).with_opacity(opacity));
+                });
+            }
+        }
+    }
+
+    /// 復習の予定が記録されていれば保存する
+    fn save_review(&mut self) {
+        let review = match &self.typing {
+            Model::Menu(scene) => &scene.review,
+            Model::TypingStart(scene) => &scene.review,
+            Model::Typing(scene) => &scene.review,
+            Model::Pause(scene) => &scene.typing_model.review,
+            Model::Result(scene) => &scene.typing_model.review,
+            Model::LayoutEditor(scene) => &scene.review,
+        };
+        if review.revision != self.saved_review {
+            storage::save_json("review_schedule", review);
+            self.saved_review = review.revision;
+        }
+    }
+
+    fn theme(&self) -> &Theme {
+        &self.themes[self.theme]
+    }
+
+    fn select_theme(&mut self, ctx: &egui::Context, index: usize) {
+        self.theme = index;
+        ctx.set_visuals(self.theme().visuals());
+        storage::save_json("theme", &self.theme().name);
+    }
+
+    /// 配色ファイルを読み込んで使う (同じ名前の配色は置き換える)
+    fn add_theme(&mut self, ctx: &egui::Context, json: &str) {
+        let theme = match parse_theme(json) {
+            Ok(theme) => theme,
+            Err(e) => {
+                #[cfg(not(target_arch = "wasm32"))]
+                eprintln!("Theme error: {}", e);
+                #[cfg(target_arch = "wasm32")]
+                web_sys::console::log_1(&format!("Theme error: {}", e).into());
+                return;
+            }
+        };
+        let builtin = builtin_themes().len();
+        let index = match self.themes.iter().skip(builtin).position(|t| t.name == theme.name) {
+            Some(position) => {
+                self.themes[builtin+position] = theme;
+                builtin+position
+            },
+            None => {
+                self.themes.push(theme);
+                self.themes.len()-1
+            },
+        };
+        storage::save_json("user_themes", &self.themes[builtin..].to_vec());
+        self.select_theme(ctx, index);
+    }
+}
+
+/// 書き出した画像を保存する (ファイル名は `name` に拡張子を付ける)
+fn save_export(name: &str, format: ExportFormat, image: Result<Vec<u8>, String>) {
+    let image = match image {
+        Ok(image) => image,
+        Err(e) => {
+            #[cfg(not(target_arch = "wasm32"))]
+            eprintln!("Export error: {}", e);
+            #[cfg(target_arch = "wasm32")]
+            web_sys::console::log_1(&format!("Export error: {}", e).into());
+            return;
+        }
+    };
+    // ファイル名に使えない文字を置き換える
+    let name = name.chars().map(|c| if "\\/:*?\"<>|".contains(c) { '_' } else { c }).collect::<String>();
+    let file_name = format!("{}.{}", name, format.extension());
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        if let Some(path) = FileDialog::new().add_filter(format.label(), &[format.extension()]).set_file_name(&file_name).save_file() {
+            if let Err(e) = fs::write(&path, image) {
+                eprintln!("File write error: {}", e);
+            }
+        }
+    }
+    #[cfg(target_arch = "wasm32")]
+    {
+        wasm_bindgen_futures::spawn_local(async move {
+            if let Some(file) = AsyncFileDialog::new()
+                .add_filter(format.label(), &[format.extension()])
+                .set_file_name(&file_name)
+                .save_file()
+                .await
+            {
+                if file.write(&image).await.is_err() {
+                    web_sys::console::log_1(&"Failed to save the image.".into());
+                }
+            }
+        });
+    }
 }
 
 impl eframe::App for TypingApp {
     fn update(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
+        self.save_review();
+        if !matches!(self.typing, Model::Menu(_)) {
+            self.due_count = None;
+        }
+        // 読み込んだ配色ファイルを反映
+        #[cfg(target_arch = "wasm32")]
+        {
+            self.handle_pending_themes(ctx);
+            self.handle_pending_fonts(ctx);
+        }
         // Apply font scaling once
         if (!self.init) {
             let mut style = (*ctx.style()).clone();
@@ -137,7 +441,7 @@ impl eframe::App for TypingApp {
             }
             ctx.set_style(style);
             self.init = true;
-            ctx.set_visuals(egui::Visuals::dark());
+            ctx.set_visuals(self.theme().visuals());
             #[cfg(target_arch = "wasm32")]
             {
                 crate::jsapi::notify_start();
@@ -176,11 +480,20 @@ impl eframe::App for TypingApp {
         }
 
         let cursor_target: f32 = 0.3;
+        let theme = self.theme().clone();
+        let ruby_scale = self.font_settings.ruby_scale;
+
+        // 折り返すときの1行の長さと表示する行数
+        let wrap = match self.text_orientation {
+            TextOrientation::Horizontal => Wrap { length: window_width*0.9, rows: 2 },
+            TextOrientation::Vertical => Wrap { length: window_height*0.9, rows: 2 },
+        };
+        let wrap_margin = -wrap.length/0.9*0.05;
 
         let typing_font_size = match self.text_orientation {
             TextOrientation::Horizontal => (window_height/8.0).min(window_width/8.0),
             TextOrientation::Vertical => (window_width/8.0).min(window_height/8.0),
-        };
+        } * self.font_settings.text_scale;
 
         match self.typing.clone() {
             Model::Menu(scene) => {
@@ -191,11 +504,7 @@ impl eframe::App for TypingApp {
                     .max_width(270.0)
                     .frame(
                         egui::Frame {
-                            fill: if self.dark_mode {
-                                egui::Color32::from_rgb(6,12,22)
-                            } else {
-                                egui::Color32::from_rgb(237, 238, 222)
-                            },
+                            fill: theme.side_panel,
                             inner_margin: egui::Margin {
                                 left: 20,
                                 right: 20,
@@ -212,14 +521,114 @@ impl eframe::App for TypingApp {
                             self.toggle_fullscreen(ui);
                         }
                         ui.label("Color Theme");
-                        if ui.button(if self.dark_mode {"Dark"} else {"Light"}).clicked() {
-                            self.dark_mode = !self.dark_mode;
-                            let visuals = if self.dark_mode {
-                                egui::Visuals::dark()
-                            } else {
-                                egui::Visuals::light()
-                            };
-                            ctx.set_visuals(visuals);
+                        ui.horizontal(|ui| {
+                            if ui.button(&theme.name).on_hover_text_at_pointer("Switch to the next color theme").clicked() {
+                                self.select_theme(ctx, (self.theme + 1) % self.themes.len());
+                            }
+                            if ui.button("Load Theme").on_hover_text_at_pointer("Add a color theme from a JSON file").clicked() {
+                                #[cfg(not(target_arch = "wasm32"))]
+                                {
+                                    if let Some(path) = FileDialog::new().add_filter("Theme File", &["json"]).pick_file() {
+                                        match fs::read_to_string(&path) {
+                                            Ok(json) => self.add_theme(ctx, &json),
+                                            Err(e) => eprintln!("File read error: {}", e),
+                                        }
+                                    }
+                                }
+                                #[cfg(target_arch = "wasm32")]
+                                {
+                                    wasm_bindgen_futures::spawn_local(async move {
+                                        if let Some(file) = AsyncFileDialog::new()
+                                            .add_filter("Theme File", &["json"])
+                                            .pick_file()
+                                            .await
+                                        {
+                                            if let Ok(json) = String::from_utf8(file.read().await) {
+                                                if let Ok(mut themes) = PENDING_THEMES.lock() {
+                                                    themes.push(json);
+                                                }
+                                            } else {
+                                                web_sys::console::log_1(&"Invalid UTF-8 data.".into());
+                                            }
+                                        }
+                                    });
+                                }
+                            }
+                        });
+                        ui.label("Fonts");
+                        let names = font_names(&self.user_fonts);
+                        let mut settings = self.font_settings.clone();
+                        // スライダーは動かしている間も反映し、離したときにだけ保存する
+                        let mut save = false;
+                        egui::Grid::new("font_grid").num_columns(2).show(ui, |ui| {
+                            for family in FAMILIES {
+                                ui.label(family_label(family));
+                                let selected = settings.families.get(family).cloned();
+                                egui::ComboBox::from_id_salt(family)
+                                    .selected_text(selected.clone().unwrap_or("Default".to_string()))
+                                    .show_ui(ui, |ui| {
+                                        if ui.selectable_label(selected.is_none(), "Default").clicked() {
+                                            settings.families.remove(family);
+                                            save = true;
+                                        }
+                                        for name in &names {
+                                            if ui.selectable_label(selected.as_ref() == Some(name), name).clicked() {
+                                                settings.families.insert(family.to_string(), name.clone());
+                                                save = true;
+                                            }
+                                        }
+                                    });
+                                ui.end_row();
+                            }
+                            ui.label("Text Size");
+                            let text_size = ui.add(egui::Slider::new(&mut settings.text_scale, 0.5..=2.0));
+                            ui.end_row();
+                            ui.label("Ruby Size");
+                            let ruby_size = ui.add(egui::Slider::new(&mut settings.ruby_scale, 0.2..=0.6));
+                            ui.end_row();
+                            for slider in [text_size, ruby_size] {
+                                save |= slider.drag_stopped() || (slider.changed() && !slider.dragged());
+                            }
+                        });
+                        if settings != self.font_settings {
+                            self.set_font_settings(ctx, settings);
+                        }
+                        if save {
+                            storage::save_json("fonts", &self.font_settings);
+                        }
+                        if ui.button("Load Font").on_hover_text_at_pointer("Add a font from a TTF/OTF file").clicked() {
+                            #[cfg(not(target_arch = "wasm32"))]
+                            {
+                                if let Some(path) = FileDialog::new().add_filter("Font File", &["ttf", "otf"]).pick_file() {
+                                    let path = path.to_string_lossy().to_string();
+                                    match fs::read(&path).map_err(|e| e.to_string()).and_then(|data| UserFont::new(&path, data)) {
+                                        Ok(font) => self.add_font(ctx, font, Some(path)),
+                                        Err(e) => eprintln!("Font read error: {}", e),
+                                    }
+                                }
+                            }
+                            #[cfg(target_arch = "wasm32")]
+                            {
+                                wasm_bindgen_futures::spawn_local(async move {
+                                    if let Some(file) = AsyncFileDialog::new()
+                                        .add_filter("Font File", &["ttf", "otf"])
+                                        .pick_file()
+                                        .await
+                                    {
+                                        match UserFont::new(&file.file_name(), file.read().await) {
+                                            Ok(font) => {
+                                                if let Ok(mut fonts) = PENDING_FONTS.lock() {
+                                                    fonts.push(font);
+                                                }
+                                            }
+                                            Err(e) => web_sys::console::log_1(&format!("Font read error: {}", e).into()),
+                                        }
+                                    }
+                                });
+                            }
+                        }
+                        if !self.fonts_kept {
+                            ui.colored_label(ui.visuals().warn_fg_color, "Loaded fonts are too large to keep after reload");
                         }
                         ui.label("Text Orientation");
                         if ui.button(if self.text_orientation==TextOrientation::Vertical {"Vertical"} else {"Horizontal"}).clicked() {
@@ -230,6 +639,61 @@ impl eframe::App for TypingApp {
                                 self.text_orientation = TextOrientation::Vertical;
                             }
                         }
+                        ui.label("Line Wrapping");
+                        if ui.button(if self.wrap_lines {"On"} else {"Off"}).on_hover_text_at_pointer("Wrap long lines with Japanese line-breaking rules instead of scrolling").clicked() {
+                            self.wrap_lines = !self.wrap_lines;
+                        }
+                        ui.label("Focus Mode");
+                        ui.horizontal(|ui| {
+                            let label = match self.focus_context {
+                                0 => "Off".to_string(),
+                                1 => "1 line".to_string(),
+                                n => format!("{} lines", n),
+                            };
+                            if ui.button(label).on_hover_text_at_pointer("Show this many dimmed lines before and after the current line").clicked() {
+                                self.focus_context = (self.focus_context + 1) % (FOCUS_MAX_CONTEXT + 1);
+                            }
+                            if self.focus_context > 0 && ui.button(if self.focus_hide_typed {"Hide Typed"} else {"Dim Typed"}).on_hover_text_at_pointer("Hide the lines already typed and show only the upcoming ones").clicked() {
+                                self.focus_hide_typed = !self.focus_hide_typed;
+                            }
+                        });
+                        ui.label("Caret");
+                        ui.horizontal(|ui| {
+                            if ui.button(self.caret_style.label()).on_hover_text_at_pointer(self.caret_style.description()).clicked() {
+                                let index = CaretStyle::ALL.iter().position(|c| *c == self.caret_style).unwrap_or(0);
+                                self.caret_style = CaretStyle::ALL[(index + 1) % CaretStyle::ALL.len()];
+                            }
+                            if ui.button(if self.smooth_caret {"Smooth"} else {"Instant"}).on_hover_text_at_pointer("Glide the caret and the line to the typing position instead of jumping").clicked() {
+                                self.smooth_caret = !self.smooth_caret;
+                            }
+                        });
+                        ui.label("Speed Unit");
+                        if ui.button(self.speed_unit.label()).on_hover_text_at_pointer(self.speed_unit.description()).clicked() {
+                            let index = SpeedUnit::ALL.iter().position(|u| *u == self.speed_unit).unwrap_or(0);
+                            self.speed_unit = SpeedUnit::ALL[(index + 1) % SpeedUnit::ALL.len()];
+                        }
+                        ui.label("AFK Exclusion");
+                        let afk_thresholds = [None, Some(5000.0), Some(10000.0), Some(30000.0), Some(60000.0)];
+                        let afk_label = match scene.options.afk_threshold {
+                            Some(threshold) => format!("Gaps over {}s", threshold / 1000.0),
+                            None => "Off".to_string(),
+                        };
+                        if ui.button(afk_label).on_hover_text_at_pointer("Keystroke gaps longer than this are excluded from the active time").clicked() {
+                            let index = afk_thresholds.iter().position(|t| *t == scene.options.afk_threshold).unwrap_or(0);
+                            let options = TypingOptions {
+                                afk_threshold: afk_thresholds[(index + 1) % afk_thresholds.len()],
+                                ..scene.options.clone()
+                            };
+                            self.typing = update(self.typing.clone(), Msg::Menu(MenuMsg::SetOptions(options)));
+                        }
+                        ui.label("Romaji Guide");
+                        if ui.button(if self.romaji_guide {"On"} else {"Off"}).clicked() {
+                            self.romaji_guide = !self.romaji_guide;
+                        }
+                        ui.label("Keyboard Layout");
+                        if ui.button("Edit Layout").clicked() {
+                            self.typing = update(self.typing.clone(),Msg::Menu(MenuMsg::OpenLayoutEditor));
+                        }
                     });
 
                     egui::TopBottomPanel::bottom("bottom_panel")
@@ -237,11 +701,7 @@ impl eframe::App for TypingApp {
                     .max_height(window_height*0.3)
                     .frame(
                         egui::Frame {
-                            fill: if self.dark_mode {
-                                egui::Color32::from_rgb(6, 9, 15)
-                            } else {
-                                egui::Color32::from_rgb(237, 238, 222)
-                            },
+                            fill: theme.bottom_panel,
                             inner_margin: egui::Margin {
                                 left: 20,
                                 right: 20,
@@ -256,7 +716,14 @@ impl eframe::App for TypingApp {
                             if let Some(content) = scene.available_contents.get(idx) {
                                 let mut font = egui::FontSelection::Default.resolve(ui.style());
                                 font.size *= 1.5;
-                                ui.add(RenderLineWithRuby::new(content.title.clone(), CharOrientation::Horizontal).with_font(font).with_max(window_width));
+                                ui.add(RenderLineWithRuby::new(content.title.clone(), CharOrientation::Horizontal).with_theme(&theme).with_ruby_scale(ruby_scale).with_font(font).with_max(window_width));
+                                ui.horizontal(|ui| {
+                                    for format in [ExportFormat::Png, ExportFormat::Svg] {
+                                        if ui.button(format!("Export {}", format.extension().to_uppercase())).on_hover_text_at_pointer("Save the text with its readings as an image").clicked() {
+                                            save_export(&content.title.to_string(), format, export_content(content, &self.export_style(ctx), format));
+                                        }
+                                    }
+                                });
                                 let button_height = 40.0;
                                 let button_width = ui.available_width();
                                 // Allocate full available space
@@ -269,7 +736,7 @@ impl eframe::App for TypingApp {
                                             ui.set_width(ui.available_size().x);
                                             ui.with_layout(egui::Layout::top_down(egui::Align::Min), |ui| {
                                                 for line in content.lines.iter() {
-                                                    ui.add(RenderLineWithRuby::new(line.clone(), CharOrientation::Horizontal).with_max(window_width));
+                                                    ui.add(RenderLineWithRuby::new(line.clone(), CharOrientation::Horizontal).with_theme(&theme).with_ruby_scale(ruby_scale).with_max(window_width));
                                                 }
                                             });
                                         });
@@ -290,11 +757,7 @@ impl eframe::App for TypingApp {
                 egui::CentralPanel::default()
                     .frame(
                         egui::Frame {
-                            fill: if self.dark_mode {
-                                egui::Color32::from_rgb(6,5,10)
-                            } else {
-                                egui::Color32::from_rgb(243, 243, 253)
-                            },
+                            fill: theme.background,
                             inner_margin: egui::Margin {
                                 left: 20,
                                 right: 20,
@@ -407,6 +870,21 @@ impl eframe::App for TypingApp {
                             }
                         }
 
+                        // 復習の時期が来た行 (メニューに入ったときと問題を追加したときに数える)
+                        let due_count = match self.due_count {
+                            Some((contents, count)) if contents == scene.available_contents.len() => count,
+                            _ => {
+                                let count = scene.review.due_lines(&scene.available_contents, current_time()).len();
+                                self.due_count = Some((scene.available_contents.len(), count));
+                                count
+                            },
+                        };
+                        if ui.add_enabled(due_count > 0, egui::Button::new(format!("Review Due Lines ({})", due_count)))
+                            .on_hover_text_at_pointer("Practice the lines scheduled for review today")
+                            .clicked() {
+                            self.typing = update(self.typing.clone(),Msg::Menu(MenuMsg::StartReview));
+                        }
+
                         // Calculate common button size
                         let button_height = 40.0;
                         let button2_width = 130.0;
@@ -464,14 +942,12 @@ impl eframe::App for TypingApp {
             },
             Model::TypingStart(scene) => {
                 let content: &Content = &scene.content;
+                // 範囲や並べ替えを指定していても、実際に最初に出題される行を見せる
+                let first = first_line(&scene);
                 egui::CentralPanel::default()
                     .frame(
                         egui::Frame {
-                            fill: if self.dark_mode {
-                                egui::Color32::from_rgb(6,5,10)
-                            } else {
-                                egui::Color32::from_rgb(243, 243, 253)
-                            },
+                            fill: theme.background,
                             inner_margin: egui::Margin {
                                 left: 20,
                                 right: 20,
@@ -488,27 +964,31 @@ impl eframe::App for TypingApp {
                     egui::Area::new("centent_title".into())
                         .fixed_pos(egui::Pos2::new(window_width-typing_font_size*0.9, 0.0))
                         .show(ctx, |ui| {
-                            let line = RenderLineWithRuby::new(content.title.clone(), CharOrientation::Vertical).with_font(egui::FontId::new(typing_font_size*0.7, egui::FontFamily::Proportional)).with_max(window_height);
+                            let line = RenderLineWithRuby::new(content.title.clone(), CharOrientation::Vertical).with_theme(&theme).with_ruby_scale(ruby_scale).with_font(egui::FontId::new(typing_font_size*0.7, egui::FontFamily::Proportional)).with_max(window_height);
                             let scroll_to = line.calc_size(ui).0;
                             ui.add(line.with_offset(-window_height*0.5+scroll_to*0.5));
                         });
                     egui::Area::new("centered_text2".into())
                         .fixed_pos(egui::Pos2::new(window_width/2.0+typing_font_size*0.5, 0.0))
                         .show(ctx, |ui| {
-                            ui.add(RenderLineWithRuby::new(content.lines[0].clone(), CharOrientation::Vertical).with_font(font.clone()).with_offset(-window_height*cursor_target).with_max(window_height));
+                            if let Some(first) = &first {
+                                ui.add(RenderLineWithRuby::new(first.clone(), CharOrientation::Vertical).with_theme(&theme).with_ruby_scale(ruby_scale).with_font(font.clone()).with_display(line_display(first, &scene.options, |_| false)).with_offset(-window_height*cursor_target).with_max(window_height));
+                            }
                         });
                 } else {
                     egui::Area::new("content_title".into())
                         .fixed_pos(egui::Pos2::new(0.0, typing_font_size*0.1))
                         .show(ctx, |ui| {
-                            let line = RenderLineWithRuby::new(content.title.clone(), CharOrientation::Horizontal).with_font(egui::FontId::new(typing_font_size*0.7, egui::FontFamily::Proportional)).with_max(window_width);
+                            let line = RenderLineWithRuby::new(content.title.clone(), CharOrientation::Horizontal).with_theme(&theme).with_ruby_scale(ruby_scale).with_font(egui::FontId::new(typing_font_size*0.7, egui::FontFamily::Proportional)).with_max(window_width);
                             let scroll_to = line.calc_size(ui).0;
                             ui.add(line.with_offset(-window_width*0.5+scroll_to*0.5));
                         });
                     egui::Area::new("centered_text2".into())
                         .fixed_pos(egui::Pos2::new(0.0, window_height/2.0-typing_font_size*2.0))
                         .show(ctx, |ui| {
-                            ui.add(RenderLineWithRuby::new(content.lines[0].clone(), CharOrientation::Horizontal).with_font(font.clone()).with_offset(-window_width*cursor_target).with_max(window_width));
+                            if let Some(first) = &first {
+                                ui.add(RenderLineWithRuby::new(first.clone(), CharOrientation::Horizontal).with_theme(&theme).with_ruby_scale(ruby_scale).with_font(font.clone()).with_display(line_display(first, &scene.options, |_| false)).with_offset(-window_width*cursor_target).with_max(window_width));
+                            }
                         });
                 }
                 egui::Area::new("full_screen_overlay".into()) // オーバーレイ
@@ -524,7 +1004,7 @@ impl eframe::App for TypingApp {
                         ui.painter().rect_filled(
                             rect,
                             egui::Rounding::same(0),
-                            egui::Color32::from_rgba_premultiplied(0, 0, 0, 230),
+                            theme.overlay,
                         );
                         // Display overlay text in the center.
                         ui.painter().text(
@@ -532,9 +1012,111 @@ impl eframe::App for TypingApp {
                             egui::Align2::CENTER_CENTER,
                             "Press [Space] to Start",
                             egui::FontId::proportional(80.0),
-                            egui::Color32::WHITE,
+                            theme.overlay_text,
                         );
                     });
+                // このセッションの設定
+                egui::Area::new("session_options".into())
+                    .anchor(egui::Align2::CENTER_CENTER, egui::vec2(0.0, 120.0))
+                    .order(egui::Order::Tooltip)
+                    .show(ctx, |ui| {
+                        let mut options = scene.options.clone();
+                        ui.horizontal(|ui| {
+                            ui.label(egui::RichText::new("Karuta").color(theme.overlay_text));
+                            if ui.button(if options.karuta.is_some() {"On"} else {"Off"}).on_hover_text_at_pointer("Hide the latter part of each line and type it from memory").clicked() {
+                                options.karuta = match options.karuta {
+                                    Some(_) => None,
+                                    None => Some(1),
+                                };
+                            }
+                            if let Some(shown) = options.karuta.as_mut() {
+                                ui.label(egui::RichText::new("Shown Segments").color(theme.overlay_text));
+                                ui.add(egui::DragValue::new(shown).range(0..=20));
+                            }
+                        });
+                        let line_count = scene.content.lines.len();
+                        ui.horizontal(|ui| {
+                            ui.label(egui::RichText::new("Lines").color(theme.overlay_text));
+                            let mut use_range = options.selection.range.is_some();
+                            if ui.checkbox(&mut use_range, egui::RichText::new("Range").color(theme.overlay_text)).changed() {
+                                options.selection.range = if use_range { Some((0, line_count)) } else { None };
+                            }
+                            if let Some((start, end)) = options.selection.range.as_mut() {
+                                // 表示は1始まりで両端を含む
+                                let mut from = *start + 1;
+                                let mut to = *end;
+                                ui.add(egui::DragValue::new(&mut from).range(1..=line_count));
+                                ui.label(egui::RichText::new("-").color(theme.overlay_text));
+                                ui.add(egui::DragValue::new(&mut to).range(from..=line_count));
+                                *start = from - 1;
+                                *end = to.max(from);
+                            }
+                            let mut use_sample = options.selection.sample.is_some();
+                            if ui.checkbox(&mut use_sample, egui::RichText::new("Random Subset").color(theme.overlay_text)).changed() {
+                                options.selection.sample = if use_sample { Some(line_count.min(10)) } else { None };
+                            }
+                            if let Some(sample) = options.selection.sample.as_mut() {
+                                ui.add(egui::DragValue::new(sample).range(1..=line_count));
+                            }
+                        });
+                        ui.horizontal(|ui| {
+                            ui.checkbox(&mut options.selection.shuffle, egui::RichText::new("Shuffle").color(theme.overlay_text));
+                            ui.label(egui::RichText::new("Seed").color(theme.overlay_text));
+                            ui.add(egui::DragValue::new(&mut options.selection.seed));
+                            if ui.button("New Seed").clicked() {
+                                options.selection.seed = current_time() as u64 % 100000;
+                            }
+                        });
+                        ui.horizontal(|ui| {
+                            ui.label(egui::RichText::new("Time Attack").color(theme.overlay_text));
+                            let durations = [None, Some(60000.0), Some(180000.0)];
+                            let duration = options.time_attack.map(|t| t.duration);
+                            let label = match duration {
+                                Some(duration) => format!("{}s", duration / 1000.0),
+                                None => "Off".to_string(),
+                            };
+                            if ui.button(label).on_hover_text_at_pointer("Repeat the lines until the time runs out").clicked() {
+                                let index = durations.iter().position(|d| *d == duration).unwrap_or(0);
+                                let shuffle = options.time_attack.is_some_and(|t| t.shuffle);
+                                options.time_attack = durations[(index + 1) % durations.len()].map(|duration| TimeAttack { duration, shuffle });
+                            }
+                            if let Some(time_attack) = options.time_attack.as_mut() {
+                                ui.checkbox(&mut time_attack.shuffle, egui::RichText::new("Shuffle").color(theme.overlay_text));
+                            }
+                        });
+                        ui.horizontal(|ui| {
+                            ui.label(egui::RichText::new("Survival").color(theme.overlay_text));
+                            if ui.button(if options.survival.is_some() {"On"} else {"Off"}).on_hover_text_at_pointer("Fail the run once the allowed misses are exceeded").clicked() {
+                                options.survival = match options.survival {
+                                    Some(_) => None,
+                                    None => Some(Survival { lives: 5, consecutive: false }),
+                                };
+                            }
+                            if let Some(survival) = options.survival.as_mut() {
+                                ui.label(egui::RichText::new("Lives").color(theme.overlay_text));
+                                ui.add(egui::DragValue::new(&mut survival.lives).range(0..=100));
+                                ui.checkbox(&mut survival.consecutive, egui::RichText::new("Consecutive Misses").color(theme.overlay_text));
+                            }
+                        });
+                        ui.horizontal(|ui| {
+                            ui.label(egui::RichText::new("Dictation").color(theme.overlay_text));
+                            let (label, hover) = match options.dictation {
+                                None => ("Off", "Show the text with its reading"),
+                                Some(Dictation::BaseOnly) => ("Base Only", "Show only the kanji, without the reading"),
+                                Some(Dictation::Hidden) => ("Hidden", "Show nothing until it is typed"),
+                            };
+                            if ui.button(label).on_hover_text_at_pointer(hover).clicked() {
+                                options.dictation = match options.dictation {
+                                    None => Some(Dictation::BaseOnly),
+                                    Some(Dictation::BaseOnly) => Some(Dictation::Hidden),
+                                    Some(Dictation::Hidden) => None,
+                                };
+                            }
+                        });
+                        if options != scene.options {
+                            self.typing = update(self.typing.clone(),Msg::TypingStart(TypingStartMsg::SetOptions(options)));
+                        }
+                    });
                     ctx.input(|i| {
                         for event in &i.events {
                             match event {
@@ -568,11 +1150,7 @@ impl eframe::App for TypingApp {
                 egui::CentralPanel::default()
                     .frame(
                         egui::Frame {
-                            fill: if self.dark_mode {
-                                egui::Color32::from_rgb(6,5,10)
-                            } else {
-                                egui::Color32::from_rgb(243, 243, 253)
-                            },
+                            fill: theme.background,
                             inner_margin: egui::Margin {
                                 left: 20,
                                 right: 20,
@@ -589,6 +1167,13 @@ impl eframe::App for TypingApp {
                     TextOrientation::Horizontal => window_width,
                     TextOrientation::Vertical => window_height,
                 };
+                // 滑らかにするときは、行が変わっても前の行の末尾から飛ばさず、新しい行の先頭をカーソルの位置に置く
+                let scroll = if self.smooth_caret && scene.status.line != self.scroll_line {
+                    -scrollmax*cursor_target
+                } else {
+                    scene.scroll.scroll as f32
+                };
+                self.scroll_line = scene.status.line;
 
                 // リアルタイムステータス表示を左下に配置
                 let stat = calculate_total_metrics(&scene);
@@ -607,7 +1192,7 @@ impl eframe::App for TypingApp {
                             .body(|mut body| {
                                 body.row(30.0, |mut row| {
                                     row.col(|ui| { ui.label("Speed"); });
-                                    row.col(|ui| { ui.label(format!("{:.3} KPS", stat.speed)); });
+                                    row.col(|ui| { ui.label(format!("{:.3} {}", stat.speed_in(self.speed_unit), self.speed_unit.label())); });
                                 });
                                 body.row(30.0, |mut row| {
                                     row.col(|ui| { ui.label("Accuracy"); });
@@ -629,30 +1214,87 @@ impl eframe::App for TypingApp {
                                     row.col(|ui| { ui.label("Time"); });
                                     row.col(|ui| { ui.label(format!("{:02.0}:{:02.0}:{:05.2}", hours, minutes, seconds)); });
                                 });
+                                if let Some(lives) = lives_remaining(&scene) {
+                                    body.row(30.0, |mut row| {
+                                        row.col(|ui| { ui.label("Lives"); });
+                                        row.col(|ui| { ui.strong(format!("{}", lives.max(0))); });
+                                    });
+                                }
+                                if let Some(remaining) = time_remaining(&scene, current_time()) {
+                                    let remaining_seconds = remaining / 1000.0;
+                                    body.row(30.0, |mut row| {
+                                        row.col(|ui| { ui.label("Remaining"); });
+                                        row.col(|ui| { ui.strong(format!("{:02.0}:{:05.2}", (remaining_seconds / 60.0).floor(), remaining_seconds % 60.0)); });
+                                    });
+                                }
                             });
                     });
 
+                // ローマ字ガイド (現在位置から行末まで)
+                if self.romaji_guide {
+                    // 隠しているセグメントや読みを見せないセグメントのガイドは出さない
+                    let display = segment_display(&scene, scene.status.line);
+                    let remaining = content.lines[scene.status.line as usize].segments.iter()
+                        .zip(display.iter())
+                        .skip(scene.status.segment as usize)
+                        .take_while(|(_, display)| **display == SegmentDisplay::Full)
+                        .map(|(segment, _)| segment)
+                        .enumerate()
+                        .map(|(i, segment)| {
+                            let text = match segment {
+                                crate::parser::Segment::Plain { text } => text,
+                                crate::parser::Segment::Annotated { base: _, reading } => reading,
+                            };
+                            let skip = if i == 0 { scene.status.char_ as usize } else { 0 };
+                            text.chars().skip(skip).collect::<String>()
+                        })
+                        .collect::<String>();
+                    let guide = romaji_guide(&remaining, &scene.layout, &scene.preferences);
+                    let unconfirmed = scene.status.unconfirmed.iter().collect::<String>();
+                    let guide = guide.strip_prefix(unconfirmed.as_str()).unwrap_or(&guide).to_string();
+                    egui::Area::new("romaji_guide".into())
+                        .anchor(egui::Align2::CENTER_BOTTOM, egui::vec2(0.0, -30.0))
+                        .interactable(false)
+                        .show(ctx, |ui| {
+                            ui.label(
+                                egui::RichText::new(guide)
+                                    .monospace()
+                                    .size(typing_font_size*0.3)
+                                    .color(ui.style().visuals.weak_text_color()),
+                            );
+                        });
+                }
+
                 if self.text_orientation == TextOrientation::Vertical {
                     egui::Area::new("centent_title".into())
                         .fixed_pos(egui::Pos2::new(window_width-typing_font_size*0.9, 0.0))
                         .show(ctx, |ui| {
-                            let line = RenderLineWithRuby::new(content.title.clone(), CharOrientation::Vertical).with_font(egui::FontId::new(typing_font_size*0.7, egui::FontFamily::Proportional)).with_max(window_height);
+                            let line = RenderLineWithRuby::new(content.title.clone(), CharOrientation::Vertical).with_theme(&theme).with_ruby_scale(ruby_scale).with_font(egui::FontId::new(typing_font_size*0.7, egui::FontFamily::Proportional)).with_max(window_height);
                             let scroll_to = line.calc_size(ui).0;
                             ui.add(line.with_offset(-window_height*0.5+scroll_to*0.5));
                         });
                     egui::Area::new("centered_text2".into())
                         .fixed_pos(egui::Pos2::new(window_width/2.0+typing_font_size*0.5, 0.0))
                         .show(ctx, |ui| {
-                            ui.add(RenderLineWithRuby::new(content.lines[scene.status.line as usize].clone(), CharOrientation::Vertical).with_font(font.clone()).with_offset(scene.scroll.scroll as f32).with_max(window_height));
+                            let line = RenderLineWithRuby::new(content.lines[scene.status.line as usize].clone(), CharOrientation::Vertical).with_theme(&theme).with_ruby_scale(ruby_scale).with_font(font.clone()).with_display(segment_display(&scene, scene.status.line)).with_max(window_height);
+                            if self.wrap_lines {
+                                ui.add(line.with_wrap(wrap).with_focus(scene.status.segment as usize).with_offset(wrap_margin));
+                            } else {
+                                ui.add(line.with_offset(scroll));
+                            }
                         });
+                    // 折り返すときは行が左へ増えるので右端を揃える
                     egui::Area::new("centered_text1".into())
-                        .fixed_pos(egui::Pos2::new(window_width/2.0-typing_font_size*1.0, 0.0))
+                        .pivot(if self.wrap_lines { egui::Align2::RIGHT_TOP } else { egui::Align2::LEFT_TOP })
+                        .fixed_pos(egui::Pos2::new(if self.wrap_lines { window_width/2.0+typing_font_size*0.3 } else { window_width/2.0-typing_font_size*1.0 }, 0.0))
                         .show(ctx, |ui| {
-                            let line = RenderTypingLine::new(content.lines[scene.status.line as usize].clone(), scene.typing_correctness.lines[scene.status.line as usize].clone(), scene.status.clone(), CharOrientation::Vertical).with_font(font.clone()).with_offset(scene.scroll.scroll as f32);
+                            let line = RenderTypingLine::new(content.lines[scene.status.line as usize].clone(), scene.typing_correctness.lines[scene.status.line as usize].clone(), scene.status.clone(), CharOrientation::Vertical).with_theme(&theme).with_ruby_scale(ruby_scale).with_font(font.clone()).with_dictation(scene.options.dictation).with_offset(scroll).with_caret(self.caret_style).with_smooth(self.smooth_caret);
+                            if self.wrap_lines {
+                                ui.add(line.with_wrap(wrap).with_offset(wrap_margin));
+                                return;
+                            }
                             let scrollto = line.calc_size(ui).0-window_height*cursor_target;
-                            let now = scene.scroll.scroll as f32;
-                            let d = scrollto-now;
-                            let new = now+d* (d*d/(5000000.0+d*d));
+                            let new = self.scroll_toward(scroll, scrollto, ctx.input(|i| i.stable_dt));
                             self.typing = update(self.typing.clone(),Msg::Typing(TypingMsg::ScrollTo(new as f64, -scrollmax as f64)));
                             ui.add(line);
                         });
@@ -660,27 +1302,53 @@ impl eframe::App for TypingApp {
                     egui::Area::new("centered_text1".into())
                     .fixed_pos(egui::Pos2::new(0.0, window_height/2.-typing_font_size*0.5))
                         .show(ctx, |ui| {
-                            let line = RenderTypingLine::new(content.lines[scene.status.line as usize].clone(), scene.typing_correctness.lines[scene.status.line as usize].clone(), scene.status.clone(), CharOrientation::Horizontal).with_font(font.clone()).with_offset(scene.scroll.scroll as f32);
+                            let line = RenderTypingLine::new(content.lines[scene.status.line as usize].clone(), scene.typing_correctness.lines[scene.status.line as usize].clone(), scene.status.clone(), CharOrientation::Horizontal).with_theme(&theme).with_ruby_scale(ruby_scale).with_font(font.clone()).with_dictation(scene.options.dictation).with_offset(scroll).with_caret(self.caret_style).with_smooth(self.smooth_caret);
+                            if self.wrap_lines {
+                                ui.add(line.with_wrap(wrap).with_offset(wrap_margin));
+                                return;
+                            }
                             let scrollto = line.calc_size(ui).0-window_width*cursor_target;
-                            let now = scene.scroll.scroll as f32;
-                            let d = scrollto-now;
-                            let new = now+d* (d*d/(5000000.0+d*d));
+                            let new = self.scroll_toward(scroll, scrollto, ctx.input(|i| i.stable_dt));
                             self.typing = update(self.typing.clone(),Msg::Typing(TypingMsg::ScrollTo(new as f64, -scrollmax as f64)));
                             ui.add(line);
                         });
                     egui::Area::new("content_title".into())
                         .fixed_pos(egui::Pos2::new(0.0, typing_font_size*0.1))
                         .show(ctx, |ui| {
-                            let line = RenderLineWithRuby::new(content.title.clone(), CharOrientation::Horizontal).with_font(egui::FontId::new(typing_font_size*0.7, egui::FontFamily::Proportional)).with_max(window_width);
+                            let line = RenderLineWithRuby::new(content.title.clone(), CharOrientation::Horizontal).with_theme(&theme).with_ruby_scale(ruby_scale).with_font(egui::FontId::new(typing_font_size*0.7, egui::FontFamily::Proportional)).with_max(window_width);
                             let scroll_to = line.calc_size(ui).0;
                             ui.add(line.with_offset(-window_width*0.5+scroll_to*0.5));
                         });
+                    // 折り返すときは行が下へ増えるので下端を揃える
                     egui::Area::new("centered_text2".into())
-                    .fixed_pos(egui::Pos2::new(0.0, window_height/2.0-typing_font_size*2.0))
+                    .pivot(if self.wrap_lines { egui::Align2::LEFT_BOTTOM } else { egui::Align2::LEFT_TOP })
+                    .fixed_pos(egui::Pos2::new(0.0, if self.wrap_lines { window_height/2.0-typing_font_size*0.4 } else { window_height/2.0-typing_font_size*2.0 }))
                         .show(ctx, |ui| {
-                            ui.add(RenderLineWithRuby::new(content.lines[scene.status.line as usize].clone(), CharOrientation::Horizontal).with_font(font.clone()).with_offset(scene.scroll.scroll as f32).with_max(window_width));
+                            let line = RenderLineWithRuby::new(content.lines[scene.status.line as usize].clone(), CharOrientation::Horizontal).with_theme(&theme).with_ruby_scale(ruby_scale).with_font(font.clone()).with_display(segment_display(&scene, scene.status.line)).with_max(window_width);
+                            if self.wrap_lines {
+                                ui.add(line.with_wrap(wrap).with_focus(scene.status.segment as usize).with_offset(wrap_margin));
+                            } else {
+                                ui.add(line.with_offset(scroll));
+                            }
                         });
                 }
+                if self.focus_context > 0 {
+                    // 入力中の行 (手本と入力) の外側の端
+                    let block = typing_font_size*(1.0+ruby_scale);
+                    let rows = if self.wrap_lines { wrap.rows as f32 } else { 1.0 };
+                    match self.text_orientation {
+                        TextOrientation::Horizontal => {
+                            let before = if self.wrap_lines { window_height/2.0-typing_font_size*0.4-block*rows } else { window_height/2.0-typing_font_size*2.0 };
+                            let after = window_height/2.0-typing_font_size*0.5+block*rows;
+                            self.show_context_lines(ctx, &scene, &font, (before, after), window_width*cursor_target, window_width);
+                        },
+                        TextOrientation::Vertical => {
+                            let before = window_width/2.0+typing_font_size*0.5+block*rows;
+                            let after = if self.wrap_lines { window_width/2.0+typing_font_size*0.3-block*rows } else { window_width/2.0-typing_font_size*1.0 };
+                            self.show_context_lines(ctx, &scene, &font, (before, after), window_height*cursor_target, window_height);
+                        },
+                    }
+                }
                 ctx.input(|i| {
                     for event in &i.events {
                         match event {
@@ -702,6 +1370,10 @@ impl eframe::App for TypingApp {
                         }
                     }
                 });
+                // タイムアタックの時間切れを確認 (切れたときだけモデルを更新する)
+                if time_remaining(&scene, current_time()) == Some(0.0) {
+                    self.typing = update(self.typing.clone(),Msg::Typing(TypingMsg::Tick));
+                }
                 // フォーカスが外れたらPause画面
                 if !ctx.input(|i| i.viewport().focused).unwrap_or(true) {
                     self.typing = update(self.typing.clone(),Msg::Typing(TypingMsg::Pause));
@@ -712,11 +1384,7 @@ impl eframe::App for TypingApp {
                 egui::CentralPanel::default()
                     .frame(
                         egui::Frame {
-                            fill: if self.dark_mode {
-                                egui::Color32::from_rgb(6,5,10)
-                            } else {
-                                egui::Color32::from_rgb(243, 243, 253)
-                            },
+                            fill: theme.background,
                             inner_margin: egui::Margin {
                                 left: 20,
                                 right: 20,
@@ -751,7 +1419,7 @@ impl eframe::App for TypingApp {
                             .body(|mut body| {
                                 body.row(30.0, |mut row| {
                                     row.col(|ui| { ui.label("Speed"); });
-                                    row.col(|ui| { ui.label(format!("{:.3} KPS", stat.speed)); });
+                                    row.col(|ui| { ui.label(format!("{:.3} {}", stat.speed_in(self.speed_unit), self.speed_unit.label())); });
                                 });
                                 body.row(30.0, |mut row| {
                                     row.col(|ui| { ui.label("Accuracy"); });
@@ -780,19 +1448,29 @@ impl eframe::App for TypingApp {
                     egui::Area::new("centent_title".into())
                         .fixed_pos(egui::Pos2::new(window_width-typing_font_size*0.9, 0.0))
                         .show(ctx, |ui| {
-                            let line = RenderLineWithRuby::new(content.title.clone(), CharOrientation::Vertical).with_font(egui::FontId::new(typing_font_size*0.7, egui::FontFamily::Proportional)).with_max(window_height);
+                            let line = RenderLineWithRuby::new(content.title.clone(), CharOrientation::Vertical).with_theme(&theme).with_ruby_scale(ruby_scale).with_font(egui::FontId::new(typing_font_size*0.7, egui::FontFamily::Proportional)).with_max(window_height);
                             let scroll_to = line.calc_size(ui).0;
                             ui.add(line.with_offset(-window_height*0.5+scroll_to*0.5));
                         });
                     egui::Area::new("centered_text2".into())
                         .fixed_pos(egui::Pos2::new(window_width/2.0+typing_font_size*0.5, 0.0))
                         .show(ctx, |ui| {
-                            ui.add(RenderLineWithRuby::new(content.lines[scene.typing_model.status.line as usize].clone(), CharOrientation::Vertical).with_font(font.clone()).with_offset(scene.typing_model.scroll.scroll as f32).with_max(window_height));
+                            let line = RenderLineWithRuby::new(content.lines[scene.typing_model.status.line as usize].clone(), CharOrientation::Vertical).with_theme(&theme).with_ruby_scale(ruby_scale).with_font(font.clone()).with_max(window_height);
+                            if self.wrap_lines {
+                                ui.add(line.with_wrap(wrap).with_focus(scene.typing_model.status.segment as usize).with_offset(wrap_margin));
+                            } else {
+                                ui.add(line.with_offset(scene.typing_model.scroll.scroll as f32));
+                            }
                         });
                     egui::Area::new("centered_text1".into())
-                        .fixed_pos(egui::Pos2::new(window_width/2.0-typing_font_size*1.0, 0.0))
+                        .pivot(if self.wrap_lines { egui::Align2::RIGHT_TOP } else { egui::Align2::LEFT_TOP })
+                        .fixed_pos(egui::Pos2::new(if self.wrap_lines { window_width/2.0+typing_font_size*0.3 } else { window_width/2.0-typing_font_size*1.0 }, 0.0))
                         .show(ctx, |ui| {
-                            let line = RenderTypingLine::new(content.lines[scene.typing_model.status.line as usize].clone(), scene.typing_model.typing_correctness.lines[scene.typing_model.status.line as usize].clone(), scene.typing_model.status.clone(), CharOrientation::Vertical).with_font(font.clone()).with_offset(scene.typing_model.scroll.scroll as f32);
+                            let line = RenderTypingLine::new(content.lines[scene.typing_model.status.line as usize].clone(), scene.typing_model.typing_correctness.lines[scene.typing_model.status.line as usize].clone(), scene.typing_model.status.clone(), CharOrientation::Vertical).with_theme(&theme).with_ruby_scale(ruby_scale).with_font(font.clone()).with_offset(scene.typing_model.scroll.scroll as f32).with_caret(self.caret_style);
+                            if self.wrap_lines {
+                                ui.add(line.with_wrap(wrap).with_offset(wrap_margin));
+                                return;
+                            }
                             let scrollto = line.calc_size(ui).0+window_height*cursor_target;
                             self.typing = update(self.typing.clone(),Msg::Typing(TypingMsg::ScrollTo(scrollto as f64, -scrollmax as f64)));
                             ui.add(line);
@@ -801,7 +1479,11 @@ impl eframe::App for TypingApp {
                     egui::Area::new("centered_text1".into())
                     .fixed_pos(egui::Pos2::new(0.0, window_height/2.-typing_font_size*0.5))
                         .show(ctx, |ui| {
-                            let line = RenderTypingLine::new(content.lines[scene.typing_model.status.line as usize].clone(), scene.typing_model.typing_correctness.lines[scene.typing_model.status.line as usize].clone(), scene.typing_model.status.clone(), CharOrientation::Horizontal).with_font(font.clone()).with_offset(scene.typing_model.scroll.scroll as f32);
+                            let line = RenderTypingLine::new(content.lines[scene.typing_model.status.line as usize].clone(), scene.typing_model.typing_correctness.lines[scene.typing_model.status.line as usize].clone(), scene.typing_model.status.clone(), CharOrientation::Horizontal).with_theme(&theme).with_ruby_scale(ruby_scale).with_font(font.clone()).with_offset(scene.typing_model.scroll.scroll as f32).with_caret(self.caret_style);
+                            if self.wrap_lines {
+                                ui.add(line.with_wrap(wrap).with_offset(wrap_margin));
+                                return;
+                            }
                             let scrollto = line.calc_size(ui).0-window_width*cursor_target;
                             self.typing = update(self.typing.clone(),Msg::Typing(TypingMsg::ScrollTo(scrollto as f64, -scrollmax as f64)));
                             ui.add(line);
@@ -809,14 +1491,20 @@ impl eframe::App for TypingApp {
                     egui::Area::new("content_title".into())
                         .fixed_pos(egui::Pos2::new(0.0, typing_font_size*0.1))
                         .show(ctx, |ui| {
-                            let line = RenderLineWithRuby::new(content.title.clone(), CharOrientation::Horizontal).with_font(egui::FontId::new(typing_font_size*0.7, egui::FontFamily::Proportional)).with_max(window_width);
+                            let line = RenderLineWithRuby::new(content.title.clone(), CharOrientation::Horizontal).with_theme(&theme).with_ruby_scale(ruby_scale).with_font(egui::FontId::new(typing_font_size*0.7, egui::FontFamily::Proportional)).with_max(window_width);
                             let scroll_to = line.calc_size(ui).0;
                             ui.add(line.with_offset(-window_width*0.5+scroll_to*0.5));
                         });
                     egui::Area::new("centered_text2".into())
-                    .fixed_pos(egui::Pos2::new(0.0, window_height/2.0-typing_font_size*2.0))
+                    .pivot(if self.wrap_lines { egui::Align2::LEFT_BOTTOM } else { egui::Align2::LEFT_TOP })
+                    .fixed_pos(egui::Pos2::new(0.0, if self.wrap_lines { window_height/2.0-typing_font_size*0.4 } else { window_height/2.0-typing_font_size*2.0 }))
                         .show(ctx, |ui| {
-                            ui.add(RenderLineWithRuby::new(content.lines[scene.typing_model.status.line as usize].clone(), CharOrientation::Horizontal).with_font(font.clone()).with_offset(scene.typing_model.scroll.scroll as f32).with_max(window_width));
+                            let line = RenderLineWithRuby::new(content.lines[scene.typing_model.status.line as usize].clone(), CharOrientation::Horizontal).with_theme(&theme).with_ruby_scale(ruby_scale).with_font(font.clone()).with_max(window_width);
+                            if self.wrap_lines {
+                                ui.add(line.with_wrap(wrap).with_focus(scene.typing_model.status.segment as usize).with_offset(wrap_margin));
+                            } else {
+                                ui.add(line.with_offset(scene.typing_model.scroll.scroll as f32));
+                            }
                         });
                 }
                 egui::Area::new("full_screen_overlay".into()) // オーバーレイ
@@ -832,7 +1520,7 @@ impl eframe::App for TypingApp {
                         ui.painter().rect_filled(
                             rect,
                             egui::Rounding::same(0),
-                            egui::Color32::from_rgba_premultiplied(0, 0, 0, 230),
+                            theme.overlay,
                         );
                         // Display overlay text in the center.
                         ui.painter().text(
@@ -840,7 +1528,7 @@ impl eframe::App for TypingApp {
                             egui::Align2::CENTER_CENTER,
                             "Pause\n\nPress [Space] to Resume\nPress [Escape] to Finish",
                             egui::FontId::proportional(80.0),
-                            egui::Color32::WHITE,
+                            theme.overlay_text,
                         );
                     });
                     ctx.input(|i| {
@@ -869,6 +1557,7 @@ impl eframe::App for TypingApp {
             Model::Result(scene) => {
                 let content: Content = scene.typing_model.content.clone();
                 let stat = calculate_total_metrics(&scene.typing_model);
+                let rhythm = calculate_total_rhythm(&scene.typing_model);
 
                 // Robustly get the latest timestamp from user_input, fallback if empty
                 let end_time = scene.typing_model.user_input.iter()
@@ -884,38 +1573,62 @@ impl eframe::App for TypingApp {
                     date.to_locale_string("ja-JP", &js_sys::Object::new()).into()
                 };
                 let title_text = format!("{}", content.title);
+                // 出題した行の番号 (同じ行を同じ順に入力した結果同士を比べられるよう記録する)
+                let selection = describe_selection(&scene.typing_model.selection);
 let result_text = format!(
 "```neknajtypinggame
 < Neknaj Typing Game >\n\n{}\n─────────────────────────\n\
- Speed     : {:.3} KPS\n\
+ Result    : {}\n\
+ Speed     : {:.3} {}\n\
  Accuracy  : {:.3}%\n\
  Keystrokes: {:<3}\n\
  Mistyped  : {} ({:.3}%)\n\
+ Efficiency: {:.3}%\n\
  Time      : {:02.0}:{:02.0}:{:05.2}\n\
+ Elapsed   : {:02.0}:{:02.0}:{:05.2}\n\
+ Lines     : {}\n\
+ Order     : {}\n\
 ─────────────────────────\n\
 {}\n\
 ```
 ",
     title_text,
-    stat.speed,
+    scene.outcome.label(),
+    stat.speed_in(self.speed_unit),
+    self.speed_unit.label(),
     stat.accuracy * 100.0,
     stat.type_count + stat.miss_count,
     stat.miss_count,
     (stat.miss_count as f64 / (stat.type_count + stat.miss_count) as f64) * 100.0,
+    stat.efficiency * 100.0,
     (stat.total_time / 1000.0 / 3600.0).floor(),
     ((stat.total_time / 1000.0) % 3600.0 / 60.0).floor(),
     (stat.total_time / 1000.0) % 60.0,
+    (stat.elapsed_time / 1000.0 / 3600.0).floor(),
+    ((stat.elapsed_time / 1000.0) % 3600.0 / 60.0).floor(),
+    (stat.elapsed_time / 1000.0) % 60.0,
+    scene.typing_model.selection_label,
+    selection,
     end_time_str
 );
+                // 書き出す結果の項目
+                let result_metrics = vec![
+                    ("Result", scene.outcome.label().to_string()),
+                    ("Speed", format!("{:.3} {}", stat.speed_in(self.speed_unit), self.speed_unit.label())),
+                    ("Accuracy", format!("{:.3}%", stat.accuracy * 100.0)),
+                    ("Keystrokes", format!("{}", stat.type_count + stat.miss_count)),
+                    ("Mistyped", format!("{} ({:.3}%)", stat.miss_count, (stat.miss_count as f64 / (stat.type_count + stat.miss_count) as f64) * 100.0)),
+                    ("Efficiency", format!("{:.3}%", stat.efficiency * 100.0)),
+                    ("Time", format!("{:02.0}:{:02.0}:{:05.2}", (stat.total_time / 1000.0 / 3600.0).floor(), ((stat.total_time / 1000.0) % 3600.0 / 60.0).floor(), (stat.total_time / 1000.0) % 60.0)),
+                    ("Lines", scene.typing_model.selection_label.clone()),
+                    ("Order", selection.clone()),
+                    ("Date", end_time_str.clone()),
+                ].into_iter().map(|(label, value)| (label.to_string(), value)).collect::<Vec<_>>();
                 let mut copy_result = false;
                 egui::CentralPanel::default()
                     .frame(
                         egui::Frame {
-                            fill: if self.dark_mode {
-                                egui::Color32::from_rgb(6,5,10)
-                            } else {
-                                egui::Color32::from_rgb(243, 243, 253)
-                            },
+                            fill: theme.background,
                             inner_margin: egui::Margin {
                                 left: 20,
                                 right: 20,
@@ -926,75 +1639,312 @@ let result_text = format!(
                         }
                     )
                     .show(ctx, |ui| {
-                        // タイトル
-                        ui.add_space(50.0);
-                        ui.vertical_centered(|ui| {
-                            let mut font = egui::FontSelection::Default.resolve(ui.style());
-                            font.size *= 3.0;
-                            ui.add(RenderLineWithRuby::new(content.title.clone(), CharOrientation::Horizontal).with_font(font).with_max(window_width));
-                        });
-                        ui.add_space(100.0);
+                        ScrollArea::vertical().show(ui, |ui| {
+                            // タイトル
+                            ui.add_space(50.0);
+                            ui.vertical_centered(|ui| {
+                                let mut font = egui::FontSelection::Default.resolve(ui.style());
+                                font.size *= 3.0;
+                                ui.add(RenderLineWithRuby::new(content.title.clone(), CharOrientation::Horizontal).with_theme(&theme).with_ruby_scale(ruby_scale).with_font(font).with_max(window_width));
+                                ui.add_space(20.0);
+                                let outcome_color = match scene.outcome {
+                                    SessionOutcome::Failed => theme.warning,
+                                    _ => ui.style().visuals.strong_text_color(),
+                                };
+                                ui.label(egui::RichText::new(scene.outcome.label()).size(40.0).color(outcome_color));
+                            });
+                            ui.add_space(80.0);
 
-                        // テーブルサイズの制御と中央寄せ
-                        let table_width = ui.available_width().min(600.0);
-                        let indent = ((ui.available_width() - table_width) / 2.0) as i32;
-                        ui.indent(indent, |ui| {
-                            TableBuilder::new(ui)
-                                .striped(true)
-                                .resizable(false)
-                                .cell_layout(egui::Layout::left_to_right(egui::Align::Center))
-                                .column(Column::auto().at_least(100.0))
-                                .column(Column::remainder().at_least(100.0))
-                                .min_scrolled_height(0.0)
-                                // .background_color(egui::Color32::from_rgba_premultiplied(0, 0, 0, 180))
-                                .body(|mut body| {
-                                    body.row(30.0, |mut row| {
-                                        row.col(|ui| { ui.label("Speed"); });
-                                        row.col(|ui| { ui.label(format!("{:.3} KPS", stat.speed)); });
-                                    });
-                                    body.row(30.0, |mut row| {
-                                        row.col(|ui| { ui.label("Accuracy"); });
-                                        row.col(|ui| { ui.label(format!("{:.3}%", stat.accuracy * 100.0)); });
-                                    });
-                                    body.row(30.0, |mut row| {
-                                        row.col(|ui| { ui.label("Keystrokes"); });
-                                        row.col(|ui| { ui.label(format!("{}", stat.type_count + stat.miss_count)); });
-                                    });
-                                    body.row(30.0, |mut row| {
-                                        row.col(|ui| { ui.label("Mistyped"); });
-                                        row.col(|ui| { ui.label(format!("{} ({:.3}%)", stat.miss_count, (stat.miss_count as f64 / (stat.type_count + stat.miss_count) as f64) * 100.0)); });
+                            // テーブルサイズの制御と中央寄せ
+                            let table_width = ui.available_width().min(600.0);
+                            let indent = ((ui.available_width() - table_width) / 2.0) as i32;
+                            ui.indent(indent, |ui| {
+                                TableBuilder::new(ui)
+                                    .striped(true)
+                                    .resizable(false)
+                                    .cell_layout(egui::Layout::left_to_right(egui::Align::Center))
+                                    .column(Column::auto().at_least(100.0))
+                                    .column(Column::remainder().at_least(100.0))
+                                    .min_scrolled_height(0.0)
+                                    // .background_color(egui::Color32::from_rgba_premultiplied(0, 0, 0, 180))
+                                    .body(|mut body| {
+                                        body.row(30.0, |mut row| {
+                                            row.col(|ui| { ui.label("Speed"); });
+                                            row.col(|ui| { ui.label(format!("{:.3} {}", stat.speed_in(self.speed_unit), self.speed_unit.label())); });
+                                        });
+                                        body.row(30.0, |mut row| {
+                                            row.col(|ui| { ui.label("Accuracy"); });
+                                            row.col(|ui| { ui.label(format!("{:.3}%", stat.accuracy * 100.0)); });
+                                        });
+                                        body.row(30.0, |mut row| {
+                                            row.col(|ui| { ui.label("Keystrokes"); });
+                                            row.col(|ui| { ui.label(format!("{}", stat.type_count + stat.miss_count)); });
+                                        });
+                                        body.row(30.0, |mut row| {
+                                            row.col(|ui| { ui.label("Mistyped"); });
+                                            row.col(|ui| { ui.label(format!("{} ({:.3}%)", stat.miss_count, (stat.miss_count as f64 / (stat.type_count + stat.miss_count) as f64) * 100.0)); });
+                                        });
+                                        body.row(30.0, |mut row| {
+                                            row.col(|ui| { ui.label("Guide Keystrokes"); });
+                                            row.col(|ui| { ui.label(format!("{}", stat.guide_count)); });
+                                        });
+                                        body.row(30.0, |mut row| {
+                                            row.col(|ui| { ui.label("Ideal Keystrokes"); });
+                                            row.col(|ui| { ui.label(format!("{}", stat.ideal_count)); });
+                                        });
+                                        body.row(30.0, |mut row| {
+                                            row.col(|ui| { ui.label("Efficiency"); });
+                                            row.col(|ui| { ui.label(format!("{:.3}%", stat.efficiency * 100.0)); });
+                                        });
+                                        // タイムアタックは時間が決まっているので1分あたりで比べる
+                                        if scene.typing_model.options.time_attack.is_some() {
+                                            for (label, count) in [("Keystrokes / min", stat.type_count + stat.miss_count), ("Characters / min", stat.char_count), ("Mistyped / min", stat.miss_count)] {
+                                                body.row(30.0, |mut row| {
+                                                    row.col(|ui| { ui.label(label); });
+                                                    row.col(|ui| { ui.label(format!("{:.3}", stat.per_minute(count))); });
+                                                });
+                                            }
+                                        }
+                                        if let Some(score) = memorization_score(&scene.typing_model) {
+                                            body.row(30.0, |mut row| {
+                                                row.col(|ui| { ui.label("Memorization"); }).1.on_hover_text_at_pointer("Hidden segments typed without a hint");
+                                                row.col(|ui| { ui.label(format!("{:.3}%", score * 100.0)); });
+                                            });
+                                        }
+                                        let total_seconds = stat.total_time / 1000.0;
+                                        let hours = (total_seconds / 3600.0).floor();
+                                        let minutes = ((total_seconds % 3600.0) / 60.0).floor();
+                                        let seconds = total_seconds % 60.0;
+                                        body.row(30.0, |mut row| {
+                                            row.col(|ui| { ui.label("Time"); });
+                                            row.col(|ui| { ui.label(format!("{:02.0}:{:02.0}:{:05.2}", hours, minutes, seconds)); });
+                                        });
+                                        let elapsed_seconds = stat.elapsed_time / 1000.0;
+                                        body.row(30.0, |mut row| {
+                                            row.col(|ui| { ui.label("Elapsed"); }).1.on_hover_text_at_pointer("Including time excluded as AFK");
+                                            row.col(|ui| { ui.label(format!("{:02.0}:{:02.0}:{:05.2}", (elapsed_seconds / 3600.0).floor(), ((elapsed_seconds % 3600.0) / 60.0).floor(), elapsed_seconds % 60.0)); });
+                                        });
+                                        body.row(30.0, |mut row| {
+                                            row.col(|ui| { ui.label("Lines"); }).1.on_hover_text_at_pointer("Compare scores only between runs with the same lines");
+                                            row.col(|ui| { ui.label(&scene.typing_model.selection_label); });
+                                        });
                                     });
-                                    let total_seconds = stat.total_time / 1000.0;
-                                    let hours = (total_seconds / 3600.0).floor();
-                                    let minutes = ((total_seconds % 3600.0) / 60.0).floor();
-                                    let seconds = total_seconds % 60.0;
-                                    body.row(30.0, |mut row| {
-                                        row.col(|ui| { ui.label("Time"); });
-                                        row.col(|ui| { ui.label(format!("{:02.0}:{:02.0}:{:05.2}", hours, minutes, seconds)); });
+                            });
+
+                            ui.add_space(30.0);
+
+                            // 打鍵リズム
+                            ui.indent(indent, |ui| {
+                                TableBuilder::new(ui)
+                                    .id_salt("rhythm_table")
+                                    .striped(true)
+                                    .resizable(false)
+                                    .cell_layout(egui::Layout::left_to_right(egui::Align::Center))
+                                    .column(Column::auto().at_least(100.0))
+                                    .column(Column::remainder().at_least(100.0))
+                                    .min_scrolled_height(0.0)
+                                    .body(|mut body| {
+                                        body.row(30.0, |mut row| {
+                                            row.col(|ui| { ui.label("Median Interval"); });
+                                            row.col(|ui| { ui.label(format!("{:.0} ms", rhythm.median_interval)); });
+                                        });
+                                        body.row(30.0, |mut row| {
+                                            row.col(|ui| { ui.label("90th Percentile"); });
+                                            row.col(|ui| { ui.label(format!("{:.0} ms", rhythm.p90_interval)); });
+                                        });
+                                        body.row(30.0, |mut row| {
+                                            row.col(|ui| { ui.label("Standard Deviation"); });
+                                            row.col(|ui| { ui.label(format!("{:.0} ms", rhythm.std_dev)); });
+                                        });
+                                        body.row(30.0, |mut row| {
+                                            row.col(|ui| { ui.label("Longest Hesitation"); });
+                                            row.col(|ui| { ui.label(format!("{:.0} ms", rhythm.longest_pause)); });
+                                        });
+                                        body.row(30.0, |mut row| {
+                                            row.col(|ui| { ui.label("First-Stroke Latency"); });
+                                            row.col(|ui| { ui.label(format!("{:.0} ms", stat.latency)); });
+                                        });
+                                        body.row(30.0, |mut row| {
+                                            row.col(|ui| { ui.label("Burst Speed (10s)"); });
+                                            row.col(|ui| { ui.label(format!("{:.3} {}", rhythm.burst_speed_in(self.speed_unit, &stat), self.speed_unit.label())); });
+                                        });
                                     });
-                                });
-                        });
+                            });
 
-                        ui.add_space(100.0);
+                            ui.add_space(100.0);
 
-                        // ボタン
-                        ui.vertical_centered(|ui| {
-                            let button_width = 300.0;
-                            let button_height = 50.0;
-                            if ui.add_sized([button_width, button_height], egui::Button::new("Copy Result")).on_hover_text_at_pointer("[Enter]").clicked() {
-                                copy_result = true;
-                            }
-                            // Enterキーでコピー
-                            if ctx.input(|i| i.key_pressed(egui::Key::Enter)) {
-                            }
-                            ui.add_space(20.0);
-                            if ui.add_sized([button_width, button_height], egui::Button::new("Return to Menu")).on_hover_text_at_pointer("[Escape]").clicked() {
-                                self.typing = update(self.typing.clone(), Msg::Result(ResultMsg::BackToMenu));
+                            // ボタン
+                            ui.vertical_centered(|ui| {
+                                let button_width = 300.0;
+                                let button_height = 50.0;
+                                if ui.add_sized([button_width, button_height], egui::Button::new("Copy Result")).on_hover_text_at_pointer("[Enter]").clicked() {
+                                    copy_result = true;
+                                }
+                                // Enterキーでコピー
+                                if ctx.input(|i| i.key_pressed(egui::Key::Enter)) {
+                                }
+                                ui.add_space(20.0);
+                                for format in [ExportFormat::Png, ExportFormat::Svg] {
+                                    if ui.add_sized([button_width, button_height], egui::Button::new(format!("Export {}", format.extension().to_uppercase()))).on_hover_text_at_pointer("Save the result and the typed text as an image").clicked() {
+                                        save_export(&format!("{} result", content.title), format, export_result(&scene, &result_metrics, &self.export_style(ctx), format));
+                                    }
+                                    ui.add_space(20.0);
+                                }
+                                if ui.add_sized([button_width, button_height], egui::Button::new("Return to Menu")).on_hover_text_at_pointer("[Escape]").clicked() {
+                                    self.typing = update(self.typing.clone(), Msg::Result(ResultMsg::BackToMenu));
+                                }
+                                ui.add_space(20.0);
+                                if ui.add_sized([button_width, button_height], egui::Button::new("Retry")).on_hover_text_at_pointer("[Space]").clicked() {
+                                    self.typing = update(self.typing.clone(), Msg::Result(ResultMsg::Retry));
+                                }
+                                ui.add_space(20.0);
+                                let has_mistakes = !mistake_lines(&scene.typing_model).is_empty();
+                                if ui.add_enabled(has_mistakes, egui::Button::new("Drill Mistakes").min_size(vec2(button_width, button_height)))
+                                    .on_hover_text_at_pointer("Type the mistyped parts again until each is typed without mistakes")
+                                    .clicked() {
+                                    self.typing = update(self.typing.clone(), Msg::Result(ResultMsg::DrillMistakes));
+                                }
+                            });
+
+                            // 行ごとの成績
+                            ui.add_space(50.0);
+                            let mut line_stats = scene.line_metrics.iter().enumerate().collect::<Vec<_>>();
+                            // 最も遅い行と最もミスの多い行を強調する
+                            let slowest = line_stats.iter()
+                                .filter(|(_, stat)| stat.typing_time > 0.0)
+                                .min_by(|a, b| a.1.speed.partial_cmp(&b.1.speed).unwrap())
+                                .map(|(line, _)| *line);
+                            let most_missed = line_stats.iter()
+                                .filter(|(_, stat)| stat.miss_count > 0)
+                                .max_by_key(|(_, stat)| stat.miss_count)
+                                .map(|(line, _)| *line);
+                            let speed_unit = self.speed_unit;
+                            line_stats.sort_by(|a, b| {
+                                let order = match self.line_sort {
+                                    LineSortKey::Line => a.0.cmp(&b.0),
+                                    LineSortKey::Speed => a.1.speed_in(speed_unit).partial_cmp(&b.1.speed_in(speed_unit)).unwrap(),
+                                    LineSortKey::Accuracy => a.1.accuracy.partial_cmp(&b.1.accuracy).unwrap(),
+                                    LineSortKey::Misses => a.1.miss_count.cmp(&b.1.miss_count),
+                                    LineSortKey::Time => a.1.total_time.partial_cmp(&b.1.total_time).unwrap(),
+                                };
+                                if self.line_sort_descending { order.reverse() } else { order }
+                            });
+                            let slow_color = theme.highlight;
+                            let miss_color = theme.warning;
+                            let mut sort_clicked = None;
+                            let mut drill_line = None;
+                            ui.indent(indent, |ui| {
+                                egui::CollapsingHeader::new("Lines").default_open(true).show(ui, |ui| {
+                                    ui.horizontal(|ui| {
+                                        ui.label(egui::RichText::new("Slowest").color(slow_color));
+                                        ui.label(egui::RichText::new("Most Mistyped").color(miss_color));
+                                        ui.label("Click a line to drill it");
+                                    });
+                                    TableBuilder::new(ui)
+                                        .id_salt("line_table")
+                                        .striped(true)
+                                        .resizable(false)
+                                        .cell_layout(egui::Layout::left_to_right(egui::Align::Center))
+                                        .column(Column::auto().at_least(60.0))
+                                        .column(Column::remainder().at_least(200.0).clip(true))
+                                        .columns(Column::auto().at_least(100.0), 4)
+                                        .min_scrolled_height(0.0)
+                                        .header(30.0, |mut header| {
+                                            let columns = [
+                                                (Some(LineSortKey::Line), "Line".to_string()),
+                                                (None, "Text".to_string()),
+                                                (Some(LineSortKey::Speed), format!("Speed ({})", speed_unit.label())),
+                                                (Some(LineSortKey::Accuracy), "Accuracy".to_string()),
+                                                (Some(LineSortKey::Misses), "Mistyped".to_string()),
+                                                (Some(LineSortKey::Time), "Time".to_string()),
+                                            ];
+                                            for (key, title) in columns {
+                                                header.col(|ui| {
+                                                    match key {
+                                                        Some(key) => {
+                                                            let arrow = if self.line_sort != key { "" } else if self.line_sort_descending { " ▼" } else { " ▲" };
+                                                            if ui.selectable_label(self.line_sort == key, format!("{}{}", title, arrow)).clicked() {
+                                                                sort_clicked = Some(key);
+                                                            }
+                                                        },
+                                                        None => { ui.strong(title); },
+                                                    }
+                                                });
+                                            }
+                                        })
+                                        .body(|mut body| {
+                                            for (line, stat) in &line_stats {
+                                                let color = if most_missed == Some(*line) {
+                                                    Some(miss_color)
+                                                } else if slowest == Some(*line) {
+                                                    Some(slow_color)
+                                                } else {
+                                                    None
+                                                };
+                                                let text = |text: String| match color {
+                                                    Some(color) => egui::RichText::new(text).color(color),
+                                                    None => egui::RichText::new(text),
+                                                };
+                                                body.row(30.0, |mut row| {
+                                                    row.col(|ui| { ui.label(text(format!("{}", line + 1))); });
+                                                    row.col(|ui| {
+                                                        if ui.link(text(content.lines[*line].to_string())).on_hover_text_at_pointer("Drill this line").clicked() {
+                                                            drill_line = Some(*line);
+                                                        }
+                                                    });
+                                                    row.col(|ui| { ui.label(text(format!("{:.3}", stat.speed_in(speed_unit)))); });
+                                                    row.col(|ui| { ui.label(text(format!("{:.3}%", stat.accuracy * 100.0))); });
+                                                    row.col(|ui| { ui.label(text(format!("{}", stat.miss_count))); });
+                                                    row.col(|ui| { ui.label(text(format!("{:.2} s", stat.total_time / 1000.0))); });
+                                                });
+                                            }
+                                        });
+                                });
+                            });
+                            if let Some(key) = sort_clicked {
+                                // 同じ列なら昇順と降順を切り替える
+                                self.line_sort_descending = self.line_sort == key && !self.line_sort_descending;
+                                self.line_sort = key;
                             }
-                            ui.add_space(20.0);
-                            if ui.add_sized([button_width, button_height], egui::Button::new("Retry")).on_hover_text_at_pointer("[Space]").clicked() {
-                                self.typing = update(self.typing.clone(), Msg::Result(ResultMsg::Retry));
+                            if let Some(line) = drill_line {
+                                self.typing = update(self.typing.clone(), Msg::Result(ResultMsg::DrillLine(line)));
                             }
+
+                            // 行ごとの打鍵リズム
+                            ui.add_space(50.0);
+                            ui.indent(indent, |ui| {
+                                egui::CollapsingHeader::new("Rhythm by Line").show(ui, |ui| {
+                                    TableBuilder::new(ui)
+                                        .id_salt("line_rhythm_table")
+                                        .striped(true)
+                                        .resizable(false)
+                                        .cell_layout(egui::Layout::left_to_right(egui::Align::Center))
+                                        .column(Column::auto().at_least(60.0))
+                                        .columns(Column::auto().at_least(100.0), 6)
+                                        .min_scrolled_height(0.0)
+                                        .header(30.0, |mut header| {
+                                            for title in ["Line", "Latency", "Median", "P90", "Std Dev", "Longest", "Burst"] {
+                                                header.col(|ui| { ui.strong(title); });
+                                            }
+                                        })
+                                        .body(|mut body| {
+                                            for line in 0..content.lines.len().min(scene.typing_model.status.line as usize + 1) {
+                                                let rhythm = calculate_line_rhythm(&scene.typing_model, line as i32);
+                                                let latency = line_latency(&scene.typing_model, line as i32);
+                                                body.row(30.0, |mut row| {
+                                                    row.col(|ui| { ui.label(format!("{}", line + 1)); });
+                                                    row.col(|ui| { ui.label(latency.map_or("-".to_string(), |l| format!("{:.0} ms", l))); });
+                                                    row.col(|ui| { ui.label(format!("{:.0} ms", rhythm.median_interval)); });
+                                                    row.col(|ui| { ui.label(format!("{:.0} ms", rhythm.p90_interval)); });
+                                                    row.col(|ui| { ui.label(format!("{:.0} ms", rhythm.std_dev)); });
+                                                    row.col(|ui| { ui.label(format!("{:.0} ms", rhythm.longest_pause)); });
+                                                    let burst = scene.line_metrics.get(line).map_or(0.0, |stat| rhythm.burst_speed_in(self.speed_unit, stat));
+                                                    row.col(|ui| { ui.label(format!("{:.3} {}", burst, self.speed_unit.label())); });
+                                                });
+                                            }
+                                        });
+                                });
+                            });
                         });
                     });
                     ctx.input(|i| {
@@ -1047,7 +1997,171 @@ let result_text = format!(
                             }
                         }
                     }
-            }
+            },
+            Model::LayoutEditor(scene) => {
+                let conflict_color = theme.warning;
+                let conflicts_of = |entry: usize| scene.conflicts.iter().filter(move |c| c.entry() == entry);
+                egui::SidePanel::left("layout_entries_panel")
+                    .resizable(false)
+                    .min_width(360.0)
+                    .max_width(360.0)
+                    .frame(
+                        egui::Frame {
+                            fill: theme.side_panel,
+                            inner_margin: egui::Margin {
+                                left: 20,
+                                right: 20,
+                                top: 20,
+                                bottom: 20,
+                            },
+                            ..Default::default()
+                        }
+                    )
+                    .show(ctx, |ui| {
+                        ui.heading("Entries");
+                        ui.horizontal(|ui| {
+                            ui.label("Filter");
+                            ui.text_edit_singleline(&mut self.layout_editor_filter);
+                        });
+                        ui.horizontal(|ui| {
+                            ui.text_edit_singleline(&mut self.layout_editor_new_entry);
+                            if ui.button("Add Entry").clicked() {
+                                self.typing = update(self.typing.clone(), Msg::LayoutEditor(LayoutEditorMsg::AddEntry(self.layout_editor_new_entry.trim().to_string())));
+                                self.layout_editor_new_entry.clear();
+                            }
+                        });
+                        ui.label(format!("{} entries, {} problems", scene.layout.mapping.len(), scene.conflicts.len()));
+                        ui.separator();
+                        ScrollArea::vertical().show(ui, |ui| {
+                            for (index, (key, values)) in scene.layout.mapping.iter().enumerate() {
+                                let filter = self.layout_editor_filter.trim();
+                                if !filter.is_empty() && !key.contains(filter) && !values.iter().any(|v| v.contains(filter)) {
+                                    continue;
+                                }
+                                let mut text = egui::RichText::new(format!("{}  {}", key, values.join(", ")));
+                                if conflicts_of(index).next().is_some() {
+                                    text = text.color(conflict_color);
+                                }
+                                if ui.selectable_label(scene.selecting == index, text).clicked() {
+                                    self.typing = update(self.typing.clone(), Msg::LayoutEditor(LayoutEditorMsg::Select(index)));
+                                }
+                            }
+                        });
+                    });
+                egui::CentralPanel::default()
+                    .frame(
+                        egui::Frame {
+                            fill: theme.background,
+                            inner_margin: egui::Margin {
+                                left: 20,
+                                right: 20,
+                                top: 20,
+                                bottom: 20,
+                            },
+                            ..Default::default()
+                        }
+                    )
+                    .show(ctx, |ui| {
+                        ui.heading("Layout Editor");
+                        ui.horizontal(|ui| {
+                            if ui.button("Save JSON").clicked() {
+                                let json = layout_to_json(&scene.layout);
+                                #[cfg(not(target_arch = "wasm32"))]
+                                {
+                                    if let Some(path) = FileDialog::new().add_filter("Layout File", &["json"]).set_file_name("layout.json").save_file() {
+                                        if let Err(e) = fs::write(&path, json) {
+                                            eprintln!("File write error: {}", e);
+                                        }
+                                    }
+                                }
+                                #[cfg(target_arch = "wasm32")]
+                                {
+                                    wasm_bindgen_futures::spawn_local(async move {
+                                        if let Some(file) = AsyncFileDialog::new()
+                                            .add_filter("Layout File", &["json"])
+                                            .set_file_name("layout.json")
+                                            .save_file()
+                                            .await
+                                        {
+                                            if file.write(json.as_bytes()).await.is_err() {
+                                                web_sys::console::log_1(&"Failed to save the layout.".into());
+                                            }
+                                        }
+                                    });
+                                }
+                            }
+                            let blocked = scene.conflicts.iter().any(|c| c.is_blocking());
+                            if ui.add_enabled(!blocked, egui::Button::new("Apply"))
+                                .on_hover_text_at_pointer("Use the edited layout and return to the menu")
+                                .on_disabled_hover_text("Fix the unreachable, duplicate or empty spellings first")
+                                .clicked() {
+                                self.typing = update(self.typing.clone(), Msg::LayoutEditor(LayoutEditorMsg::Apply));
+                                if let Model::Menu(menu) = &self.typing {
+                                    storage::save_json("spelling_preferences", &menu.preferences);
+                                }
+                            }
+                            if ui.button("Cancel").on_hover_text_at_pointer("Discard the changes and return to the menu").clicked() {
+                                self.typing = update(self.typing.clone(), Msg::LayoutEditor(LayoutEditorMsg::Cancel));
+                            }
+                        });
+                        ui.separator();
+                        if let Some((key, values)) = scene.layout.mapping.get(scene.selecting) {
+                            ui.horizontal(|ui| {
+                                ui.label(egui::RichText::new(key).size(60.0).color(ui.style().visuals.strong_text_color()));
+                                if ui.button("Remove Entry").clicked() {
+                                    self.typing = update(self.typing.clone(), Msg::LayoutEditor(LayoutEditorMsg::RemoveEntry));
+                                }
+                            });
+                            for (index, value) in values.iter().enumerate() {
+                                ui.horizontal(|ui| {
+                                    let preferred = scene.preferences.preferred.get(key) == Some(value);
+                                    let mut text = egui::RichText::new(if value.is_empty() { "(empty)" } else { value.as_str() }).monospace();
+                                    if conflicts_of(scene.selecting).any(|c| c.spelling() == Some(index)) {
+                                        text = text.color(conflict_color);
+                                    }
+                                    ui.add_sized(Vec2::new(200.0, 30.0), egui::Label::new(text));
+                                    if ui.add_enabled(index > 0, egui::Button::new("Up")).clicked() {
+                                        self.typing = update(self.typing.clone(), Msg::LayoutEditor(LayoutEditorMsg::MoveSpellingUp(index)));
+                                    }
+                                    if ui.add_enabled(index + 1 < values.len(), egui::Button::new("Down")).clicked() {
+                                        self.typing = update(self.typing.clone(), Msg::LayoutEditor(LayoutEditorMsg::MoveSpellingDown(index)));
+                                    }
+                                    if ui.button("Remove").clicked() {
+                                        self.typing = update(self.typing.clone(), Msg::LayoutEditor(LayoutEditorMsg::RemoveSpelling(index)));
+                                    }
+                                    if ui.selectable_label(preferred, "Prefer").on_hover_text_at_pointer("Always use this spelling for guides").clicked() {
+                                        let index = if preferred { None } else { Some(index) };
+                                        self.typing = update(self.typing.clone(), Msg::LayoutEditor(LayoutEditorMsg::SetPreferred(index)));
+                                    }
+                                    if index == 0 {
+                                        ui.label("Primary");
+                                    }
+                                });
+                            }
+                            ui.horizontal(|ui| {
+                                let response = ui.text_edit_singleline(&mut self.layout_editor_new_spelling);
+                                if ui.button("Add Spelling").clicked() || (response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter))) {
+                                    self.typing = update(self.typing.clone(), Msg::LayoutEditor(LayoutEditorMsg::AddSpelling(self.layout_editor_new_spelling.trim().to_string())));
+                                    self.layout_editor_new_spelling.clear();
+                                }
+                            });
+                            ui.separator();
+                            ui.label("Problems");
+                            for conflict in conflicts_of(scene.selecting) {
+                                ui.colored_label(conflict_color, conflict.describe(&scene.layout));
+                            }
+                        }
+                        ui.separator();
+                        ui.label("All Problems");
+                        ScrollArea::vertical().id_salt("layout_conflicts").show(ui, |ui| {
+                            for conflict in &scene.conflicts {
+                                if ui.link(conflict.describe(&scene.layout)).clicked() {
+                                    self.typing = update(self.typing.clone(), Msg::LayoutEditor(LayoutEditorMsg::Select(conflict.entry())));
+                                }
+                            }
+                        });
+                    });
+            },
         }
         egui::Area::new("debug_overlay".into())
         .fixed_pos(egui::Pos2::new(0.0, 0.0))
@@ -1057,14 +2171,14 @@ let result_text = format!(
             ui.painter().rect_filled(
                 rect,
                 egui::Rounding::same(10),
-                egui::Color32::from_rgba_premultiplied(0, 0, 0, 230),
+                theme.overlay,
             );
             ui.painter().text(
                 rect.center(),
                 egui::Align2::CENTER_CENTER,
                 &format!("FPS: {}",self.fps),
                 egui::FontId::proportional(20.0),
-                egui::Color32::WHITE,
+                theme.overlay_text,
             );
         });
         egui::Area::new("key_event".into())
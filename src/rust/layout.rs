@@ -0,0 +1,191 @@
+// layout.rs
+
+use std::collections::HashMap;
+use std::fmt;
+use serde::de::{Deserialize, Deserializer, MapAccess, Visitor};
+use crate::model::{TextConvert, SpellingPreferences};
+use crate::typing::ordered_spellings;
+
+/// JSONのオブジェクトを記述順のまま読み込むためのラッパー
+struct OrderedMapping(Vec<(String, Vec<String>)>);
+
+impl<'de> Deserialize<'de> for OrderedMapping {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct OrderedMappingVisitor;
+        impl<'de> Visitor<'de> for OrderedMappingVisitor {
+            type Value = OrderedMapping;
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "an object mapping kana to a list of spellings")
+            }
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+                let mut mapping = Vec::new();
+                while let Some((key, values)) = map.next_entry::<String, Vec<String>>()? {
+                    mapping.push((key, values));
+                }
+                Ok(OrderedMapping(mapping))
+            }
+        }
+        deserializer.deserialize_map(OrderedMappingVisitor)
+    }
+}
+
+/// レイアウトファイル(JSON)を読み込む
+/// エントリの順序はファイルの記述順を保持する
+pub fn parse_layout(json: &str) -> Result<TextConvert, String> {
+    serde_json::from_str::<OrderedMapping>(json)
+        .map(|m| TextConvert { mapping: m.0 })
+        .map_err(|e| e.to_string())
+}
+
+/// レイアウトをlayouts/*.jsonと同じ書式のJSONに変換する
+pub fn layout_to_json(layout: &TextConvert) -> String {
+    let entries = layout.mapping.iter()
+        .map(|(key, values)| {
+            let values = values.iter()
+                .map(|v| serde_json::to_string(v).unwrap())
+                .collect::<Vec<_>>()
+                .join(", ");
+            format!("    {}: [{}]", serde_json::to_string(key).unwrap(), values)
+        })
+        .collect::<Vec<_>>()
+        .join(",\n");
+    format!("{{\n{}\n}}", entries)
+}
+
+/// レイアウトの問題点
+#[derive(Debug, Clone, PartialEq)]
+pub enum LayoutConflict {
+    /// 綴りが一つも登録されていない (この仮名は入力できない)
+    NoSpelling { entry: usize },
+    /// 空の綴り
+    EmptySpelling { entry: usize, spelling: usize },
+    /// 同時に候補になる別の綴りと全く同じ (どちらが使われるか決まらない)
+    Ambiguous { entry: usize, spelling: usize, other_entry: usize, other_spelling: usize },
+    /// 同時に候補になり先に照合される別の綴りと途中まで一致するため、そちらが使われて到達できない
+    Unreachable { entry: usize, spelling: usize, other_entry: usize, other_spelling: usize },
+}
+
+impl LayoutConflict {
+    /// 問題のあるエントリ
+    pub fn entry(&self) -> usize {
+        match self {
+            LayoutConflict::NoSpelling { entry } => *entry,
+            LayoutConflict::EmptySpelling { entry, .. } => *entry,
+            LayoutConflict::Ambiguous { entry, .. } => *entry,
+            LayoutConflict::Unreachable { entry, .. } => *entry,
+        }
+    }
+
+    /// 問題のある綴り (エントリ全体の問題ならNone)
+    pub fn spelling(&self) -> Option<usize> {
+        match self {
+            LayoutConflict::NoSpelling { .. } => None,
+            LayoutConflict::EmptySpelling { spelling, .. } => Some(*spelling),
+            LayoutConflict::Ambiguous { spelling, .. } => Some(*spelling),
+            LayoutConflict::Unreachable { spelling, .. } => Some(*spelling),
+        }
+    }
+
+    /// 入力できなくなる問題か (このままではレイアウトを使えない)
+    /// 綴りの無いエントリはその仮名が入力できないだけなので含めない
+    pub fn is_blocking(&self) -> bool {
+        !matches!(self, LayoutConflict::NoSpelling { .. })
+    }
+
+    pub fn describe(&self, layout: &TextConvert) -> String {
+        let spelling = |entry: usize, spelling: usize| {
+            format!("{} \"{}\"", layout.mapping[entry].0, layout.mapping[entry].1[spelling])
+        };
+        match self {
+            LayoutConflict::NoSpelling { entry } => {
+                format!("{}: no spelling", layout.mapping[*entry].0)
+            },
+            LayoutConflict::EmptySpelling { entry, .. } => {
+                format!("{}: empty spelling", layout.mapping[*entry].0)
+            },
+            LayoutConflict::Ambiguous { entry, spelling: s, other_entry, other_spelling } => {
+                format!("{}: same as {}", spelling(*entry, *s), spelling(*other_entry, *other_spelling))
+            },
+            LayoutConflict::Unreachable { entry, spelling: s, other_entry, other_spelling } => {
+                format!("{}: unreachable, {} is matched first", spelling(*entry, *s), spelling(*other_entry, *other_spelling))
+            },
+        }
+    }
+}
+
+/// レイアウトの問題点を検出する
+///
+/// 入力中は、現在位置から始まる仮名列に一致する全てのエントリが候補になり、
+/// 綴りの優先順 (ユーザーの指定 → レイアウトの記述順)、同じ順位ならエントリの記述順に照合して最初に一致したものが使われる。
+/// そのため、仮名が前方一致するエントリ同士(「き」と「きゃ」など)の綴りを比較し、
+/// 同一の綴りや、先に照合される綴りと一方がもう一方の途中までと一致する綴りを報告する。
+pub fn detect_conflicts(layout: &TextConvert, preferences: &SpellingPreferences) -> Vec<LayoutConflict> {
+    let mut conflicts = Vec::new();
+    let mut index: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (i, (key, _)) in layout.mapping.iter().enumerate() {
+        index.entry(key.as_str()).or_default().push(i);
+    }
+    // 綴りごとの照合の順位 (key_input と同じ順)
+    let ranks = layout.mapping.iter()
+        .map(|(key, values)| {
+            let ordered = ordered_spellings(values, preferences.preferred.get(key));
+            values.iter().map(|v| ordered.iter().position(|o| std::ptr::eq(*o, v)).unwrap()).collect::<Vec<usize>>()
+        })
+        .collect::<Vec<_>>();
+    let matched_before = |a: (usize, usize), b: (usize, usize)| (ranks[a.0][a.1], a.0) < (ranks[b.0][b.1], b.0);
+    for (entry, (key, values)) in layout.mapping.iter().enumerate() {
+        if values.is_empty() {
+            conflicts.push(LayoutConflict::NoSpelling { entry });
+        }
+        // この仮名と同時に候補になるエントリ (自分自身と、仮名が前方一致するもの)
+        let mut others = Vec::new();
+        for (end, _) in key.char_indices().skip(1).chain(std::iter::once((key.len(), ' '))) {
+            if let Some(list) = index.get(&key[..end]) {
+                others.extend(list.iter().copied());
+            }
+        }
+        for (spelling, v) in values.iter().enumerate() {
+            if v.is_empty() {
+                conflicts.push(LayoutConflict::EmptySpelling { entry, spelling });
+                continue;
+            }
+            for &other_entry in &others {
+                for (other_spelling, w) in layout.mapping[other_entry].1.iter().enumerate() {
+                    if (other_entry, other_spelling) == (entry, spelling) || w.is_empty() {
+                        continue;
+                    }
+                    if v == w {
+                        // 同じエントリ内の重複は片方にだけ報告する
+                        if other_entry != entry || other_spelling > spelling {
+                            conflicts.push(LayoutConflict::Ambiguous { entry, spelling, other_entry, other_spelling });
+                        }
+                    } else if (v.starts_with(w.as_str()) || w.starts_with(v.as_str())) && matched_before((other_entry, other_spelling), (entry, spelling)) {
+                        // 先に照合される綴りが短ければ先に確定し、長ければそちらの入力が続く
+                        conflicts.push(LayoutConflict::Unreachable { entry, spelling, other_entry, other_spelling });
+                    }
+                }
+            }
+        }
+    }
+    conflicts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conflicts_follow_match_order() {
+        let layout = parse_layout(r#"{"ん":["nn","n"],"か":["ka"],"き":["ki",""],"きゃ":["ki"]}"#).unwrap();
+        let mut preferences = SpellingPreferences::default();
+        assert_eq!(detect_conflicts(&layout, &preferences), vec![
+            LayoutConflict::Unreachable { entry: 0, spelling: 1, other_entry: 0, other_spelling: 0 },
+            LayoutConflict::EmptySpelling { entry: 2, spelling: 1 },
+            LayoutConflict::Ambiguous { entry: 3, spelling: 0, other_entry: 2, other_spelling: 0 },
+        ]);
+        // 優先する綴りを変えると、照合の順が変わる
+        preferences.preferred.insert("ん".to_string(), "n".to_string());
+        assert_eq!(detect_conflicts(&layout, &preferences)[0], LayoutConflict::Unreachable { entry: 0, spelling: 0, other_entry: 0, other_spelling: 1 });
+        assert!(detect_conflicts(&layout, &preferences).iter().all(|c| c.is_blocking()));
+    }
+}
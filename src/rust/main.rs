@@ -10,6 +10,13 @@ mod typing;
 mod gui;
 mod textrender;
 mod timestamp;
+mod layout;
+mod storage;
+mod review;
+mod rng;
+mod export;
+mod theme;
+mod fonts;
 
 fn main() {
     // Print a greeting message for native execution
@@ -25,67 +32,9 @@ fn main() {
         &format!("Neknaj Typing Game - ver.{}",env!("CARGO_PKG_VERSION")),
         native_options,
         Box::new(|cc| {
-            // Configure font definitions
-            let mut fonts = egui::FontDefinitions::default();
-
-            fonts.font_data.insert(
-                "KaiseiHarunoUmi".to_owned(),
-                egui::FontData::from_static(include_bytes!("../fonts/KaiseiHarunoUmi-Bold.ttf")).into(),
-            );
-
-            fonts.font_data.insert(
-                "Merienda".to_owned(),
-                egui::FontData::from_static(include_bytes!("../fonts/Merienda-Regular.ttf")).into(),
-            );
-
-            fonts.font_data.insert(
-                "Hurricane".to_owned(),
-                egui::FontData::from_static(include_bytes!("../fonts/Hurricane-Regular.ttf")).into(),
-            );
-
-            fonts.font_data.insert(
-                "YujiSyuku".to_owned(),
-                egui::FontData::from_static(include_bytes!("../fonts/YujiSyuku-Regular.ttf")).into(),
-            );
-
-            fonts.font_data.insert(
-                "ShipporiAntique".to_owned(),
-                egui::FontData::from_static(include_bytes!("../fonts/ShipporiAntique-Regular.ttf")).into(),
-            );
-
-            fonts.font_data.insert(
-                "NotoSerifJP".to_owned(),
-                egui::FontData::from_static(include_bytes!("../fonts/NotoSerifJP-VariableFont_wght.ttf")).into(),
-            );
-
-            // Configure the Proportional font family with YujiSyuku as primary and NotoSerifJP as fallback
-            if let Some(proportional) = fonts.families.get_mut(&egui::FontFamily::Proportional) {
-                proportional.clear();
-                proportional.push("NotoSerifJP".to_owned());
-            }
-
-            fonts.families.insert(
-                egui::FontFamily::Name("main".into()),
-                vec!["Merienda".to_owned(), "YujiSyuku".to_owned(), "ShipporiAntique".to_owned(), "NotoSerifJP".to_owned()],
-            );
-
-            fonts.families.insert(
-                egui::FontFamily::Name("kana".into()),
-                vec!["YujiSyuku".to_owned()],
-            );
-
-            fonts.families.insert(
-                egui::FontFamily::Name("ruby".into()),
-                vec!["Merienda".to_owned(), "ShipporiAntique".to_owned(), "YujiSyuku".to_owned(), "KaiseiHarunoUmi".to_owned(), "NotoSerifJP".to_owned()],
-            );
-
-            fonts.families.insert(
-                egui::FontFamily::Name("app_title".into()),
-                vec!["Hurricane".to_owned()],
-            );
-
-            // Apply the customized fonts to the egui context
-            cc.egui_ctx.set_fonts(fonts);
+            // Configure font definitions (bundled fonts and the ones chosen in the settings)
+            let app = gui::TypingApp::default();
+            cc.egui_ctx.set_fonts(app.font_definitions());
 
             // Set the default theme to dark mode
             let style = egui::Style {
@@ -94,7 +43,7 @@ fn main() {
             };
             cc.egui_ctx.set_style(style);
 
-            Ok(Box::new(gui::TypingApp::default()))
+            Ok(Box::new(app))
         }),
     ).ok();
 }
\ No newline at end of file
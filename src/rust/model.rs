@@ -1,7 +1,9 @@
 // model.rs
 
 use serde::{Serialize, Deserialize};
-use crate::{parser::Content, typing};
+use crate::{parser::{Content, Line}, typing};
+use crate::rng::Rng;
+use crate::layout::LayoutConflict;
 use std::collections::HashMap;
 
 #[derive(Debug, Clone)]
@@ -9,6 +11,9 @@ pub struct MenuModel {
     pub available_contents: Vec<Content>,
     pub selecting: usize,
     pub layout: TextConvert,
+    pub preferences: SpellingPreferences,
+    pub review: ReviewSchedule,
+    pub options: TypingOptions,
     pub error_messages: Vec<ErrorMsg>,
 }
 
@@ -17,6 +22,9 @@ pub struct TypingStartModel {
     pub content: Content,
     pub available_contents: Vec<Content>,
     pub layout: TextConvert,
+    pub preferences: SpellingPreferences,
+    pub review: ReviewSchedule,
+    pub options: TypingOptions,
     pub scroll_max: f64,
 }
 
@@ -25,11 +33,24 @@ pub struct TypingModel {
     pub content: Content,
     pub typing_correctness: TypingCorrectnessContent,
     pub user_input: Vec<TypingSession>,
+    pub timing_events: Vec<TimingEvent>,
     pub status: TypingStatus,
     pub available_contents: Vec<Content>,
     pub layout: TextConvert,
+    pub preferences: SpellingPreferences,
+    pub review: ReviewSchedule,
+    pub options: TypingOptions,
     pub keyboard_remapping: KeyboardRemapping,
     pub scroll: TypingScroll,
+    pub repeat_mistakes: bool, // 終了時に誤りがあれば、その部分だけでもう一度行う
+    pub hints: Vec<(i32, i32)>, // ヒントとして表示した(行, セグメント)
+    pub pool: Vec<Line>,        // タイムアタックで繰り返す行
+    pub keystrokes: Vec<LineKeystrokes>, // 行ごとの打鍵数 (content.lines と同じ順。毎フレーム求め直さないよう、行を出題したときに求める)
+    pub latencies: HashMap<i32, f64>, // 行ごとの最初の打鍵までの時間 (行の最初の打鍵で記録する)
+    pub source: Content,        // 行を選ぶ前の問題 (やり直すときはここから選び直す)
+    pub selection: Vec<usize>,  // 出題した行の、元の問題での番号
+    pub selection_label: String, // 行の選び方の説明
+    pub rng: Rng,
 }
 
 #[derive(Debug, Clone)]
@@ -40,11 +61,35 @@ pub struct PauseModel {
 #[derive(Debug, Clone)]
 pub struct ResultModel {
     pub typing_model: TypingModel,
+    pub outcome: SessionOutcome,
+    pub line_metrics: Vec<TypingMetrics>, // 入力した行ごとの成績 (表示のたびに集計し直さないよう、終了時に集計する)
     // pub start_time: Option<f64>,
     // pub end_time: Option<f64>,
     // pub pause_time: Option<f64>,
 }
 
+#[derive(Debug, Clone)]
+pub struct LayoutEditorModel {
+    pub layout: TextConvert,
+    pub original_layout: TextConvert,
+    pub preferences: SpellingPreferences,
+    pub review: ReviewSchedule,
+    pub original_preferences: SpellingPreferences,
+    pub options: TypingOptions,
+    pub available_contents: Vec<Content>,
+    pub selecting: usize,
+    pub conflicts: Vec<LayoutConflict>,
+}
+
+/// セッションの終わり方
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SessionOutcome {
+    Cleared,  // 最後の行まで入力した
+    TimeUp,   // タイムアタックの時間切れ
+    Failed,   // サバイバルでミスが許される数を超えた
+    Canceled, // 一時停止から中断した
+}
+
 // ------------------------------------
 // Top-level Model enum
 // ------------------------------------
@@ -56,6 +101,7 @@ pub enum Model {
     Typing(TypingModel),
     Pause(PauseModel),
     Result(ResultModel),
+    LayoutEditor(LayoutEditorModel),
 }
 
 
@@ -70,6 +116,7 @@ pub struct TypingStatus {
     pub char_: i32,
     pub unconfirmed: Vec<char>,
     pub last_wrong_keydown: Option<char>,
+    pub segment_misses: i32, // 現在のセグメントでのミス数
 }
 
 #[derive(Debug, Clone)]
@@ -78,6 +125,69 @@ pub struct TypingSession {
     pub inputs: Vec<TypingInput>,
 }
 
+/// 時間の集計の区切りになる出来事
+#[derive(Debug, Clone, PartialEq)]
+pub enum TimingEventKind {
+    LineStart(i32), // 行が入力対象になった
+    Pause,
+    Resume(i32),    // 一時停止から再開した (再開時の行)
+    Finish,
+}
+
+#[derive(Debug, Clone)]
+pub struct TimingEvent {
+    pub kind: TimingEventKind,
+    pub timestamp: f64,
+}
+
+/// タイピングの設定
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TypingOptions {
+    pub afk_threshold: Option<f64>, // これより長い打鍵間隔は放置とみなし、有効時間に含めない（ミリ秒）
+    pub karuta: Option<usize>,      // 暗記モード: 各行の先頭から表示するセグメント数 (残りは隠す)
+    pub dictation: Option<Dictation>, // 書き取りモード
+    pub time_attack: Option<TimeAttack>,
+    pub selection: LineSelection,
+    pub survival: Option<Survival>,
+}
+
+/// サバイバル: ミスが許される数を超えたら失敗
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Survival {
+    pub lives: i32,        // 許されるミスの数
+    pub consecutive: bool, // 連続した誤入力の数で数える (falseなら全体のミス数)
+}
+
+/// 出題する行の選び方
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LineSelection {
+    pub range: Option<(usize, usize)>, // 出題する行の範囲 (先頭の行, 末尾の次の行)
+    pub sample: Option<usize>,         // 範囲からランダムに選ぶ行数
+    pub shuffle: bool,                 // 行を並べ替える
+    pub seed: u64,                     // 並べ替えと選択に使う乱数のシード
+}
+
+/// タイムアタック: 制限時間まで行を繰り返す
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimeAttack {
+    pub duration: f64, // 制限時間（ミリ秒）
+    pub shuffle: bool, // 周回ごとに行を並べ替える
+}
+
+/// 書き取りモード
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Dictation {
+    BaseOnly, // 漢字だけを表示し、読みは表示しない
+    Hidden,   // 入力するまで何も表示しない
+}
+
+/// 行の入力に必要な打鍵数
+#[derive(Debug, Clone, Copy)]
+pub struct LineKeystrokes {
+    pub guide: i32,         // 優先綴りで入力した場合の打鍵数
+    pub ideal: Option<i32>, // 最小打鍵数 (入力できない文字を含む行はNone)
+}
+
 #[derive(Debug, Clone)]
 pub struct TypingInput {
     pub key: char,
@@ -96,6 +206,30 @@ pub struct TextConvert {
     pub mapping: Vec<(String, Vec<String>)>,
 }
 
+/// 仮名ごとにユーザーが優先する綴り (例: 「し」は常に"si")
+/// 指定の無い仮名はレイアウトで最初に書かれた綴りが優先される
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SpellingPreferences {
+    pub preferred: HashMap<String, String>,
+}
+
+/// 行ごとの復習の記録 (SM-2)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReviewCard {
+    pub ease: f64,        // 易しさ係数（1.3以上）
+    pub interval: f64,    // 次の復習までの間隔（日）
+    pub repetitions: i32, // 続けて覚えていた回数
+    pub due: f64,         // 次に復習する日時（UNIX時間, ミリ秒）
+}
+
+/// 復習の予定 (行の内容をキーとする)
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ReviewSchedule {
+    pub cards: HashMap<String, ReviewCard>,
+    #[serde(skip)]
+    pub revision: u32, // 記録した回数 (アプリが記録したことを知って保存するため)
+}
+
 #[derive(Debug, Clone)]
 pub struct KeyboardRemapping {
     pub mapping: HashMap<char, char>,
@@ -135,7 +269,40 @@ pub struct TypingScroll {
 pub struct TypingMetrics {
     pub miss_count: i32,      // タイプミス数
     pub type_count: i32,      // タイプ数（正解のみ）
-    pub total_time: f64,      // 合計時間（ミリ秒）
+    pub elapsed_time: f64,    // 経過時間（一時停止中を除く, ミリ秒）
+    pub total_time: f64,      // 有効時間（放置していた時間を除く, ミリ秒）
+    pub typing_time: f64,     // 打鍵中の時間（有効時間から行の最初の打鍵までを除く, ミリ秒）
+    pub char_count: i32,      // 入力を終えた文字数
+    pub guide_count: i32,     // 優先綴りで入力した場合の打鍵数
+    pub ideal_count: i32,     // 最小打鍵数
     pub accuracy: f64,        // 正確さ（0.0 - 1.0）
-    pub speed: f64,           // 速さ（タイプ/秒）
-}
\ No newline at end of file
+    pub speed: f64,           // 速さ（タイプ/秒, 打鍵中の時間あたり）
+    pub efficiency: f64,      // 効率（最小打鍵数/正解タイプ数）
+    pub latency: f64,         // 行の最初の打鍵までの時間（ミリ秒, 全体では平均）
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RhythmMetrics {
+    pub median_interval: f64, // 打鍵間隔の中央値（ミリ秒）
+    pub p90_interval: f64,    // 打鍵間隔の90パーセンタイル（ミリ秒）
+    pub std_dev: f64,         // 打鍵間隔の標準偏差（ミリ秒）
+    pub longest_pause: f64,   // 最も長い打鍵間隔（ミリ秒）
+    pub burst_speed: f64,     // 最も速い10秒間の速さ（タイプ/秒）
+}
+
+/// セグメントの表示方法
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SegmentDisplay {
+    Full,
+    BaseOnly, // 読み(ルビ)を表示しない
+    Hidden,
+}
+
+/// 速さの表示単位
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SpeedUnit {
+    KeysPerSecond,
+    KeysPerMinute,
+    CharsPerMinute,
+    WordsPerMinute,
+}
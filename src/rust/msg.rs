@@ -2,12 +2,16 @@
 
 use serde::{Serialize, Deserialize};
 use crate::parser::Content;
+use crate::model::TypingOptions;
 
 #[derive(Debug, Clone)]
 pub enum MenuMsg {
     MoveCursor(usize),
     AddContent(String),
-    Start
+    Start,
+    StartReview,
+    SetOptions(TypingOptions),
+    OpenLayoutEditor,
 }
 
 #[derive(Debug, Clone)]
@@ -15,12 +19,14 @@ pub enum TypingStartMsg {
     StartTyping,
     Cancel,
     ScrollMax(f64),
+    SetOptions(TypingOptions),
 }
 
 #[derive(Debug, Clone)]
 pub enum TypingMsg {
     KeyInput(char),
     Pause,
+    Tick,
     ScrollTo(f64,f64),
 }
 
@@ -34,6 +40,22 @@ pub enum PauseMsg {
 pub enum ResultMsg {
     BackToMenu,
     Retry,
+    DrillLine(usize),
+    DrillMistakes,
+}
+
+#[derive(Debug, Clone)]
+pub enum LayoutEditorMsg {
+    Select(usize),
+    AddEntry(String),
+    RemoveEntry,
+    AddSpelling(String),
+    RemoveSpelling(usize),
+    MoveSpellingUp(usize),
+    MoveSpellingDown(usize),
+    SetPreferred(Option<usize>),
+    Apply,
+    Cancel,
 }
 
 #[derive(Debug, Clone)]
@@ -43,4 +65,5 @@ pub enum Msg {
     TypingStart(TypingStartMsg),
     Pause(PauseMsg),
     Result(ResultMsg),
+    LayoutEditor(LayoutEditorMsg),
 }
\ No newline at end of file
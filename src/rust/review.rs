@@ -0,0 +1,176 @@
+// review.rs
+// 間隔反復(SM-2)による行ごとの復習の予定
+
+use std::collections::{HashMap, HashSet};
+use chrono::{Local, TimeZone};
+use crate::model::{TypingModel, TypingMetrics, ReviewCard, ReviewSchedule};
+use crate::parser::{Content, Line, Segment};
+use crate::typing::{calculate_line_metrics, calculate_total_metrics};
+
+const DAY: f64 = 24.0 * 60.0 * 60.0 * 1000.0;
+
+/// 復習の記録のキー (読みも含めた行の内容)
+pub fn line_key(line: &Line) -> String {
+    line.segments.iter()
+        .map(|segment| match segment {
+            Segment::Plain { text } => text.clone(),
+            Segment::Annotated { base, reading } => format!("({}/{})", base, reading),
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// `now` の日が終わる時刻 (ローカル時刻の翌日0時)
+fn end_of_day(now: f64) -> f64 {
+    Local.timestamp_millis_opt(now as i64).single()
+        .and_then(|time| time.date_naive().succ_opt())
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .and_then(|midnight| Local.from_local_datetime(&midnight).earliest())
+        .map_or(now, |end| end.timestamp_millis() as f64)
+}
+
+/// 行の成績を0〜5の評価にする
+/// 正確さで決め、全体の平均よりかなり遅い行(思い出すのに時間がかかった行)は一段階下げる
+fn grade(metrics: &TypingMetrics, average_speed: f64) -> i32 {
+    let mut quality = if metrics.accuracy >= 1.0 {
+        5
+    } else if metrics.accuracy >= 0.95 {
+        4
+    } else if metrics.accuracy >= 0.85 {
+        3
+    } else if metrics.accuracy >= 0.7 {
+        2
+    } else {
+        1
+    };
+    if average_speed > 0.0 && metrics.speed < average_speed * 0.6 {
+        quality -= 1;
+    }
+    quality.max(0)
+}
+
+impl ReviewCard {
+    fn new(now: f64) -> Self {
+        ReviewCard {
+            ease: 2.5,
+            interval: 0.0,
+            repetitions: 0,
+            due: now,
+        }
+    }
+
+    /// 評価(0〜5)から次の復習日を決める
+    pub fn review(&mut self, quality: i32, now: f64) {
+        if quality >= 3 {
+            self.interval = match self.repetitions {
+                0 => 1.0,
+                1 => 6.0,
+                _ => (self.interval * self.ease).round(),
+            };
+            self.repetitions += 1;
+        } else {
+            // 覚えていなかったので最初から
+            self.repetitions = 0;
+            self.interval = 1.0;
+        }
+        let q = (5 - quality) as f64;
+        self.ease = (self.ease + 0.1 - q * (0.08 + q * 0.02)).max(1.3);
+        self.due = now + self.interval * DAY;
+    }
+}
+
+impl ReviewSchedule {
+    /// 入力を終えた行の成績を記録する
+    /// 読み込まれている問題に無い行(ミスの練習で作った行など)は記録しない
+    /// 同じ行を何度も入力した場合(タイムアタックの周回など)は、最も悪い評価で一度だけ記録する
+    pub fn record(&mut self, model: &TypingModel, now: f64) {
+        let known = model.available_contents.iter()
+            .flat_map(|content| content.lines.iter())
+            .map(line_key)
+            .collect::<HashSet<String>>();
+        let average_speed = calculate_total_metrics(model).speed;
+        let finished_lines = (model.status.line as usize).min(model.content.lines.len());
+        let mut qualities: HashMap<String, i32> = HashMap::new();
+        for (index, line) in model.content.lines.iter().enumerate().take(finished_lines) {
+            let key = line_key(line);
+            if !known.contains(&key) {
+                continue;
+            }
+            let quality = grade(&calculate_line_metrics(model, index as i32), average_speed);
+            qualities.entry(key).and_modify(|worst| *worst = (*worst).min(quality)).or_insert(quality);
+        }
+        for (key, quality) in qualities {
+            self.cards.entry(key).or_insert_with(|| ReviewCard::new(now)).review(quality, now);
+        }
+        self.revision += 1;
+    }
+
+    /// 今日のうちに復習の時期が来る行を、予定日の古い順に集める
+    pub fn due_lines(&self, contents: &[Content], now: f64) -> Vec<Line> {
+        let end = end_of_day(now);
+        let mut seen = HashSet::new();
+        let mut due = contents.iter()
+            .flat_map(|content| content.lines.iter())
+            .filter_map(|line| {
+                let key = line_key(line);
+                let card = self.cards.get(&key)?;
+                (card.due < end && seen.insert(key)).then(|| (card.due, line.clone()))
+            })
+            .collect::<Vec<_>>();
+        due.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        due.into_iter().map(|(_, line)| line).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sm2_progression() {
+        let mut card = ReviewCard::new(0.0);
+        card.review(5, 0.0);
+        assert_eq!((card.repetitions, card.interval), (1, 1.0));
+        assert!((card.ease - 2.6).abs() < 1e-9);
+        card.review(4, 0.0);
+        assert_eq!((card.repetitions, card.interval), (2, 6.0));
+        assert!((card.ease - 2.6).abs() < 1e-9);
+        card.review(3, 0.0);
+        assert_eq!((card.repetitions, card.interval), (3, 16.0));
+        assert!((card.ease - 2.46).abs() < 1e-9);
+        assert_eq!(card.due, 16.0 * DAY);
+        // 覚えていなければ最初から (易しさは下限まで)
+        card.review(0, 0.0);
+        assert_eq!((card.repetitions, card.interval), (0, 1.0));
+        assert!((card.ease - 1.66).abs() < 1e-9);
+        card.review(0, 0.0);
+        assert_eq!(card.ease, 1.3);
+    }
+
+    #[test]
+    fn repeated_lines_are_reviewed_once() {
+        use crate::model::{Model, TypingStartModel, TypingOptions, SpellingPreferences};
+        use crate::typing::{start_typing, key_input};
+        let content = crate::parser::parse_problem("#title test\nか\nか");
+        let mut model = start_typing(TypingStartModel {
+            content: content.clone(),
+            available_contents: vec![content],
+            layout: crate::layout::parse_layout(r#"{"か":["ka"]}"#).unwrap(),
+            preferences: SpellingPreferences::default(),
+            review: ReviewSchedule::default(),
+            options: TypingOptions::default(),
+            scroll_max: 0.0,
+        });
+        for key in "kaka".chars() {
+            model = match key_input(model, key) {
+                Model::Typing(model) => model,
+                Model::Result(result) => result.typing_model,
+                _ => panic!("unexpected model"),
+            };
+        }
+        let mut schedule = ReviewSchedule::default();
+        schedule.record(&model, 0.0);
+        let card = &schedule.cards[&line_key(&model.content.lines[0])];
+        assert_eq!((card.repetitions, card.interval), (1, 1.0));
+    }
+}
@@ -0,0 +1,115 @@
+// storage.rs
+// ユーザー設定などを保存する (ネイティブ: 設定ディレクトリのファイル, Web: localStorage)
+
+#[cfg(feature = "web")]
+fn local_storage() -> Option<web_sys::Storage> {
+    web_sys::window()?.local_storage().ok()?
+}
+
+#[cfg(feature = "web")]
+pub fn load(key: &str) -> Option<String> {
+    local_storage()?.get_item(&format!("neknaj_typing.{}", key)).ok()?
+}
+
+#[cfg(feature = "web")]
+pub fn save(key: &str, value: &str) {
+    if let Some(storage) = local_storage() {
+        let _ = storage.set_item(&format!("neknaj_typing.{}", key), value);
+    }
+}
+
+#[cfg(not(feature = "web"))]
+fn storage_dir() -> Option<std::path::PathBuf> {
+    use std::path::PathBuf;
+    let base = if cfg!(target_os = "windows") {
+        std::env::var_os("APPDATA").map(PathBuf::from)
+    } else {
+        std::env::var_os("XDG_CONFIG_HOME").map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+    };
+    base.map(|dir| dir.join("neknaj_typing"))
+}
+
+#[cfg(not(feature = "web"))]
+pub fn load(key: &str) -> Option<String> {
+    std::fs::read_to_string(storage_dir()?.join(format!("{}.json", key))).ok()
+}
+
+#[cfg(not(feature = "web"))]
+pub fn save(key: &str, value: &str) {
+    if let Some(dir) = storage_dir() {
+        if let Err(e) = std::fs::create_dir_all(&dir).and_then(|_| std::fs::write(dir.join(format!("{}.json", key)), value)) {
+            eprintln!("Storage write error: {}", e);
+        }
+    }
+}
+
+/// JSONとして保存された値を読み込む (無い/壊れている場合はNone)
+pub fn load_json<T: serde::de::DeserializeOwned>(key: &str) -> Option<T> {
+    serde_json::from_str(&load(key)?).ok()
+}
+
+pub fn save_json<T: serde::Serialize>(key: &str, value: &T) {
+    if let Ok(json) = serde_json::to_string(value) {
+        save(key, &json);
+    }
+}
+
+#[cfg(any(target_arch = "wasm32", test))]
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// バイト列を Base64 の文字列にする (localStorage には文字列しか保存できないため)
+#[cfg(any(target_arch = "wasm32", test))]
+pub fn encode_base64(data: &[u8]) -> String {
+    let mut text = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, byte)| bits | (*byte as u32) << (16 - i * 8));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                text.push(BASE64[(bits >> (18 - i * 6)) as usize & 0x3F] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+    text
+}
+
+/// Base64 の文字列をバイト列に戻す (壊れている場合はNone)
+#[cfg(any(target_arch = "wasm32", test))]
+pub fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let text = text.trim_end_matches('=').as_bytes();
+    let mut data = Vec::with_capacity(text.len() * 3 / 4);
+    for chunk in text.chunks(4) {
+        if chunk.len() == 1 {
+            return None;
+        }
+        let mut bits = 0u32;
+        for (i, c) in chunk.iter().enumerate() {
+            let value = BASE64.iter().position(|b| b == c)? as u32;
+            bits |= value << (18 - i * 6);
+        }
+        for i in 0..chunk.len() - 1 {
+            data.push((bits >> (16 - i * 8)) as u8);
+        }
+    }
+    Some(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64_round_trip() {
+        assert_eq!(encode_base64(b"Man"), "TWFu");
+        assert_eq!(encode_base64(b"Ma"), "TWE=");
+        assert_eq!(encode_base64(b"M"), "TQ==");
+        for len in 0..8 {
+            let data = (0..len).map(|i| (i * 97 + 200) as u8).collect::<Vec<u8>>();
+            assert_eq!(decode_base64(&encode_base64(&data)), Some(data));
+        }
+        assert_eq!(decode_base64("T"), None);
+        assert_eq!(decode_base64("T!=="), None);
+    }
+}
@@ -1,3 +1,5 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
 use crate::parser::{Content, Line, Segment};
 
 /// Enum to specify character orientation.
@@ -7,6 +9,53 @@ pub enum CharOrientation {
     Horizontal,
 }
 
+/// フォントの大きさの種類 (キャッシュのキー)
+type FontKey = (String, u32);
+
+fn font_key(font_id: &egui::FontId) -> FontKey {
+    (font_id.family.to_string(), font_id.size.to_bits())
+}
+
+/// 文字の配置と描画に使うGalleyのキャッシュ
+/// 毎フレーム全ての文字を測り直さないよう、フレームをまたいで使い回す
+struct LayoutCache {
+    /// キャッシュを作ったときのフォントのテクスチャ
+    /// フォントやpixels_per_pointが変わるとテクスチャが作り直され、それまでのGalleyは使えなくなる
+    atlas: usize,
+    /// (文字列, フォント, 縦書きか) ごとの文字の配置
+    glyphs: BTreeMap<(String, FontKey, bool), Vec<Glyph>>,
+    /// (文字, フォント) ごとのGalley (色は描くときに決める)
+    galleys: BTreeMap<(char, FontKey), Arc<egui::Galley>>,
+}
+
+/// キャッシュがこれより大きくなったら捨てる
+const LAYOUT_CACHE_CAPACITY: usize = 4096;
+
+static LAYOUT_CACHE: Mutex<LayoutCache> = Mutex::new(LayoutCache { atlas: 0, glyphs: BTreeMap::new(), galleys: BTreeMap::new() });
+
+/// キャッシュを使えるようにして返す (フォントのテクスチャが変わっていたら捨てる)
+fn layout_cache(ui: &egui::Ui) -> std::sync::MutexGuard<'static, LayoutCache> {
+    let atlas = ui.ctx().fonts(|fonts| Arc::as_ptr(&fonts.texture_atlas()) as usize);
+    let mut cache = LAYOUT_CACHE.lock().unwrap();
+    if cache.atlas != atlas || cache.glyphs.len()+cache.galleys.len() > LAYOUT_CACHE_CAPACITY {
+        cache.atlas = atlas;
+        cache.glyphs.clear();
+        cache.galleys.clear();
+    }
+    cache
+}
+
+/// 文字1つのGalley
+fn char_galley(ui: &egui::Ui, ch: char, font_id: &egui::FontId) -> Arc<egui::Galley> {
+    let key = (ch, font_key(font_id));
+    if let Some(galley) = layout_cache(ui).galleys.get(&key) {
+        return galley.clone();
+    }
+    let galley = ui.painter().layout_no_wrap(ch.to_string(), font_id.clone(), egui::Color32::PLACEHOLDER);
+    layout_cache(ui).galleys.insert(key, galley.clone());
+    galley
+}
+
 /// Helper function to render a single character at a given position.
 /// Returns the size of the rendered character.
 fn render_char_at(
@@ -17,9 +66,18 @@ fn render_char_at(
     font_id: &egui::FontId,
     color: egui::Color32,
 ) -> egui::Vec2 {
-    // Convert the character to a string for layout.
-    // Clone the font_id to pass ownership.
-    let galley = ui.painter().layout_no_wrap(ch.to_string(), font_id.clone(), color);
+    let galley = char_galley(ui, ch, font_id);
+    paint_galley(ui, galley, pos, orientation, color)
+}
+
+/// Galleyを中心の位置に合わせて描く
+fn paint_galley(
+    ui: &mut egui::Ui,
+    galley: Arc<egui::Galley>,
+    pos: egui::Pos2,
+    orientation: CharOrientation,
+    color: egui::Color32,
+) -> egui::Vec2 {
     // Determine rotation angle in radians.
     let angle_rad = match orientation {
         CharOrientation::Horizontal => 0.0,
@@ -67,6 +125,523 @@ fn is_japanese_hiragana(c: char) -> bool {
     (code >= 0x3040 && code <= 0x309F) // ひらがな
 }
 
+/// 全角英数・記号
+fn is_fullwidth(c: char) -> bool {
+    let code = c as u32;
+    (0xFF01..=0xFF60).contains(&code)
+}
+
+/// 小書きの仮名
+fn is_small_kana(c: char) -> bool {
+    [
+        '\u{3041}','\u{3043}','\u{3045}','\u{3047}','\u{3049}','\u{3063}','\u{3083}','\u{3085}','\u{3087}','\u{308e}','\u{3095}','\u{3096}',
+        '\u{30a1}','\u{30a3}','\u{30a5}','\u{30a7}','\u{30a9}','\u{30c3}','\u{30e3}','\u{30e5}','\u{30e7}','\u{30ee}','\u{30f5}','\u{30f6}'
+    ].contains(&c)
+}
+
+/// 縦書きでの字形の扱い
+#[derive(Clone, Copy, PartialEq)]
+enum VerticalForm {
+    /// そのまま正立させる
+    Upright,
+    /// 90°回転させる (長音符・ダッシュ・括弧など)
+    Rotated,
+    /// 字面を右上に寄せる (句読点)
+    Corner,
+    /// 小書きの仮名 (少し右上に寄せる)
+    Small,
+}
+
+/// 縦書きの字形の表
+/// フォントに縦書き用の字形(U+FE10〜など)が無くても崩れないよう、置き換えではなく回転と位置のずれで表す
+fn vertical_form(c: char) -> VerticalForm {
+    match c {
+        'ー' | '〜' | '～' | '…' | '‥' | '—' | '―' | '–' | '－' | '＝' | '：' | '；' | '＿' | '｜' |
+        '「' | '」' | '『' | '』' | '（' | '）' | '〔' | '〕' | '【' | '】' | '《' | '》' | '〈' | '〉' |
+        '［' | '］' | '｛' | '｝' | '〖' | '〗' | '〘' | '〙' | '＜' | '＞' | '→' | '←' => VerticalForm::Rotated,
+        '、' | '。' | '，' | '．' => VerticalForm::Corner,
+        c if is_small_kana(c) => VerticalForm::Small,
+        _ => VerticalForm::Upright,
+    }
+}
+
+/// 文字1つ分の配置
+#[derive(Clone)]
+struct Glyph {
+    ch: char,
+    /// 描画に使うフォント
+    font: egui::FontId,
+    /// 本文のフォントで測った文字の大きさ
+    size: egui::Vec2,
+    /// 行の進む方向の送り幅
+    advance: f32,
+    /// 行と直交する方向の大きさ
+    extent: f32,
+    /// 文字枠の中心からのずれ
+    shift: egui::Vec2,
+    /// 90°回転して描くか
+    rotated: bool,
+    /// `font` で組んだGalley
+    galley: Arc<egui::Galley>,
+}
+
+/// 本文の文字1つの送り幅・フォント・ずれを決める
+fn layout_glyph(ui: &egui::Ui, ch: char, orientation: &CharOrientation, font_main: &egui::FontId) -> Glyph {
+    let size = char_galley(ui, ch, font_main).size();
+    let mut font = font_main.clone();
+    let mut shift = egui::Vec2::ZERO;
+    let mut rotated = false;
+    let kana_font = |scale: f32| egui::FontId::new(font_main.size*scale, egui::FontFamily::Name("kana".into()));
+    let (advance, extent) = match (orientation, is_japanese(ch)) {
+        (CharOrientation::Horizontal, true) => {
+            if is_japanese_kana(ch) {
+                shift.y = if is_japanese_hiragana(ch) { size.y*0.03 } else { size.y*0.01 };
+            }
+            if is_japanese_kana(ch) && ch != '\u{30fc}' {
+                font = kana_font(if is_japanese_hiragana(ch) { 0.85 } else { 0.95 });
+                if is_small_kana(ch) {
+                    font = kana_font(if is_japanese_hiragana(ch) { 0.8 } else { 0.9 });
+                    shift += egui::vec2(-size.x/100.0, size.y/80.0);
+                }
+            } else if ch == '\u{30fc}' {
+                font.size = font_main.size*0.8;
+            }
+            (if is_japanese_kana(ch) { size.x*0.8 } else { size.x }, size.y)
+        },
+        (CharOrientation::Horizontal, false) => {
+            shift.y = size.y/20.0;
+            font.size = font_main.size*0.85;
+            (size.x*0.8, size.y)
+        },
+        (CharOrientation::Vertical, _) if is_japanese(ch) || is_fullwidth(ch) => {
+            let mut advance = if is_japanese_kana(ch) { size.x*0.85 } else { size.x };
+            match vertical_form(ch) {
+                VerticalForm::Rotated => {
+                    rotated = true;
+                    font.size = font_main.size*0.93;
+                    advance = size.x;
+                },
+                VerticalForm::Corner => {
+                    shift = egui::vec2(font_main.size*0.55, -font_main.size*0.55);
+                },
+                VerticalForm::Small => {
+                    font = kana_font(if is_japanese_hiragana(ch) { 0.8 } else { 0.9 });
+                    shift = egui::vec2(size.x/10.0, -size.y/10.0);
+                },
+                VerticalForm::Upright => {
+                    if is_japanese_kana(ch) {
+                        font = kana_font(if is_japanese_hiragana(ch) { 0.85 } else { 0.95 });
+                    }
+                },
+            }
+            (advance, size.x)
+        },
+        (CharOrientation::Vertical, _) => {
+            rotated = true;
+            shift.x = font_main.size/100.0;
+            font.size = font_main.size*0.9;
+            (size.x*0.75, size.y)
+        },
+    };
+    let galley = char_galley(ui, ch, &font);
+    Glyph { ch, font, size, advance, extent, shift, rotated, galley }
+}
+
+/// 縦中横にする連続した半角英数の最大の長さ
+const TATE_CHU_YOKO_MAX: usize = 3;
+
+/// 縦書きで半角英数の短い連なりを1文字分の枠に横に並べる (縦中横)
+/// 文字ごとの色やカーソルの位置が変わらないよう、枠を文字数で等分して送り幅とし、ずれで横に並べる
+fn set_tate_chu_yoko(ui: &egui::Ui, glyphs: &mut [Glyph], font_main: &egui::FontId) {
+    let cell = font_main.size;
+    let natural = glyphs.iter().map(|glyph| glyph.size.x*0.8).sum::<f32>();
+    let scale = (cell*0.9/natural).min(0.9);
+    let widths = glyphs.iter().map(|glyph| glyph.size.x*0.8*scale).collect::<Vec<_>>();
+    let total = widths.iter().sum::<f32>();
+    let step = cell/glyphs.len() as f32;
+    let mut x = -total/2.0;
+    for (index, (glyph, width)) in glyphs.iter_mut().zip(widths).enumerate() {
+        glyph.font.size = font_main.size*scale;
+        glyph.galley = char_galley(ui, glyph.ch, &glyph.font);
+        glyph.rotated = false;
+        glyph.advance = step;
+        glyph.extent = cell;
+        glyph.shift = egui::vec2(x+width/2.0, cell/2.0-(index as f32+0.5)*step);
+        x += width;
+    }
+}
+
+fn layout_glyphs(ui: &egui::Ui, text: &str, orientation: &CharOrientation, font_main: &egui::FontId) -> Vec<Glyph> {
+    let key = (text.to_string(), font_key(font_main), *orientation == CharOrientation::Vertical);
+    if let Some(glyphs) = layout_cache(ui).glyphs.get(&key) {
+        return glyphs.clone();
+    }
+    let glyphs = layout_glyphs_uncached(ui, text, orientation, font_main);
+    layout_cache(ui).glyphs.insert(key, glyphs.clone());
+    glyphs
+}
+
+fn layout_glyphs_uncached(ui: &egui::Ui, text: &str, orientation: &CharOrientation, font_main: &egui::FontId) -> Vec<Glyph> {
+    let mut glyphs = text.chars().map(|ch| layout_glyph(ui, ch, orientation, font_main)).collect::<Vec<_>>();
+    if *orientation == CharOrientation::Vertical {
+        // 半角英数の連なりを探し、短いものを縦中横にする
+        let mut start = 0;
+        while start < glyphs.len() {
+            let end = glyphs[start..].iter().position(|glyph| !glyph.ch.is_ascii_alphanumeric()).map_or(glyphs.len(), |length| start+length);
+            if end == start {
+                start += 1;
+                continue;
+            }
+            if end-start <= TATE_CHU_YOKO_MAX {
+                set_tate_chu_yoko(ui, &mut glyphs[start..end], font_main);
+            }
+            start = end;
+        }
+    }
+    glyphs
+}
+
+/// 文字を描く
+/// `pos` は行の進む方向では文字枠の始まり、直交する方向では文字の中心
+fn paint_glyph(ui: &mut egui::Ui, glyph: &Glyph, pos: egui::Pos2, orientation: &CharOrientation, color: egui::Color32) {
+    let center = match orientation {
+        CharOrientation::Horizontal => egui::pos2(pos.x+glyph.advance/2.0, pos.y),
+        CharOrientation::Vertical => egui::pos2(pos.x, pos.y+glyph.advance/2.0),
+    } + glyph.shift;
+    let rotation = if glyph.rotated { CharOrientation::Vertical } else { CharOrientation::Horizontal };
+    paint_galley(ui, glyph.galley.clone(), center, rotation, color);
+}
+
+/// 行の進む方向で表示範囲(0〜max)に入っているか
+fn is_visible(position: f32, size: f32, max: f32) -> bool {
+    position+size >= 0.0 && position <= max
+}
+
+/// ルビの文字を1つ描く (`pos` は文字の中心)
+fn paint_ruby_char(ui: &mut egui::Ui, ch: char, pos: egui::Pos2, orientation: &CharOrientation, font_ruby: &egui::FontId, color: egui::Color32) {
+    let size = char_galley(ui, ch, font_ruby).size();
+    let (pos, rotation) = match (orientation, vertical_form(ch)) {
+        (CharOrientation::Horizontal, VerticalForm::Small) => (pos+egui::vec2(-size.x/100.0, size.y/80.0), CharOrientation::Horizontal),
+        (CharOrientation::Horizontal, _) => (pos, CharOrientation::Horizontal),
+        (CharOrientation::Vertical, VerticalForm::Small) => (pos+egui::vec2(size.x/10.0, -size.y/10.0), CharOrientation::Horizontal),
+        (CharOrientation::Vertical, VerticalForm::Rotated) => (pos, CharOrientation::Vertical),
+        (CharOrientation::Vertical, VerticalForm::Corner) => (pos+egui::vec2(font_ruby.size*0.55, -font_ruby.size*0.55), CharOrientation::Horizontal),
+        (CharOrientation::Vertical, VerticalForm::Upright) => (pos, CharOrientation::Horizontal),
+    };
+    render_char_at(ui, ch, pos, rotation, font_ruby, color);
+}
+
+/// ルビの文字と、親文字の始まりからの位置 (行の進む方向での文字の中心)
+type RubyLayout = Vec<(char, f32)>;
+
+/// 隣の文字の上に読みをはみ出させてよい長さ
+/// 読みの無い仮名にはルビ1文字分 (親文字の半分) まで、隣のルビ(熟語ルビ)にはその読みの空いている所まで
+fn ruby_overhang(segment: &Segment, glyph: &Glyph, free: f32, font_ruby: &egui::FontId) -> f32 {
+    match segment {
+        Segment::Annotated { .. } => free,
+        Segment::Plain { text: _ } if is_japanese_kana(glyph.ch) => font_ruby.size.min(glyph.advance*0.5),
+        Segment::Plain { text: _ } => 0.0,
+    }
+}
+
+/// 読みの位置を決める (JIS X 4051 のルビの配置に倣う)
+/// - 読みが親文字より短いときは、親文字の長さに 1:2:1 の割合で字間をあけて並べる (グループルビ)
+/// - 長いときは読みをベタ組みにして、前後の仮名や隣のルビの空きにはみ出させる
+/// - それでも収まらない分は親文字の字間をあける (`glyphs` の送り幅を広げる)
+///
+/// 1文字ずつ読みを付けたセグメントが続くときは文字ごとのモノルビになり、
+/// 読みの長い文字は隣の文字の読みの空いている所へはみ出す (熟語ルビ)
+fn layout_ruby(ui: &egui::Ui, segments: &[&Segment], glyphs: &mut [Glyph], owners: &[usize], font_ruby: &egui::FontId) -> Vec<Option<RubyLayout>> {
+    let ranges = (0..segments.len())
+        .map(|index| {
+            let start = owners.iter().position(|owner| *owner == index).unwrap_or(0);
+            (start, owners.iter().filter(|owner| **owner == index).count())
+        })
+        .collect::<Vec<_>>();
+    // 読みの文字とその送り幅
+    let readings = segments.iter().zip(ranges.iter())
+        .map(|(segment, (_, count))| match segment {
+            Segment::Annotated { base: _, reading } if *count > 0 && !reading.is_empty() => Some(reading.chars()
+                .map(|ch| (ch, char_galley(ui, ch, font_ruby).size().x))
+                .collect::<Vec<_>>()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let natural = |index: usize| readings[index].as_ref().map_or(0.0, |ruby| ruby.iter().map(|(_, width)| width).sum::<f32>());
+    // 親文字の長さと、1:2:1 で並べたときの読みの両端の空き
+    let lengths = ranges.iter().map(|(start, count)| advance_of(&glyphs[*start..start+count])).collect::<Vec<_>>();
+    let free = readings.iter().enumerate()
+        .map(|(index, reading)| reading.as_ref().map_or(0.0, |ruby| ((lengths[index]-natural(index))/(2*ruby.len()) as f32).max(0.0)))
+        .collect::<Vec<_>>();
+
+    let mut layouts = Vec::new();
+    for (index, reading) in readings.iter().enumerate() {
+        let Some(ruby) = reading else {
+            layouts.push(None);
+            continue;
+        };
+        let (start, count) = ranges[index];
+        let (length, width) = (lengths[index], natural(index));
+        if width <= length {
+            let slot = length/ruby.len() as f32;
+            layouts.push(Some(ruby.iter().enumerate().map(|(i, (ch, _))| (*ch, slot*(i as f32+0.5))).collect()));
+            continue;
+        }
+        let before = match index.checked_sub(1).filter(|prev| ranges[*prev].1 > 0) {
+            Some(prev) => ruby_overhang(segments[prev], &glyphs[start-1], free[prev], font_ruby),
+            None => 0.0,
+        };
+        let after = match Some(index+1).filter(|next| *next < segments.len() && ranges[*next].1 > 0) {
+            Some(next) => ruby_overhang(segments[next], &glyphs[start+count], free[next], font_ruby),
+            None => 0.0,
+        };
+        // はみ出せない分は親文字の字間をあける
+        let needed = width-before-after;
+        let length = if needed > length {
+            let extra = (needed-length)/count as f32;
+            for glyph in glyphs[start..start+count].iter_mut() {
+                glyph.advance += extra;
+            }
+            needed
+        } else {
+            length
+        };
+        // はみ出しは前後に均等に振り分け、片側で収まらない分は反対側へ回す
+        let overhang = width-length;
+        let mut head = (overhang/2.0).min(before);
+        if overhang-head > after {
+            head = overhang-after;
+        }
+        let mut along = -head;
+        layouts.push(Some(ruby.iter().map(|(ch, advance)| {
+            let center = along+advance/2.0;
+            along += advance;
+            (*ch, center)
+        }).collect()));
+    }
+    layouts
+}
+
+/// 読みを描く位置
+struct RubyPlacement {
+    /// 親文字の始まり (行の進む方向)
+    start: f32,
+    /// 横書きでは読みの中心の高さ、縦書きでは読みの左端
+    cross: f32,
+    /// 行の進む方向のずらし量
+    offset: f32,
+    /// 表示する範囲の長さ
+    max: f32,
+}
+
+/// 配置を決めた読みを描く
+fn paint_ruby(
+    ui: &mut egui::Ui,
+    ruby: &RubyLayout,
+    colors: &[egui::Color32],
+    placement: RubyPlacement,
+    orientation: &CharOrientation,
+    font_ruby: &egui::FontId,
+) {
+    let RubyPlacement { start, cross, offset, max } = placement;
+    for ((ch, center), color) in ruby.iter().zip(colors.iter()) {
+        let size = char_galley(ui, *ch, font_ruby).size();
+        let along = start+center;
+        if is_visible(along-offset, size.x, max) {
+            let pos = match orientation {
+                CharOrientation::Horizontal => egui::pos2(along-offset, cross),
+                CharOrientation::Vertical => egui::pos2(cross+size.x*0.5, along+size.x*0.25-offset),
+            };
+            paint_ruby_char(ui, *ch, pos, orientation, font_ruby, *color);
+        }
+    }
+}
+
+fn resolve_font(font_id: Option<egui::FontId>, ui: &egui::Ui, family: &str) -> egui::FontId {
+    let mut font = font_id.unwrap_or_else(|| egui::FontSelection::Default.resolve(ui.style()));
+    font.family = egui::FontFamily::Name(family.into());
+    font
+}
+
+/// 読みの大きさの既定値 (親文字に対する倍率)
+pub const RUBY_SCALE: f32 = 0.3;
+
+/// ルビのフォント (親文字の `scale` 倍の大きさ)
+fn resolve_ruby_font(font_id: Option<egui::FontId>, ui: &egui::Ui, font_main: &egui::FontId, scale: f32) -> egui::FontId {
+    let mut font_ruby = resolve_font(font_id, ui, "ruby");
+    font_ruby.size = font_main.size*scale;
+    font_ruby
+}
+
+/// セグメントの親文字 (読みの無いセグメントはその文字列)
+fn segment_base(segment: &Segment) -> &str {
+    match segment {
+        Segment::Plain { text } => text,
+        Segment::Annotated { base, reading: _ } => base,
+    }
+}
+
+/// 文字列の送り幅の合計
+fn advance_of(glyphs: &[Glyph]) -> f32 {
+    glyphs.iter().map(|glyph| glyph.advance).sum()
+}
+
+/// 文字列の行と直交する方向の大きさ
+fn extent_of(glyphs: &[Glyph]) -> f32 {
+    glyphs.iter().map(|glyph| glyph.extent).fold(0.0, f32::max)
+}
+
+/// 行頭禁則の文字 (行の始めに置かない)
+fn is_no_line_start(c: char) -> bool {
+    matches!(c,
+        '、' | '。' | '，' | '．' | ',' | '.' | '・' | '：' | '；' | '！' | '？' | '!' | '?' | ')' | ']' | '}' |
+        '）' | '」' | '』' | '】' | '〕' | '〉' | '》' | '〗' | '〙' | '］' | '｝' | '＞' |
+        'ー' | '々' | 'ゝ' | 'ゞ' | 'ヽ' | 'ヾ' | '…' | '‥' | '〜' | '～'
+    ) || is_small_kana(c)
+}
+
+/// 行末禁則の文字 (行の終わりに置かない)
+fn is_no_line_end(c: char) -> bool {
+    matches!(c, '(' | '[' | '{' | '（' | '「' | '『' | '【' | '〔' | '〈' | '《' | '〖' | '〘' | '［' | '｛' | '＜')
+}
+
+/// 折り返しの設定
+#[derive(Clone, Copy)]
+pub struct Wrap {
+    /// 1行の長さ (行の進む方向)
+    pub length: f32,
+    /// 表示する行数
+    pub rows: usize,
+}
+
+/// カーソルの形
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CaretStyle {
+    Bar,
+    Block,
+    Underline,
+}
+
+impl CaretStyle {
+    pub const ALL: [CaretStyle; 3] = [CaretStyle::Bar, CaretStyle::Block, CaretStyle::Underline];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            CaretStyle::Bar => "Bar",
+            CaretStyle::Block => "Block",
+            CaretStyle::Underline => "Underline",
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            CaretStyle::Bar => "A thin line after the romaji being typed",
+            CaretStyle::Block => "A box over the next character and the romaji being typed",
+            CaretStyle::Underline => "A line under the next character and the romaji being typed",
+        }
+    }
+}
+
+/// カーソルが動くのにかける時間 (秒)
+pub const CARET_ANIMATION_TIME: f32 = 0.08;
+
+/// カーソルを描く
+/// `start` は入力位置 (横書きでは次の文字の左端と本文の上端、縦書きでは本文の中心と次の文字の上端)
+/// `length` は未確定のローマ字の長さ (ブロックと下線はその分と次の文字を覆う)
+fn paint_caret(ui: &mut egui::Ui, style: CaretStyle, start: egui::Pos2, length: f32, orientation: &CharOrientation, font_size: f32, color: egui::Color32) {
+    let thickness = 2.0;
+    let cover = length.max(font_size);
+    let rect = match (style, orientation) {
+        (CaretStyle::Bar, CharOrientation::Horizontal) => egui::Rect::from_min_size(egui::pos2(start.x+length, start.y), egui::vec2(thickness, font_size)),
+        (CaretStyle::Bar, CharOrientation::Vertical) => egui::Rect::from_min_size(egui::pos2(start.x-font_size*0.5, start.y+length), egui::vec2(font_size, thickness)),
+        (CaretStyle::Block, CharOrientation::Horizontal) => egui::Rect::from_min_size(start, egui::vec2(cover, font_size)),
+        (CaretStyle::Block, CharOrientation::Vertical) => egui::Rect::from_min_size(egui::pos2(start.x-font_size*0.5, start.y), egui::vec2(font_size, cover)),
+        (CaretStyle::Underline, CharOrientation::Horizontal) => egui::Rect::from_min_size(egui::pos2(start.x, start.y+font_size), egui::vec2(cover, thickness)),
+        // 縦書きでは右側に読みを置くので、線は左側に引く
+        (CaretStyle::Underline, CharOrientation::Vertical) => egui::Rect::from_min_size(egui::pos2(start.x-font_size*0.5-thickness, start.y), egui::vec2(thickness, cover)),
+    };
+    // ブロックは文字の後ろに描くので、文字が読めるよう薄くする
+    let color = if style == CaretStyle::Block { color.gamma_multiply(0.6) } else { color };
+    ui.painter().rect_filled(rect, 0.0, color);
+}
+
+/// `index` 番目の文字の前で改行できるか
+/// 禁則文字の前後・ルビの親文字の途中・半角英数の単語の途中では改行しない
+fn can_break_before(glyphs: &[Glyph], unbreakable: &[bool], index: usize) -> bool {
+    let (prev, next) = (glyphs[index-1].ch, glyphs[index].ch);
+    let in_word = prev.is_ascii_alphanumeric() && next.is_ascii_alphanumeric();
+    !(unbreakable[index] || is_no_line_start(next) || is_no_line_end(prev) || in_word)
+}
+
+/// 文字を `length` ごとに折り返し、文字ごとの (行番号, 行の中での位置) を返す
+/// 禁則で改行できないときは改行位置を前に追い出し、それでも無理なら長さを超えたところで改行する
+fn flow(glyphs: &[Glyph], unbreakable: &[bool], length: Option<f32>) -> Vec<(usize, f32)> {
+    let mut positions: Vec<(usize, f32)> = Vec::with_capacity(glyphs.len());
+    let (mut row, mut along, mut row_start) = (0, 0.0, 0);
+    let mut index = 0;
+    while index < glyphs.len() {
+        if let Some(length) = length {
+            if index > row_start && along+glyphs[index].advance > length {
+                let at = (row_start+1..=index).rev()
+                    .find(|&at| can_break_before(glyphs, unbreakable, at))
+                    .unwrap_or(index);
+                positions.truncate(at);
+                row += 1;
+                along = 0.0;
+                row_start = at;
+                index = at;
+            }
+        }
+        positions.push((row, along));
+        along += glyphs[index].advance;
+        index += 1;
+    }
+    positions
+}
+
+/// 折り返した行の画面上の位置
+/// 横書きは上から下へ、縦書きは右から左へ行を並べる
+struct Rows {
+    rect: egui::Rect,
+    orientation: CharOrientation,
+    /// 表示する最初の行
+    first: usize,
+    /// 表示する行数
+    count: usize,
+    /// 1行の太さ (ルビを含む)
+    height: f32,
+    font_size: f32,
+}
+
+impl Rows {
+    fn is_shown(&self, row: usize) -> bool {
+        row >= self.first && row < self.first+self.count
+    }
+    /// 行の始まりの位置 (横書きでは行の左端と本文の中心の高さ、縦書きでは本文の中心と行の上端)
+    fn origin(&self, row: usize) -> (f32, f32) {
+        let index = row.saturating_sub(self.first) as f32;
+        match self.orientation {
+            CharOrientation::Horizontal => (self.rect.left(), self.rect.top()+index*self.height+self.font_size/2.0),
+            CharOrientation::Vertical => (self.rect.left()+(self.count as f32-1.0-index)*self.height+self.font_size/2.0, self.rect.top()),
+        }
+    }
+    /// 行の進む方向の画面上の位置
+    fn along(&self, along: f32) -> f32 {
+        match self.orientation {
+            CharOrientation::Horizontal => self.rect.left()+along,
+            CharOrientation::Vertical => self.rect.top()+along,
+        }
+    }
+    /// ルビを置く位置 (横書きでは読みの中心の高さ、縦書きでは読みの左端)
+    fn ruby_cross(&self, row: usize, ruby_space: f32, base_width: f32) -> f32 {
+        let (x, y) = self.origin(row);
+        match self.orientation {
+            CharOrientation::Horizontal => y-self.font_size/2.0+ruby_space*0.5,
+            CharOrientation::Vertical => x-self.font_size/2.0+base_width,
+        }
+    }
+}
+
 /// RenderText widget: renders a string by calling RenderChar for each character.
 pub struct RenderText {
     text: String,
@@ -95,89 +670,11 @@ impl egui::Widget for RenderText {
     fn ui(self, ui: &mut egui::Ui) -> egui::Response {
         // Retrieve text color from UI style.
         let color = ui.style().visuals.strong_text_color();
-        let mut font_main = self
-            .font_id.clone()
-            .unwrap_or_else(|| egui::FontSelection::Default.resolve(ui.style()));
-        let mut font_kana = self
-            .font_id
-            .unwrap_or_else(|| egui::FontSelection::Default.resolve(ui.style()));
-        font_main.family = egui::FontFamily::Name("main".into());
-        font_kana.family = egui::FontFamily::Name("kana".into());
+        let font_main = resolve_font(self.font_id, ui, "main");
         // Calculate the total width and maximum height for the entire text.
-        let mut total_size = 0.0;
-        let mut max_size: f32 = 0.0;
-        let mut char_sizes = Vec::new();
-        let mut s = self.text;
-        if self.orientation == CharOrientation::Vertical {
-            s = s
-                // // https://ja.wikipedia.org/wiki/CJK%E4%BA%92%E6%8F%9B%E5%BD%A2
-                .replace('\u{2025}', "\u{fe30}")
-                .replace('\u{2014}', "\u{fe31}")
-                .replace('\u{2013}', "\u{fe32}")
-                .replace('\u{005f}', "\u{fe33}")
-                .replace('\u{0028}', "\u{fe35}")
-                .replace('\u{0029}', "\u{fe36}")
-                .replace('\u{007b}', "\u{fe37}")
-                .replace('\u{007d}', "\u{fe38}")
-                .replace('\u{3014}', "\u{fe39}")
-                .replace('\u{3015}', "\u{fe3a}")
-                .replace('\u{3010}', "\u{fe3b}")
-                .replace('\u{3011}', "\u{fe3c}")
-                .replace('\u{300a}', "\u{fe3d}")
-                .replace('\u{300b}', "\u{fe3e}")
-                .replace('\u{3008}', "\u{fe3f}")
-                .replace('\u{3009}', "\u{fe40}")
-                .replace('\u{300c}', "\u{fe41}")
-                .replace('\u{300d}', "\u{fe42}")
-                .replace('\u{300e}', "\u{fe43}")
-                .replace('\u{300f}', "\u{fe44}")
-                .replace('\u{005B}', "\u{fe47}")
-                .replace('\u{005D}', "\u{fe48}")
-                // https://ja.wikipedia.org/wiki/%E7%B8%A6%E6%9B%B8%E3%81%8D%E5%BD%A2
-                .replace('\u{ff0c}', "\u{fe10}")
-                .replace('\u{3001}', "\u{fe11}")
-                .replace('\u{3002}', "\u{fe12}")
-                .replace('\u{ff1a}', "\u{fe13}")
-                .replace('\u{ff1b}', "\u{fe14}")
-                .replace('\u{ff01}', "\u{fe15}")
-                .replace('\u{ff1f}', "\u{fe16}")
-                .replace('\u{3016}', "\u{fe17}")
-                .replace('\u{3017}', "\u{fe18}")
-                .replace('\u{2026}', "\u{fe19}")
-                //
-                .replace('\u{30fc}', "\u{4e28}");
-        }
-        for ch in s.chars() {
-            let s = ch.to_string();
-            let galley = ui.painter().layout_no_wrap(s, font_main.clone(), color);
-            let size = galley.size();
-            match (&self.orientation,is_japanese(ch)) {
-                (CharOrientation::Horizontal,true) => {
-                    char_sizes.push((ch, size));
-                    let dx = if is_japanese_kana(ch) { size.x*0.8 } else { size.x };
-                    total_size += dx;
-                    max_size = max_size.max(size.y);
-                },
-                (CharOrientation::Horizontal,false) => {
-                    char_sizes.push((ch, size));
-                    let dx = size.x*0.8;
-                    total_size += dx;
-                    max_size = max_size.max(size.y);
-                },
-                (CharOrientation::Vertical,true) => {
-                    char_sizes.push((ch, size));
-                    let dy = if is_japanese_kana(ch) { size.x*0.85 } else { size.x };
-                    total_size += dy;
-                    max_size = max_size.max(size.x);
-                },
-                (CharOrientation::Vertical,false) => {
-                    char_sizes.push((ch, size));
-                    let dy = size.x*0.75;
-                    total_size += dy;
-                    max_size = max_size.max(size.y);
-                },
-            };
-        }
+        let glyphs = layout_glyphs(ui, &self.text, &self.orientation, &font_main);
+        let total_size = advance_of(&glyphs);
+        let max_size = extent_of(&glyphs);
         // Allocate the required space.
         let (rect, response) = ui.allocate_exact_size(if self.orientation==CharOrientation::Horizontal { egui::vec2(total_size, max_size) } else { egui::vec2(max_size, total_size ) }, egui::Sense::hover());
         // Render each character in sequence.
@@ -185,78 +682,12 @@ impl egui::Widget for RenderText {
             CharOrientation::Horizontal => (rect.left(), rect.top()+font_main.size / 2.0),
             CharOrientation::Vertical => (rect.left()+font_main.size / 2.0, rect.top()),
         };
-        for (ch, size) in char_sizes {
-            match (&self.orientation,is_japanese(ch)) {
-                (CharOrientation::Horizontal,true) => {
-                    let dx = if is_japanese_kana(ch) { size.x*0.8 } else { size.x };
-                    let oy = if is_japanese_kana(ch) { if is_japanese_hiragana(ch) { size.y*0.03 } else { size.y*0.01 } } else { 0.0 };
-                    if is_japanese_kana(ch) && ch != '\u{30fc}' {
-                        let mut pos = egui::pos2(x_offset+dx/2.0, y_offset+oy);
-                        if is_japanese_hiragana(ch) { font_kana.size = font_main.size*0.85; } else { font_kana.size = font_main.size*0.95; }
-                        if [
-                            '\u{3041}','\u{3043}','\u{3045}','\u{3047}','\u{3049}','\u{3063}','\u{3041}','\u{3083}','\u{3085}','\u{3087}','\u{308e}','\u{3095}','\u{3096}','\u{3041}',
-                            '\u{30a1}','\u{30a3}','\u{30a5}','\u{30a7}','\u{30a9}','\u{30c3}','\u{30e3}','\u{30e5}','\u{30e7}','\u{30ee}','\u{30f5}','\u{30f6}'
-                            ].contains(&ch) {
-                            if is_japanese_hiragana(ch) { font_kana.size = font_main.size*0.8; } else { font_kana.size = font_main.size*0.9; }
-                            pos = egui::pos2(x_offset+dx/2.0-size.x/100.0, y_offset+oy+size.y/80.0);
-                        }
-                        render_char_at(ui, ch, pos, CharOrientation::Horizontal, &font_kana, color);
-                    }
-                    else if ch == '\u{30fc}'
-                    {
-                        let pos = egui::pos2(x_offset+dx/2.0, y_offset+oy);
-                        let mut font = font_main.clone();
-                        font.size = font_main.size*0.8;
-                        render_char_at(ui, ch, pos, CharOrientation::Horizontal, &font, color);
-                    }
-                    else {
-                        let pos = egui::pos2(x_offset+dx/2.0, y_offset+oy);
-                        render_char_at(ui, ch, pos, CharOrientation::Horizontal, &font_main, color);
-                    }
-                    x_offset += dx;
-                },
-                (CharOrientation::Horizontal,false) => {
-                    let dx = size.x*0.8;
-                    let pos = egui::pos2(x_offset+dx/2.0, y_offset+size.y/20.0);
-                    let mut font = font_main.clone();
-                    font.size = font_main.size*0.85;
-                    render_char_at(ui, ch, pos, CharOrientation::Horizontal, &font, color);
-                    x_offset += dx;
-                },
-                (CharOrientation::Vertical,true) => {
-                    let dy = if is_japanese_kana(ch) { size.x*0.85 } else { size.x };
-                    let mut pos = egui::pos2(x_offset, y_offset+dy/2.0);
-                    if is_japanese_kana(ch) {
-                        if is_japanese_hiragana(ch) { font_kana.size = font_main.size*0.85; } else { font_kana.size = font_main.size*0.95; }
-                        if [
-                            '\u{3041}','\u{3043}','\u{3045}','\u{3047}','\u{3049}','\u{3063}','\u{3041}','\u{3083}','\u{3085}','\u{3087}','\u{308e}','\u{3095}','\u{3096}','\u{3041}',
-                            '\u{30a1}','\u{30a3}','\u{30a5}','\u{30a7}','\u{30a9}','\u{30c3}','\u{30e3}','\u{30e5}','\u{30e7}','\u{30ee}','\u{30f5}','\u{30f6}'
-                            ].contains(&ch) {
-                            if is_japanese_hiragana(ch) { font_kana.size = font_main.size*0.8; } else { font_kana.size = font_main.size*0.9; }
-                            pos = egui::pos2(x_offset+size.x/10.0, y_offset+dy/2.0-size.y/10.0);
-                        }
-                        render_char_at(ui, ch, pos, CharOrientation::Horizontal, &font_kana, color);
-                    }
-                    else if ch == '\u{4e28}'
-                    {
-                        let mut font = font_main.clone();
-                        font.size = font_main.size*0.93;
-                        render_char_at(ui, ch, pos, CharOrientation::Horizontal, &font, color);
-                    }
-                    else {
-                        render_char_at(ui, ch, pos, CharOrientation::Horizontal, &font_main, color);
-                    }
-                    y_offset += dy;
-                },
-                (CharOrientation::Vertical,false) => {
-                    let dy = size.x*0.75;
-                    let pos = egui::pos2(x_offset+font_main.size/100.0, y_offset+dy/2.0);
-                    let mut font = font_main.clone();
-                    font.size = font_main.size*0.9;
-                    render_char_at(ui, ch, pos, CharOrientation::Vertical, &font, color);
-                    y_offset += dy;
-                },
-            };
+        for glyph in glyphs.iter() {
+            paint_glyph(ui, glyph, egui::pos2(x_offset, y_offset), &self.orientation, color);
+            match self.orientation {
+                CharOrientation::Horizontal => x_offset += glyph.advance,
+                CharOrientation::Vertical => y_offset += glyph.advance,
+            }
         }
         response
     }
@@ -270,6 +701,12 @@ pub struct RenderLineWithRuby {
     font_id: Option<egui::FontId>,
     offset: f32,
     max: f32,
+    display: Vec<SegmentDisplay>,
+    wrap: Option<Wrap>,
+    focus: usize,
+    theme: Option<Theme>,
+    ruby_scale: f32,
+    opacity: f32,
 }
 
 impl RenderLineWithRuby {
@@ -281,6 +718,12 @@ impl RenderLineWithRuby {
             font_id: None,
             offset: 0.0,
             max: 1000.0,
+            display: Vec::new(),
+            wrap: None,
+            focus: 0,
+            theme: None,
+            ruby_scale: RUBY_SCALE,
+            opacity: 1.0,
         }
     }
 
@@ -296,355 +739,153 @@ impl RenderLineWithRuby {
         self.max = max;
         self
     }
+    /// セグメントごとの表示方法 (指定の無いセグメントはそのまま表示)
+    pub fn with_display(mut self, display: Vec<SegmentDisplay>) -> Self {
+        self.display = display;
+        self
+    }
+    /// 禁則処理をして折り返す
+    pub fn with_wrap(mut self, wrap: Wrap) -> Self {
+        self.wrap = Some(wrap);
+        self
+    }
+    /// 折り返したとき、このセグメントを含む行から表示する
+    pub fn with_focus(mut self, segment: usize) -> Self {
+        self.focus = segment;
+        self
+    }
+    /// 文字と読みの色 (指定しなければ UI の文字色)
+    pub fn with_theme(mut self, theme: &Theme) -> Self {
+        self.theme = Some(theme.clone());
+        self
+    }
+    /// 親文字に対する読みの大きさ
+    pub fn with_ruby_scale(mut self, scale: f32) -> Self {
+        self.ruby_scale = scale;
+        self
+    }
+    /// 不透明度 (入力中でない行を薄く表示する)
+    pub fn with_opacity(mut self, opacity: f32) -> Self {
+        self.opacity = opacity;
+        self
+    }
+    /// 表示するセグメントと元の番号 (隠すセグメントを除き、読みを表示しないセグメントは読み無しにする)
+    fn displayed_segments(&self) -> Vec<(usize, Segment)> {
+        self.line.segments.iter().enumerate()
+            .filter_map(|(index, segment)| match (self.display.get(index), segment) {
+                (Some(SegmentDisplay::Hidden), _) => None,
+                (Some(SegmentDisplay::BaseOnly), Segment::Annotated { base, reading: _ }) => Some((index, Segment::Plain { text: base.clone() })),
+                _ => Some((index, segment.clone())),
+            })
+            .collect()
+    }
 
     /// Calculate the size of the rendered text.
     pub fn calc_size(&self, ui: &egui::Ui) -> (f32, f32) {
-        let mut font_main = self
-            .font_id.clone()
-            .unwrap_or_else(|| egui::FontSelection::Default.resolve(ui.style()));
-        let ruby_space = font_main.size * 0.3;
-        font_main.family = egui::FontFamily::Name("main".into());
-
-        let mut total_size = 0.0;
-        let mut max_size: f32 = 0.0;
-
-        // Calculate size for typed segments
-        for (index, segment) in self.line.segments.iter().enumerate() {
-
-            let mut s = match segment {
-                Segment::Plain { text } => text.clone(),
-                Segment::Annotated { base, reading: _ } => base.clone(),
-            };
-
-            if self.orientation == CharOrientation::Vertical {
-                s = s.replace('\u{30fc}', "\u{4e28}");
-            }
+        let font_main = resolve_font(self.font_id.clone(), ui, "main");
+        let font_ruby = resolve_ruby_font(self.font_id.clone(), ui, &font_main, self.ruby_scale);
+        let ruby_space = font_ruby.size;
+        let segments = self.displayed_segments();
+        let segments = segments.iter().map(|(_, segment)| segment).collect::<Vec<_>>();
+        let (mut glyphs, _, owners) = layout_segments(ui, segments.iter().copied(), &self.orientation, &font_main);
+        layout_ruby(ui, &segments, &mut glyphs, &owners, &font_ruby);
+        (advance_of(&glyphs), if glyphs.is_empty() { 0.0 } else { extent_of(&glyphs)+ruby_space })
+    }
+}
 
-            for ch in s.chars() {
-                let galley = ui.painter().layout_no_wrap(ch.to_string(), font_main.clone(), egui::Color32::WHITE);
-                let size = galley.size();
-
-                match (&self.orientation, is_japanese(ch)) {
-                    (CharOrientation::Horizontal, true) => {
-                        let dx = if is_japanese_kana(ch) { size.x * 0.8 } else { size.x };
-                        total_size += dx;
-                        max_size = max_size.max(size.y + ruby_space);
-                    },
-                    (CharOrientation::Horizontal, false) => {
-                        total_size += size.x * 0.8;
-                        max_size = max_size.max(size.y + ruby_space);
-                    },
-                    (CharOrientation::Vertical, true) => {
-                        let dy = if is_japanese_kana(ch) { size.x * 0.85 } else { size.x };
-                        total_size += dy;
-                        max_size = max_size.max(size.x + ruby_space);
-                    },
-                    (CharOrientation::Vertical, false) => {
-                        total_size += size.x * 0.75;
-                        max_size = max_size.max(size.y + ruby_space);
-                    },
-                }
-            }
+/// セグメントの親文字を並べ、文字ごとに前で改行できないか(ルビの親文字の途中か)と、何番目のセグメントの文字かを返す
+fn layout_segments<'a>(ui: &egui::Ui, segments: impl Iterator<Item = &'a Segment>, orientation: &CharOrientation, font_main: &egui::FontId) -> (Vec<Glyph>, Vec<bool>, Vec<usize>) {
+    let mut glyphs = Vec::new();
+    let mut unbreakable = Vec::new();
+    let mut owners = Vec::new();
+    for (index, segment) in segments.enumerate() {
+        let base = layout_glyphs(ui, segment_base(segment), orientation, font_main);
+        for position in 0..base.len() {
+            unbreakable.push(position > 0 && matches!(segment, Segment::Annotated { .. }));
+            owners.push(index);
         }
-
-        (total_size, max_size)
+        glyphs.extend(base);
     }
+    (glyphs, unbreakable, owners)
+}
+
+/// 折り返した各行の長さのうち最大のもの
+fn flow_length(glyphs: &[Glyph], positions: &[(usize, f32)]) -> f32 {
+    glyphs.iter().zip(positions.iter()).map(|(glyph, (_, along))| along+glyph.advance).fold(0.0, f32::max)
 }
 
 impl egui::Widget for RenderLineWithRuby {
     fn ui(self, ui: &mut egui::Ui) -> egui::Response {
+        let opacity = ui.opacity();
+        ui.multiply_opacity(self.opacity);
         // Retrieve text color from UI style.
-        let color = ui.style().visuals.strong_text_color();
-        let mut font_main = self
-            .font_id.clone()
-            .unwrap_or_else(|| egui::FontSelection::Default.resolve(ui.style()));
-        let mut font_kana = self
-            .font_id.clone()
-            .unwrap_or_else(|| egui::FontSelection::Default.resolve(ui.style()));
-        let mut font_ruby = self
-            .font_id
-            .unwrap_or_else(|| egui::FontSelection::Default.resolve(ui.style()));
-        font_main.family = egui::FontFamily::Name("main".into());
-        font_kana.family = egui::FontFamily::Name("kana".into());
-        font_ruby.family = egui::FontFamily::Name("ruby".into());
-        font_ruby.size = font_main.size*0.3;
-        // Calculate the total width and maximum height for the entire text.
-        // Allocate the required space.
-        let mut rectinfo = Vec::new();
+        let (color, ruby_color) = match &self.theme {
+            Some(theme) => (theme.text, theme.ruby),
+            None => (ui.style().visuals.strong_text_color(), ui.style().visuals.strong_text_color()),
+        };
+        let segments = self.displayed_segments();
+        let font_main = resolve_font(self.font_id.clone(), ui, "main");
+        let font_ruby = resolve_ruby_font(self.font_id, ui, &font_main, self.ruby_scale);
         let ruby_space = font_ruby.size;
-        for segment in self.line.segments.iter() {
-            let mut total_size = 0.0;
-            let mut max_size: f32 = 0.0;
-            let mut char_sizes = Vec::new();
-            let mut s = match segment {
-                Segment::Plain { text } => text.clone(),
-                Segment::Annotated { base, reading } => base.clone(),
-            };
-            if self.orientation == CharOrientation::Vertical {
-                s = s
-                    // // https://ja.wikipedia.org/wiki/CJK%E4%BA%92%E6%8F%9B%E5%BD%A2
-                    .replace('\u{2025}', "\u{fe30}")
-                    .replace('\u{2014}', "\u{fe31}")
-                    .replace('\u{2013}', "\u{fe32}")
-                    .replace('\u{005f}', "\u{fe33}")
-                    .replace('\u{0028}', "\u{fe35}")
-                    .replace('\u{0029}', "\u{fe36}")
-                    .replace('\u{007b}', "\u{fe37}")
-                    .replace('\u{007d}', "\u{fe38}")
-                    .replace('\u{3014}', "\u{fe39}")
-                    .replace('\u{3015}', "\u{fe3a}")
-                    .replace('\u{3010}', "\u{fe3b}")
-                    .replace('\u{3011}', "\u{fe3c}")
-                    .replace('\u{300a}', "\u{fe3d}")
-                    .replace('\u{300b}', "\u{fe3e}")
-                    .replace('\u{3008}', "\u{fe3f}")
-                    .replace('\u{3009}', "\u{fe40}")
-                    .replace('\u{300c}', "\u{fe41}")
-                    .replace('\u{300d}', "\u{fe42}")
-                    .replace('\u{300e}', "\u{fe43}")
-                    .replace('\u{300f}', "\u{fe44}")
-                    .replace('\u{005B}', "\u{fe47}")
-                    .replace('\u{005D}', "\u{fe48}")
-                    // https://ja.wikipedia.org/wiki/%E7%B8%A6%E6%9B%B8%E3%81%8D%E5%BD%A2
-                    .replace('\u{ff0c}', "\u{fe10}")
-                    .replace('\u{3001}', "\u{fe11}")
-                    .replace('\u{3002}', "\u{fe12}")
-                    .replace('\u{ff1a}', "\u{fe13}")
-                    .replace('\u{ff1b}', "\u{fe14}")
-                    .replace('\u{ff01}', "\u{fe15}")
-                    .replace('\u{ff1f}', "\u{fe16}")
-                    .replace('\u{3016}', "\u{fe17}")
-                    .replace('\u{3017}', "\u{fe18}")
-                    .replace('\u{2026}', "\u{fe19}")
-                    //
-                    .replace('\u{30fc}', "\u{4e28}");
-            }
-            for ch in s.chars() {
-                let s = ch.to_string();
-                let galley = ui.painter().layout_no_wrap(s, font_main.clone(), color);
-                let size = galley.size();
-                match (&self.orientation,is_japanese(ch)) {
-                    (CharOrientation::Horizontal,true) => {
-                        char_sizes.push((ch, size));
-                        let dx = if is_japanese_kana(ch) { size.x*0.8 } else { size.x };
-                        total_size += dx;
-                        max_size = max_size.max(size.y+ruby_space);
-                    },
-                    (CharOrientation::Horizontal,false) => {
-                        char_sizes.push((ch, size));
-                        let dx = size.x*0.8;
-                        total_size += dx;
-                        max_size = max_size.max(size.y+ruby_space);
-                    },
-                    (CharOrientation::Vertical,true) => {
-                        char_sizes.push((ch, size));
-                        let dy = if is_japanese_kana(ch) { size.x*0.85 } else { size.x };
-                        total_size += dy;
-                        max_size = max_size.max(size.x+ruby_space);
-                    },
-                    (CharOrientation::Vertical,false) => {
-                        char_sizes.push((ch, size));
-                        let dy = size.x*0.75;
-                        total_size += dy;
-                        max_size = max_size.max(size.y+ruby_space);
-                    },
-                };
+        // 文字と読みを並べて折り返す
+        let (mut glyphs, unbreakable, owners) = layout_segments(ui, segments.iter().map(|(_, segment)| segment), &self.orientation, &font_main);
+        let rubies = layout_ruby(ui, &segments.iter().map(|(_, segment)| segment).collect::<Vec<_>>(), &mut glyphs, &owners, &font_ruby);
+        let positions = flow(&glyphs, &unbreakable, self.wrap.map(|wrap| wrap.length));
+        let height = if glyphs.is_empty() { 0.0 } else { extent_of(&glyphs)+ruby_space };
+        let total_rows = positions.last().map_or(1, |(row, _)| row+1);
+        let first = segments.iter().position(|(index, _)| *index >= self.focus)
+            .and_then(|segment| owners.iter().position(|owner| *owner == segment))
+            .map_or(0, |index| positions[index].0);
+        let count = self.wrap.map_or(total_rows, |wrap| wrap.rows.max(1)).min(total_rows-first);
+        let length = flow_length(&glyphs, &positions);
+        // Allocate the required space.
+        let (rect, response) = ui.allocate_exact_size(if self.orientation==CharOrientation::Horizontal { egui::vec2(length, height*count as f32) } else { egui::vec2(height*count as f32, length) }, egui::Sense::hover());
+        let rows = Rows { rect, orientation: self.orientation.clone(), first, count, height, font_size: font_main.size };
+        // baseの描画
+        for (glyph, (row, along)) in glyphs.iter().zip(positions.iter()) {
+            if !rows.is_shown(*row) {
+                continue;
             }
-            rectinfo.push((total_size, max_size, char_sizes,segment));
-        }
-        // rectinfoの中身を総和
-        let total_size = rectinfo.iter().map(|(total_size, _, _,_)| *total_size).sum::<f32>();
-        let max_size = rectinfo.iter().map(|(_, max_size, _,_)| *max_size).max_by(|a, b| a.partial_cmp(b).unwrap()).unwrap_or(0.0);
-        let (rect, response) = ui.allocate_exact_size(if self.orientation==CharOrientation::Horizontal { egui::vec2(total_size, max_size) } else { egui::vec2(max_size, total_size ) }, egui::Sense::hover());
-        // Render each character in sequence.
-        let (mut x_offset,mut y_offset) = match self.orientation {
-            CharOrientation::Horizontal => (rect.left(), rect.top()+font_main.size / 2.0),
-            CharOrientation::Vertical => (rect.left()+font_main.size / 2.0, rect.top()),
-        };
-        for (total_size, max_size, char_sizes,segment) in rectinfo {
-            let mut x_offset_ruby = x_offset;
-            let mut y_offset_ruby = y_offset;
-            let mut vert_x_offset = char_sizes[0].1.x;
-            // baseの描画
-            for (ch, size) in char_sizes {
-                let mut f = true;
-                match self.orientation {
-                    CharOrientation::Horizontal if x_offset-self.offset+size.x < 0.0 || x_offset-self.offset > self.max => {
-                        f = false;
-                    }
-                    CharOrientation::Vertical if y_offset-self.offset+size.x < 0.0 || y_offset-self.offset > self.max => {
-                        f = false;
+            let (x_offset, y_offset) = rows.origin(*row);
+            match self.orientation {
+                CharOrientation::Horizontal => {
+                    if is_visible(x_offset+along-self.offset, glyph.size.x, self.max) {
+                        paint_glyph(ui, glyph, egui::pos2(x_offset+along-self.offset, y_offset+ruby_space), &self.orientation, color);
                     }
-                    _ => {}
-                }
-                match (&self.orientation,is_japanese(ch)) {
-                    (CharOrientation::Horizontal,true) => {
-                        let dx = if is_japanese_kana(ch) { size.x*0.8 } else { size.x };
-                        if f {
-                            let oy = if is_japanese_kana(ch) { if is_japanese_hiragana(ch) { size.y*0.03 } else { size.y*0.01 } } else { 0.0 };
-                            if is_japanese_kana(ch) && ch != '\u{30fc}' {
-                                let mut pos = egui::pos2(x_offset+dx/2.0-self.offset, y_offset+oy+ruby_space);
-                                if is_japanese_hiragana(ch) { font_kana.size = font_main.size*0.85; } else { font_kana.size = font_main.size*0.95; }
-                                if [
-                                    '\u{3041}','\u{3043}','\u{3045}','\u{3047}','\u{3049}','\u{3063}','\u{3041}','\u{3083}','\u{3085}','\u{3087}','\u{308e}','\u{3095}','\u{3096}','\u{3041}',
-                                    '\u{30a1}','\u{30a3}','\u{30a5}','\u{30a7}','\u{30a9}','\u{30c3}','\u{30e3}','\u{30e5}','\u{30e7}','\u{30ee}','\u{30f5}','\u{30f6}'
-                                    ].contains(&ch) {
-                                    if is_japanese_hiragana(ch) { font_kana.size = font_main.size*0.8; } else { font_kana.size = font_main.size*0.9; }
-                                    pos = egui::pos2(x_offset+dx/2.0-self.offset-size.x/100.0, y_offset+oy+size.y/80.0+ruby_space);
-                                }
-                                render_char_at(ui, ch, pos, CharOrientation::Horizontal, &font_kana, color);
-                            }
-                            else if ch == '\u{30fc}'
-                            {
-                                let pos = egui::pos2(x_offset+dx/2.0-self.offset, y_offset+oy+ruby_space);
-                                let mut font = font_main.clone();
-                                font.size = font_main.size*0.8;
-                                render_char_at(ui, ch, pos, CharOrientation::Horizontal, &font, color);
-                            }
-                            else {
-                                let pos = egui::pos2(x_offset+dx/2.0-self.offset, y_offset+oy+ruby_space);
-                                render_char_at(ui, ch, pos, CharOrientation::Horizontal, &font_main, color);
-                            }
-                        }
-                        x_offset += dx;
-                    },
-                    (CharOrientation::Horizontal,false) => {
-                        let dx = size.x*0.8;
-                        if f {
-                            let pos = egui::pos2(x_offset+dx/2.0-self.offset, y_offset+size.y/20.0+ruby_space);
-                            let mut font = font_main.clone();
-                            font.size = font_main.size*0.85;
-                            render_char_at(ui, ch, pos, CharOrientation::Horizontal, &font, color);
-                        }
-                        x_offset += dx;
-                    },
-                    (CharOrientation::Vertical,true) => {
-                        let dy = if is_japanese_kana(ch) { size.x*0.85 } else { size.x };
-                        if f {
-                            let mut pos = egui::pos2(x_offset, y_offset+dy/2.0-self.offset);
-                            if is_japanese_kana(ch) {
-                                if is_japanese_hiragana(ch) { font_kana.size = font_main.size*0.85; } else { font_kana.size = font_main.size*0.95; }
-                                if [
-                                    '\u{3041}','\u{3043}','\u{3045}','\u{3047}','\u{3049}','\u{3063}','\u{3041}','\u{3083}','\u{3085}','\u{3087}','\u{308e}','\u{3095}','\u{3096}','\u{3041}',
-                                    '\u{30a1}','\u{30a3}','\u{30a5}','\u{30a7}','\u{30a9}','\u{30c3}','\u{30e3}','\u{30e5}','\u{30e7}','\u{30ee}','\u{30f5}','\u{30f6}'
-                                    ].contains(&ch) {
-                                    if is_japanese_hiragana(ch) { font_kana.size = font_main.size*0.8; } else { font_kana.size = font_main.size*0.9; }
-                                    pos = egui::pos2(x_offset+size.x/10.0, y_offset+dy/2.0-size.y/10.0-self.offset);
-                                }
-                                render_char_at(ui, ch, pos, CharOrientation::Horizontal, &font_kana, color);
-                            }
-                            else if ch == '\u{4e28}'
-                            {
-                                let mut font = font_main.clone();
-                                font.size = font_main.size*0.93;
-                                render_char_at(ui, ch, pos, CharOrientation::Horizontal, &font, color);
-                            }
-                            else {
-                                render_char_at(ui, ch, pos, CharOrientation::Horizontal, &font_main, color);
-                            }
-                        }
-                        y_offset += dy;
-                    },
-                    (CharOrientation::Vertical,false) => {
-                        let dy = size.x*0.75;
-                        if f {
-                            let pos = egui::pos2(x_offset+font_main.size/100.0, y_offset+dy/2.0-self.offset);
-                            let mut font = font_main.clone();
-                            font.size = font_main.size*0.9;
-                            render_char_at(ui, ch, pos, CharOrientation::Vertical, &font, color);
-                        }
-                        y_offset += dy;
-                    },
-                };
-            }
-            match segment {
-                Segment::Annotated { base, reading } => {
-                    // rubyの描画
-                    let ruby: Vec<char> = reading.chars().collect();
-                    let w = total_size/(ruby.len()) as f32;
-                    x_offset_ruby += w*0.5;
-                    y_offset_ruby += w*0.5;
-                    match self.orientation {
-                        CharOrientation::Horizontal => {
-                            for ch in ruby {
-                                let s = ch.to_string();
-                                let galley = ui.painter().layout_no_wrap(s, font_ruby.clone(), color);
-                                let size = galley.size();
-                                let mut f = true;
-                                if x_offset_ruby-self.offset+size.x < 0.0 || x_offset_ruby-self.offset > self.max {
-                                    f = false;
-                                }
-                                if f {
-                                    let dx = galley.rect.width()*0.0;
-                                    if is_japanese_kana(ch) && ch != '\u{30fc}' {
-                                        let mut pos = egui::pos2(x_offset_ruby+dx-self.offset, rect.top()+ruby_space*0.5);
-                                        if [
-                                            '\u{3041}','\u{3043}','\u{3045}','\u{3047}','\u{3049}','\u{3063}','\u{3041}','\u{3083}','\u{3085}','\u{3087}','\u{308e}','\u{3095}','\u{3096}','\u{3041}',
-                                            '\u{30a1}','\u{30a3}','\u{30a5}','\u{30a7}','\u{30a9}','\u{30c3}','\u{30e3}','\u{30e5}','\u{30e7}','\u{30ee}','\u{30f5}','\u{30f6}'
-                                            ].contains(&ch) {
-                                            pos = egui::pos2(x_offset_ruby+dx-self.offset-size.x/100.0, rect.top()+ruby_space*0.5+size.y/80.0);
-                                        }
-                                        render_char_at(ui, ch, pos, CharOrientation::Horizontal, &font_ruby, color);
-                                    }
-                                    else if ch == '\u{30fc}'
-                                    {
-                                        let pos = egui::pos2(x_offset_ruby+dx-self.offset, rect.top()+ruby_space*0.5);
-                                        render_char_at(ui, ch, pos, CharOrientation::Horizontal, &font_ruby, color);
-                                    }
-                                    else {
-                                        let mut pos = egui::pos2(x_offset_ruby+dx-self.offset, rect.top()+ruby_space*0.5);
-                                        render_char_at(ui, ch, pos, CharOrientation::Horizontal, &font_ruby, color);
-                                    }
-                                }
-                                x_offset_ruby += w;
-                            }
-                        },
-                        CharOrientation::Vertical => {
-                            for ch in ruby {
-                                let s = ch.to_string();
-                                let galley = ui.painter().layout_no_wrap(s, font_ruby.clone(), color);
-                                let size = galley.size();
-                                let mut f = true;
-                                if y_offset_ruby-self.offset+size.x < 0.0 || y_offset_ruby-self.offset > self.max {
-                                    f = false;
-                                }
-                                if f {
-                                    let dx = size.x*0.5;
-                                    let dy = size.x*0.25;
-                                    let mut pos = egui::pos2(rect.left()+vert_x_offset+dx, y_offset+dy-self.offset);
-                                    if is_japanese_kana(ch) {
-                                        let mut pos = egui::pos2(rect.left()+vert_x_offset+dx, y_offset_ruby+dy-self.offset);
-                                        if [
-                                            '\u{3041}','\u{3043}','\u{3045}','\u{3047}','\u{3049}','\u{3063}','\u{3041}','\u{3083}','\u{3085}','\u{3087}','\u{308e}','\u{3095}','\u{3096}','\u{3041}',
-                                            '\u{30a1}','\u{30a3}','\u{30a5}','\u{30a7}','\u{30a9}','\u{30c3}','\u{30e3}','\u{30e5}','\u{30e7}','\u{30ee}','\u{30f5}','\u{30f6}'
-                                            ].contains(&ch) {
-                                            pos = egui::pos2(rect.left()+vert_x_offset+dx+size.x/10.0, y_offset_ruby+dy-size.y/10.0-self.offset);
-                                        }
-                                        render_char_at(ui, ch, pos, CharOrientation::Horizontal, &font_ruby, color);
-                                    }
-                                    else if ch == '\u{4e28}'
-                                    {
-                                        render_char_at(ui, ch, pos, CharOrientation::Horizontal, &font_ruby, color);
-                                    }
-                                    else {
-                                        render_char_at(ui, ch, pos, CharOrientation::Horizontal, &font_ruby, color);
-                                    }
-                                }
-                                y_offset_ruby += w;
-                            }
-                        }
+                },
+                CharOrientation::Vertical => {
+                    if is_visible(y_offset+along-self.offset, glyph.size.x, self.max) {
+                        paint_glyph(ui, glyph, egui::pos2(x_offset, y_offset+along-self.offset), &self.orientation, color);
                     }
                 },
-                _ => {}
+            }
+        }
+        // rubyの描画
+        for (index, ruby) in rubies.iter().enumerate() {
+            let Some(ruby) = ruby else {
+                continue;
+            };
+            let Some(start) = owners.iter().position(|owner| *owner == index) else {
+                continue;
             };
+            let (row, along) = positions[start];
+            if !rows.is_shown(row) {
+                continue;
+            }
+            let base = &glyphs[start..start+owners.iter().filter(|owner| **owner == index).count()];
+            let colors = vec![ruby_color; ruby.len()];
+            let placement = RubyPlacement { start: rows.along(along), cross: rows.ruby_cross(row, ruby_space, base[0].size.x), offset: self.offset, max: self.max };
+            paint_ruby(ui, ruby, &colors, placement, &self.orientation, &font_ruby);
         }
 
+        ui.set_opacity(opacity);
         response
     }
 }
 
 
-use crate::model::{ TypingCorrectnessChar, TypingCorrectnessSegment,TypingCorrectnessLine, TypingStatus };
+use crate::theme::Theme;
+use crate::model::{ TypingCorrectnessChar, TypingCorrectnessSegment,TypingCorrectnessLine, TypingStatus, SegmentDisplay, Dictation };
 
 pub struct RenderTypingLine {
     line: Line,
@@ -653,6 +894,14 @@ pub struct RenderTypingLine {
     orientation: CharOrientation,
     font_id: Option<egui::FontId>,
     offset: f32,
+    dictation: Option<Dictation>,
+    wrap: Option<Wrap>,
+    cursor: bool,
+    caret: CaretStyle,
+    smooth: bool,
+    theme: Theme,
+    ruby_scale: f32,
+    opacity: f32,
 }
 
 impl RenderTypingLine {
@@ -665,6 +914,14 @@ impl RenderTypingLine {
             status,
             font_id: None,
             offset: 0.0,
+            dictation: None,
+            wrap: None,
+            cursor: true,
+            caret: CaretStyle::Bar,
+            smooth: false,
+            theme: Theme::default(),
+            ruby_scale: RUBY_SCALE,
+            opacity: 1.0,
         }
     }
 
@@ -676,634 +933,231 @@ impl RenderTypingLine {
         self.offset = offset;
         self
     }
+    /// 書き取りモード
+    /// BaseOnly では入力を終えた漢字にも読みを付けない (入力中のセグメントは打った読みの仮名だけを表示する)
+    /// Hidden では入力を終えた部分を読みごと表示する
+    /// この行は入力した文字しか描かないので、まだ入力していない文字を隠すのは手本の行 (RenderLineWithRuby::with_display) で行う
+    pub fn with_dictation(mut self, dictation: Option<Dictation>) -> Self {
+        self.dictation = dictation;
+        self
+    }
+    /// 禁則処理をして折り返す (カーソルのある行が見えるよう、最後の行から表示する)
+    pub fn with_wrap(mut self, wrap: Wrap) -> Self {
+        self.wrap = Some(wrap);
+        self
+    }
+    /// カーソルを表示するか (結果の書き出しなど、入力中でない表示では消す)
+    pub fn with_cursor(mut self, cursor: bool) -> Self {
+        self.cursor = cursor;
+        self
+    }
+    /// カーソルの形
+    pub fn with_caret(mut self, caret: CaretStyle) -> Self {
+        self.caret = caret;
+        self
+    }
+    /// カーソルを入力位置へ滑らかに動かす (行が変わったときは動かさずに移る)
+    pub fn with_smooth(mut self, smooth: bool) -> Self {
+        self.smooth = smooth;
+        self
+    }
+    /// 正誤・入力途中の文字・カーソルの色
+    pub fn with_theme(mut self, theme: &Theme) -> Self {
+        self.theme = theme.clone();
+        self
+    }
+    /// 親文字に対する読みの大きさ
+    pub fn with_ruby_scale(mut self, scale: f32) -> Self {
+        self.ruby_scale = scale;
+        self
+    }
+    /// 不透明度 (入力中でない行を薄く表示する)
+    pub fn with_opacity(mut self, opacity: f32) -> Self {
+        self.opacity = opacity;
+        self
+    }
 
-    /// Calculate the size of the rendered text.
-    pub fn calc_size(&self, ui: &egui::Ui) -> (f32, f32) {
-        let mut font_main = self
-            .font_id.clone()
-            .unwrap_or_else(|| egui::FontSelection::Default.resolve(ui.style()));
-        let ruby_space = font_main.size * 0.3;
-        font_main.family = egui::FontFamily::Name("main".into());
-
-        let mut total_size = 0.0;
-        let mut max_size: f32 = 0.0;
-
-        // Calculate size for typed segments
-        for (index, segment) in self.line.segments.iter().enumerate() {
-            if index >= self.status.segment as usize {
-                break;
-            }
-
-            let mut s = match segment {
-                Segment::Plain { text } => text.clone(),
-                Segment::Annotated { base, reading: _ } => base.clone(),
-            };
-
-            if self.orientation == CharOrientation::Vertical {
-                s = s.replace('\u{30fc}', "\u{4e28}");
-            }
-
-            for ch in s.chars() {
-                let galley = ui.painter().layout_no_wrap(ch.to_string(), font_main.clone(), egui::Color32::WHITE);
-                let size = galley.size();
-
-                match (&self.orientation, is_japanese(ch)) {
-                    (CharOrientation::Horizontal, true) => {
-                        let dx = if is_japanese_kana(ch) { size.x * 0.8 } else { size.x };
-                        total_size += dx;
-                        max_size = max_size.max(size.y + ruby_space);
-                    },
-                    (CharOrientation::Horizontal, false) => {
-                        total_size += size.x * 0.8;
-                        max_size = max_size.max(size.y + ruby_space);
-                    },
-                    (CharOrientation::Vertical, true) => {
-                        let dy = if is_japanese_kana(ch) { size.x * 0.85 } else { size.x };
-                        total_size += dy;
-                        max_size = max_size.max(size.x + ruby_space);
-                    },
-                    (CharOrientation::Vertical, false) => {
-                        total_size += size.x * 0.75;
-                        max_size = max_size.max(size.y + ruby_space);
-                    },
-                }
-            }
-        }
-
-        // Calculate size for current segment
-        if self.status.segment < self.line.segments.len() as i32 {
-            let current_segment = &self.line.segments[self.status.segment as usize];
-            let text = match current_segment {
-                Segment::Plain { text } => text.chars().take(self.status.char_ as usize).collect::<String>(),
-                Segment::Annotated { base: _, reading } => reading.chars().take(self.status.char_ as usize).collect::<String>(),
-            };
+    /// 現在入力中のセグメントのうち入力済みの文字
+    /// 縦中横などが入力の途中で変わらないよう、セグメント全体を組んでから入力済みの文字数で切る
+    fn current_glyphs(&self, ui: &egui::Ui, font_main: &egui::FontId) -> Vec<Glyph> {
+        let text = match self.line.segments.get(self.status.segment as usize) {
+            Some(Segment::Plain { text }) => text,
+            Some(Segment::Annotated { base: _, reading }) => reading,
+            None => return Vec::new(),
+        };
+        let mut glyphs = layout_glyphs(ui, text, &self.orientation, font_main);
+        glyphs.truncate(self.status.char_.max(0) as usize);
+        glyphs
+    }
 
-            for ch in text.chars() {
-                let galley = ui.painter().layout_no_wrap(ch.to_string(), font_main.clone(), egui::Color32::WHITE);
-                let size = galley.size();
-                
-                match self.orientation {
-                    CharOrientation::Horizontal => {
-                        total_size += size.x * 0.8;
-                        max_size = max_size.max(size.y + ruby_space);
-                    },
-                    CharOrientation::Vertical => {
-                        total_size += if is_japanese(ch) { size.x * 0.85 } else { size.x * 0.75 };
-                        max_size = max_size.max(if is_japanese(ch) { size.x } else { size.y } + ruby_space);
-                    },
-                }
-            }
-        }
+    /// 入力済みのセグメントの文字と読みを並べる
+    /// 読みの配置は行全体で決め、RenderLineWithRuby で表示した行と文字の位置を揃える
+    fn layout_typed(&self, ui: &egui::Ui, font_main: &egui::FontId, font_ruby: &egui::FontId) -> (Vec<Glyph>, Vec<bool>, Vec<usize>, Vec<Option<RubyLayout>>) {
+        let typed = self.status.segment.max(0) as usize;
+        let (mut glyphs, mut unbreakable, mut owners) = layout_segments(ui, self.line.segments.iter(), &self.orientation, font_main);
+        let mut rubies = if self.dictation != Some(Dictation::BaseOnly) {
+            layout_ruby(ui, &self.line.segments.iter().collect::<Vec<_>>(), &mut glyphs, &owners, font_ruby)
+        } else {
+            Vec::new()
+        };
+        let count = owners.iter().take_while(|owner| **owner < typed).count();
+        glyphs.truncate(count);
+        unbreakable.truncate(count);
+        owners.truncate(count);
+        rubies.truncate(typed);
+        (glyphs, unbreakable, owners, rubies)
+    }
 
-        (total_size, max_size)
+    /// Calculate the size of the rendered text.
+    pub fn calc_size(&self, ui: &egui::Ui) -> (f32, f32) {
+        let font_main = resolve_font(self.font_id.clone(), ui, "main");
+        let font_ruby = resolve_ruby_font(self.font_id.clone(), ui, &font_main, self.ruby_scale);
+        let ruby_space = font_ruby.size;
+        // 入力済みのセグメントと入力中のセグメント
+        let (mut glyphs, _, _, _) = self.layout_typed(ui, &font_main, &font_ruby);
+        glyphs.extend(self.current_glyphs(ui, &font_main));
+        (advance_of(&glyphs), if glyphs.is_empty() { 0.0 } else { extent_of(&glyphs)+ruby_space })
     }
 }
 
 impl egui::Widget for RenderTypingLine {
     fn ui(self, ui: &mut egui::Ui) -> egui::Response {
-        // Retrieve text color from UI style.
-        let color = ui.style().visuals.strong_text_color();
-        let wrong_color = egui::Color32::from_hex("#f55252").unwrap();
-        let incorrect_color = egui::Color32::from_hex("#ff9898").unwrap();
-        let correct_color = egui::Color32::from_hex("#9097ff").unwrap();
-        let pending_color = egui::Color32::from_hex("#999999").unwrap();
-        let cursor_color = ui.style().visuals.selection.bg_fill;
-        let mut font_main = self
-            .font_id.clone()
-            .unwrap_or_else(|| egui::FontSelection::Default.resolve(ui.style()));
-        let mut font_kana = self
-            .font_id.clone()
-            .unwrap_or_else(|| egui::FontSelection::Default.resolve(ui.style()));
-        let mut font_ruby = self
-            .font_id
-            .unwrap_or_else(|| egui::FontSelection::Default.resolve(ui.style()));
-        font_main.family = egui::FontFamily::Name("main".into());
-        font_kana.family = egui::FontFamily::Name("kana".into());
-        font_ruby.family = egui::FontFamily::Name("ruby".into());
-        font_ruby.size = font_main.size*0.3;
-        // Calculate the total width and maximum height for the entire text.
-        // Allocate the required space.
-        let mut rectinfo = Vec::new();
+        let opacity = ui.opacity();
+        ui.multiply_opacity(self.opacity);
+        let wrong_color = self.theme.wrong;
+        let incorrect_color = self.theme.incorrect;
+        let correct_color = self.theme.correct;
+        let pending_color = self.theme.pending;
+        let cursor_color = self.theme.cursor;
+        let font_main = resolve_font(self.font_id.clone(), ui, "main");
+        let font_ruby = resolve_ruby_font(self.font_id.clone(), ui, &font_main, self.ruby_scale);
         let ruby_space = font_ruby.size;
 
         //
         // typed segment
         //
-        for (index,segment) in self.line.segments.iter().enumerate() {
-            if index>=self.status.segment as usize {
-                break;
-            }
-            let mut total_size = 0.0;
-            let mut max_size: f32 = 0.0;
-            let mut char_sizes = Vec::new();
-            let mut s = match segment {
-                Segment::Plain { text } => text.clone(),
-                Segment::Annotated { base, reading } => base.clone(),
-            };
-            if self.orientation == CharOrientation::Vertical {
-                s = s
-                    // // https://ja.wikipedia.org/wiki/CJK%E4%BA%92%E6%8F%9B%E5%BD%A2
-                    .replace('\u{2025}', "\u{fe30}")
-                    .replace('\u{2014}', "\u{fe31}")
-                    .replace('\u{2013}', "\u{fe32}")
-                    .replace('\u{005f}', "\u{fe33}")
-                    .replace('\u{0028}', "\u{fe35}")
-                    .replace('\u{0029}', "\u{fe36}")
-                    .replace('\u{007b}', "\u{fe37}")
-                    .replace('\u{007d}', "\u{fe38}")
-                    .replace('\u{3014}', "\u{fe39}")
-                    .replace('\u{3015}', "\u{fe3a}")
-                    .replace('\u{3010}', "\u{fe3b}")
-                    .replace('\u{3011}', "\u{fe3c}")
-                    .replace('\u{300a}', "\u{fe3d}")
-                    .replace('\u{300b}', "\u{fe3e}")
-                    .replace('\u{3008}', "\u{fe3f}")
-                    .replace('\u{3009}', "\u{fe40}")
-                    .replace('\u{300c}', "\u{fe41}")
-                    .replace('\u{300d}', "\u{fe42}")
-                    .replace('\u{300e}', "\u{fe43}")
-                    .replace('\u{300f}', "\u{fe44}")
-                    .replace('\u{005B}', "\u{fe47}")
-                    .replace('\u{005D}', "\u{fe48}")
-                    // https://ja.wikipedia.org/wiki/%E7%B8%A6%E6%9B%B8%E3%81%8D%E5%BD%A2
-                    .replace('\u{ff0c}', "\u{fe10}")
-                    .replace('\u{3001}', "\u{fe11}")
-                    .replace('\u{3002}', "\u{fe12}")
-                    .replace('\u{ff1a}', "\u{fe13}")
-                    .replace('\u{ff1b}', "\u{fe14}")
-                    .replace('\u{ff01}', "\u{fe15}")
-                    .replace('\u{ff1f}', "\u{fe16}")
-                    .replace('\u{3016}', "\u{fe17}")
-                    .replace('\u{3017}', "\u{fe18}")
-                    .replace('\u{2026}', "\u{fe19}")
-                    //
-                    .replace('\u{30fc}', "\u{4e28}");
+        let typed = self.status.segment.max(0) as usize;
+        let (mut glyphs, mut unbreakable, owners, rubies) = self.layout_typed(ui, &font_main, &font_ruby);
+        // 各文字の色を決定
+        let char_colors = self.correctness.segments.iter().take(typed).map(|segment| segment.chars.iter().map(|c| match c {
+                TypingCorrectnessChar::Correct => correct_color,
+                _ => incorrect_color,
+            }).collect::<Vec<_>>()).collect::<Vec<_>>();
+        let mut colors = Vec::new();
+        for (index, segment) in self.line.segments.iter().take(typed).enumerate() {
+            let count = owners.iter().filter(|owner| **owner == index).count();
+            match segment {
+                Segment::Annotated { base: _, reading: _ } => {
+                    let col = if char_colors[index].iter().any(|c| *c != correct_color) {
+                        incorrect_color  // If at least one character is incorrect
+                    } else {
+                        correct_color    // If all characters are correct
+                    };
+                    colors.extend(std::iter::repeat_n(col, count));
+                },
+                Segment::Plain { text: _ } => {
+                    colors.extend((0..count).map(|position| char_colors[index].get(position).copied().unwrap_or(incorrect_color)));
+                },
             }
-            for ch in s.chars() {
-                let s = ch.to_string();
-                let galley = ui.painter().layout_no_wrap(s, font_main.clone(), color);
-                let size = galley.size();
-                match (&self.orientation,is_japanese(ch)) {
-                    (CharOrientation::Horizontal,true) => {
-                        char_sizes.push((ch, size));
-                        let dx = if is_japanese_kana(ch) { size.x*0.8 } else { size.x };
-                        total_size += dx;
-                        max_size = max_size.max(size.y+ruby_space);
-                    },
-                    (CharOrientation::Horizontal,false) => {
-                        char_sizes.push((ch, size));
-                        let dx = size.x*0.8;
-                        total_size += dx;
-                        max_size = max_size.max(size.y+ruby_space);
-                    },
-                    (CharOrientation::Vertical,true) => {
-                        char_sizes.push((ch, size));
-                        let dy = if is_japanese_kana(ch) { size.x*0.85 } else { size.x };
-                        total_size += dy;
-                        max_size = max_size.max(size.x+ruby_space);
-                    },
-                    (CharOrientation::Vertical,false) => {
-                        char_sizes.push((ch, size));
-                        let dy = size.x*0.75;
-                        total_size += dy;
-                        max_size = max_size.max(size.y+ruby_space);
-                    },
-                };
+        }
+        // 現在入力中のセグメント (PendingSegment) の入力済み文字
+        if self.status.segment < self.line.segments.len() as i32 {
+            let current = self.current_glyphs(ui, &font_main);
+            for i in 0..current.len() {
+                unbreakable.push(false);
+                colors.push(match &self.correctness.segments[self.status.segment as usize].chars[i] {
+                    TypingCorrectnessChar::Correct => correct_color,
+                    TypingCorrectnessChar::Incorrect => incorrect_color,
+                    TypingCorrectnessChar::Pending => pending_color,
+                });
             }
-            rectinfo.push((total_size, max_size, char_sizes,segment));
+            glyphs.extend(current);
         }
-        // rectinfoの中身を総和
-        let total_size = rectinfo.iter().map(|(total_size, _, _,_)| *total_size).sum::<f32>();
-        let max_size = rectinfo.iter().map(|(_, max_size, _,_)| *max_size).max_by(|a, b| a.partial_cmp(b).unwrap()).unwrap_or(0.0);
-        let (rect, response) = ui.allocate_exact_size(if self.orientation==CharOrientation::Horizontal { egui::vec2(total_size, max_size) } else { egui::vec2(max_size, total_size ) }, egui::Sense::hover());
-        // Render each character in sequence.
-        let (mut x_offset,mut y_offset) = match self.orientation {
-            CharOrientation::Horizontal => (rect.left(), rect.top()+font_main.size / 2.0),
-            CharOrientation::Vertical => (rect.left()+font_main.size / 2.0, rect.top()),
+        // 折り返してカーソルのある行までを表示する
+        let positions = flow(&glyphs, &unbreakable, self.wrap.map(|wrap| wrap.length));
+        let next = match (positions.last(), glyphs.last()) {
+            (Some((row, along)), Some(glyph)) => (*row, along+glyph.advance),
+            _ => (0, 0.0),
         };
-        // typed segmentsの表示
-        for (index, (total_size, max_size, char_sizes, segment)) in rectinfo.iter().enumerate() {
-            let mut x_offset_ruby = x_offset;
-            let mut y_offset_ruby = y_offset;
-            let mut vert_x_offset = char_sizes[0].1.x;
-            // 各文字の色を決定
-            let char_color = self.correctness.segments[index].chars.iter().map(|c| match c {
-                    TypingCorrectnessChar::Correct => correct_color,
-                    _ => incorrect_color,
-                }).collect::<Vec<_>>();
-            match segment {
-                Segment::Annotated { base, reading } => {
-                    let col = &if self.correctness.segments[index].chars.iter().any(|c| match c {
-                            TypingCorrectnessChar::Correct => false,  // The character is correct
-                            _ => true,                                // The character is incorrect
-                        }) {
-                            incorrect_color  // If at least one character is incorrect
-                        } else {
-                            correct_color    // If all characters are correct
-                        };
-                    // baseの描画
-                    for (ch, size) in char_sizes.iter() {
-                        let mut f = true;
-                        match self.orientation {
-                            CharOrientation::Horizontal if x_offset-self.offset+size.x < 0.0 => {
-                                f = false;
-                            }
-                            CharOrientation::Vertical if y_offset-self.offset+size.x < 0.0 => {
-                                f = false;
-                            }
-                            _ => {}
-                        }
-                        match (&self.orientation,is_japanese(*ch)) {
-                            (CharOrientation::Horizontal,true) => {
-                                let dx = if is_japanese_kana(*ch) { size.x*0.8 } else { size.x };
-                                if f {
-                                    let oy = if is_japanese_kana(*ch) { if is_japanese_hiragana(*ch) { size.y*0.03 } else { size.y*0.01 } } else { 0.0 };
-                                    if is_japanese_kana(*ch) && *ch != '\u{30fc}' {
-                                        let mut pos = egui::pos2(x_offset+dx/2.0-self.offset, y_offset+oy+ruby_space);
-                                        if is_japanese_hiragana(*ch) { font_kana.size = font_main.size*0.85; } else { font_kana.size = font_main.size*0.95; }
-                                        if [
-                                            '\u{3041}','\u{3043}','\u{3045}','\u{3047}','\u{3049}','\u{3063}','\u{3041}','\u{3083}','\u{3085}','\u{3087}','\u{308e}','\u{3095}','\u{3096}','\u{3041}',
-                                            '\u{30a1}','\u{30a3}','\u{30a5}','\u{30a7}','\u{30a9}','\u{30c3}','\u{30e3}','\u{30e5}','\u{30e7}','\u{30ee}','\u{30f5}','\u{30f6}'
-                                            ].contains(&*ch) {
-                                            if is_japanese_hiragana(*ch) { font_kana.size = font_main.size*0.8; } else { font_kana.size = font_main.size*0.9; }
-                                            pos = egui::pos2(x_offset+dx/2.0-self.offset-size.x/100.0, y_offset+oy+size.y/80.0+ruby_space);
-                                        }
-                                        render_char_at(ui, *ch, pos, CharOrientation::Horizontal, &font_kana, *col);
-                                    }
-                                    else if *ch == '\u{30fc}'
-                                    {
-                                        let pos = egui::pos2(x_offset+dx/2.0-self.offset, y_offset+oy+ruby_space);
-                                        let mut font = font_main.clone();
-                                        font.size = font_main.size*0.8;
-                                        render_char_at(ui, *ch, pos, CharOrientation::Horizontal, &font, *col);
-                                    }
-                                    else {
-                                        let pos = egui::pos2(x_offset+dx/2.0-self.offset, y_offset+oy+ruby_space);
-                                        render_char_at(ui, *ch, pos, CharOrientation::Horizontal, &font_main, *col);
-                                    }
-                                }
-                                x_offset += dx;
-                            },
-                            (CharOrientation::Horizontal,false) => {
-                                let dx = size.x*0.8;
-                                if f{
-                                    let pos = egui::pos2(x_offset+dx/2.0-self.offset, y_offset+size.y/20.0+ruby_space);
-                                    let mut font = font_main.clone();
-                                    font.size = font_main.size*0.85;
-                                    render_char_at(ui, *ch, pos, CharOrientation::Horizontal, &font, *col);
-                                }
-                                x_offset += dx;
-                            },
-                            (CharOrientation::Vertical,true) => {
-                                let dy = if is_japanese_kana(*ch) { size.x*0.85 } else { size.x };
-                                if f {
-                                    let mut pos = egui::pos2(x_offset, y_offset+dy/2.0-self.offset);
-                                    if is_japanese_kana(*ch) {
-                                        if is_japanese_hiragana(*ch) { font_kana.size = font_main.size*0.85; } else { font_kana.size = font_main.size*0.95; }
-                                        if [
-                                            '\u{3041}','\u{3043}','\u{3045}','\u{3047}','\u{3049}','\u{3063}','\u{3041}','\u{3083}','\u{3085}','\u{3087}','\u{308e}','\u{3095}','\u{3096}','\u{3041}',
-                                            '\u{30a1}','\u{30a3}','\u{30a5}','\u{30a7}','\u{30a9}','\u{30c3}','\u{30e3}','\u{30e5}','\u{30e7}','\u{30ee}','\u{30f5}','\u{30f6}'
-                                            ].contains(&*ch) {
-                                            if is_japanese_hiragana(*ch) { font_kana.size = font_main.size*0.8; } else { font_kana.size = font_main.size*0.9; }
-                                            pos = egui::pos2(x_offset+size.x/10.0, y_offset+dy/2.0-size.y/10.0-self.offset);
-                                        }
-                                        render_char_at(ui, *ch, pos, CharOrientation::Horizontal, &font_kana, *col);
-                                    }
-                                    else if *ch == '\u{4e28}'
-                                    {
-                                        let mut font = font_main.clone();
-                                        font.size = font_main.size*0.93;
-                                        render_char_at(ui, *ch, pos, CharOrientation::Horizontal, &font, *col);
-                                    }
-                                    else {
-                                        render_char_at(ui, *ch, pos, CharOrientation::Horizontal, &font_main, *col);
-                                    }
-                                }
-                                y_offset += dy;
-                            },
-                            (CharOrientation::Vertical,false) => {
-                                let dy = size.x*0.75;
-                                if f {
-                                    let pos = egui::pos2(x_offset+font_main.size/100.0, y_offset+dy/2.0-self.offset);
-                                    let mut font = font_main.clone();
-                                    font.size = font_main.size*0.9;
-                                    render_char_at(ui, *ch, pos, CharOrientation::Vertical, &font, *col);
-                                }
-                                y_offset += dy;
-                            },
-                        };
-                    }
-                    // rubyの描画
-                    let ruby: Vec<char> = reading.chars().collect();
-                    let w = total_size/(ruby.len()) as f32;
-                    x_offset_ruby += w*0.5;
-                    y_offset_ruby += w*0.5;
-                    match self.orientation {
-                        CharOrientation::Horizontal => {
-                            for (ch,col) in ruby.iter().zip(char_color.iter()) {
-                                let s = ch.to_string();
-                                let galley = ui.painter().layout_no_wrap(s, font_ruby.clone(), *col);
-                                let size = galley.size();
-                                let mut f = true;
-                                if x_offset_ruby-self.offset+size.x < 0.0 {
-                                    f = false;
-                                }
-                                if f {
-                                    let dx = galley.rect.width()*0.0;
-                                    if is_japanese_kana(*ch) && *ch != '\u{30fc}' {
-                                        let mut pos = egui::pos2(x_offset_ruby+dx-self.offset, rect.top()+ruby_space*0.5);
-                                        if [
-                                            '\u{3041}','\u{3043}','\u{3045}','\u{3047}','\u{3049}','\u{3063}','\u{3041}','\u{3083}','\u{3085}','\u{3087}','\u{308e}','\u{3095}','\u{3096}','\u{3041}',
-                                            '\u{30a1}','\u{30a3}','\u{30a5}','\u{30a7}','\u{30a9}','\u{30c3}','\u{30e3}','\u{30e5}','\u{30e7}','\u{30ee}','\u{30f5}','\u{30f6}'
-                                            ].contains(&ch) {
-                                            pos = egui::pos2(x_offset_ruby+dx-self.offset-size.x/100.0, rect.top()+ruby_space*0.5+size.y/80.0);
-                                        }
-                                        render_char_at(ui, *ch, pos, CharOrientation::Horizontal, &font_ruby, *col);
-                                    }
-                                    else if *ch == '\u{30fc}'
-                                    {
-                                        let pos = egui::pos2(x_offset_ruby+dx-self.offset, rect.top()+ruby_space*0.5);
-                                        render_char_at(ui, *ch, pos, CharOrientation::Horizontal, &font_ruby, *col);
-                                    }
-                                    else {
-                                        let mut pos = egui::pos2(x_offset_ruby+dx-self.offset, rect.top()+ruby_space*0.5);
-                                        render_char_at(ui, *ch, pos, CharOrientation::Horizontal, &font_ruby, *col);
-                                    }
-                                }
-                                x_offset_ruby += w;
-                            }
-                        },
-                        CharOrientation::Vertical => {
-                            for (ch,col) in ruby.iter().zip(char_color.iter()) {
-                                let s = ch.to_string();
-                                let galley = ui.painter().layout_no_wrap(s, font_ruby.clone(), *col);
-                                let size = galley.size();
-                                let mut f = true;
-                                if y_offset_ruby-self.offset+size.x < 0.0 {
-                                    f = false;
-                                }
-                                if f {
-                                    let dx = size.x*0.5;
-                                    let dy = size.x*0.25;
-                                    let mut pos = egui::pos2(rect.left()+vert_x_offset+dx, y_offset+dy-self.offset);
-                                    if is_japanese_kana(*ch) {
-                                        let mut pos = egui::pos2(rect.left()+vert_x_offset+dx, y_offset_ruby+dy-self.offset);
-                                        if [
-                                            '\u{3041}','\u{3043}','\u{3045}','\u{3047}','\u{3049}','\u{3063}','\u{3041}','\u{3083}','\u{3085}','\u{3087}','\u{308e}','\u{3095}','\u{3096}','\u{3041}',
-                                            '\u{30a1}','\u{30a3}','\u{30a5}','\u{30a7}','\u{30a9}','\u{30c3}','\u{30e3}','\u{30e5}','\u{30e7}','\u{30ee}','\u{30f5}','\u{30f6}'
-                                            ].contains(&ch) {
-                                            pos = egui::pos2(rect.left()+vert_x_offset+dx+size.x/10.0, y_offset_ruby+dy-size.y/10.0-self.offset);
-                                        }
-                                        render_char_at(ui, *ch, pos, CharOrientation::Horizontal, &font_ruby, *col);
-                                    }
-                                    else if *ch == '\u{4e28}'
-                                    {
-                                        render_char_at(ui, *ch, pos, CharOrientation::Horizontal, &font_ruby, *col);
-                                    }
-                                    else {
-                                        render_char_at(ui, *ch, pos, CharOrientation::Horizontal, &font_ruby, *col);
-                                    }
-                                }
-                                y_offset_ruby += w;
-                            }
-                        }
+        let height = if glyphs.is_empty() { 0.0 } else { extent_of(&glyphs)+ruby_space };
+        let first = self.wrap.map_or(0, |wrap| (next.0+1).saturating_sub(wrap.rows.max(1)));
+        let count = next.0+1-first;
+        let length = flow_length(&glyphs, &positions);
+        let (rect, response) = ui.allocate_exact_size(if self.orientation==CharOrientation::Horizontal { egui::vec2(length, height*count as f32) } else { egui::vec2(height*count as f32, length) }, egui::Sense::hover());
+        let rows = Rows { rect, orientation: self.orientation.clone(), first, count, height, font_size: font_main.size };
+        // 入力済み文字の表示（色付き） 左/上にはみ出した文字は描かない
+        for ((glyph, (row, along)), color) in glyphs.iter().zip(positions.iter()).zip(colors.iter()) {
+            if !rows.is_shown(*row) {
+                continue;
+            }
+            let (x_offset, y_offset) = rows.origin(*row);
+            match self.orientation {
+                CharOrientation::Horizontal => {
+                    if is_visible(x_offset+along-self.offset, glyph.size.x, f32::INFINITY) {
+                        paint_glyph(ui, glyph, egui::pos2(x_offset+along-self.offset, y_offset+ruby_space), &self.orientation, *color);
                     }
                 },
-                Segment::Plain { text } => {
-                    // baseの描画 (rubyは無い)
-                    for ((ch, size), col) in char_sizes.iter().zip(char_color.iter()) {
-                        let mut f = true;
-                        match self.orientation {
-                            CharOrientation::Horizontal if x_offset-self.offset+size.x < 0.0 => {
-                                f = false;
-                            }
-                            CharOrientation::Vertical if y_offset-self.offset+size.x < 0.0 => {
-                                f = false;
-                            }
-                            _ => {}
-                        }
-                        match (&self.orientation,is_japanese(*ch)) {
-                            (CharOrientation::Horizontal,true) => {
-                                let dx = if is_japanese_kana(*ch) { size.x*0.8 } else { size.x };
-                                if f {
-                                    let oy = if is_japanese_kana(*ch) { if is_japanese_hiragana(*ch) { size.y*0.03 } else { size.y*0.01 } } else { 0.0 };
-                                    if is_japanese_kana(*ch) && *ch != '\u{30fc}' {
-                                        let mut pos = egui::pos2(x_offset+dx/2.0-self.offset, y_offset+oy+ruby_space);
-                                        if is_japanese_hiragana(*ch) { font_kana.size = font_main.size*0.85; } else { font_kana.size = font_main.size*0.95; }
-                                        if [
-                                            '\u{3041}','\u{3043}','\u{3045}','\u{3047}','\u{3049}','\u{3063}','\u{3041}','\u{3083}','\u{3085}','\u{3087}','\u{308e}','\u{3095}','\u{3096}','\u{3041}',
-                                            '\u{30a1}','\u{30a3}','\u{30a5}','\u{30a7}','\u{30a9}','\u{30c3}','\u{30e3}','\u{30e5}','\u{30e7}','\u{30ee}','\u{30f5}','\u{30f6}'
-                                            ].contains(&*ch) {
-                                            if is_japanese_hiragana(*ch) { font_kana.size = font_main.size*0.8; } else { font_kana.size = font_main.size*0.9; }
-                                            pos = egui::pos2(x_offset+dx/2.0-self.offset-size.x/100.0, y_offset+oy+size.y/80.0+ruby_space);
-                                        }
-                                        render_char_at(ui, *ch, pos, CharOrientation::Horizontal, &font_kana, *col);
-                                    }
-                                    else if *ch == '\u{30fc}'
-                                    {
-                                        let pos = egui::pos2(x_offset+dx/2.0-self.offset, y_offset+oy+ruby_space);
-                                        let mut font = font_main.clone();
-                                        font.size = font_main.size*0.8;
-                                        render_char_at(ui, *ch, pos, CharOrientation::Horizontal, &font, *col);
-                                    }
-                                    else {
-                                        let pos = egui::pos2(x_offset+dx/2.0-self.offset, y_offset+oy+ruby_space);
-                                        render_char_at(ui, *ch, pos, CharOrientation::Horizontal, &font_main, *col);
-                                    }
-                                }
-                                x_offset += dx;
-                            },
-                            (CharOrientation::Horizontal,false) => {
-                                let dx = size.x*0.8;
-                                if f {
-                                    let pos = egui::pos2(x_offset+dx/2.0-self.offset, y_offset+size.y/20.0+ruby_space);
-                                    let mut font = font_main.clone();
-                                    font.size = font_main.size*0.85;
-                                    render_char_at(ui, *ch, pos, CharOrientation::Horizontal, &font, *col);
-                                }
-                                x_offset += dx;
-                            },
-                            (CharOrientation::Vertical,true) => {
-                                let dy = if is_japanese_kana(*ch) { size.x*0.85 } else { size.x };
-                                if f {
-                                    let mut pos = egui::pos2(x_offset, y_offset+dy/2.0-self.offset);
-                                    if is_japanese_kana(*ch) {
-                                        if is_japanese_hiragana(*ch) { font_kana.size = font_main.size*0.85; } else { font_kana.size = font_main.size*0.95; }
-                                        if [
-                                            '\u{3041}','\u{3043}','\u{3045}','\u{3047}','\u{3049}','\u{3063}','\u{3041}','\u{3083}','\u{3085}','\u{3087}','\u{308e}','\u{3095}','\u{3096}','\u{3041}',
-                                            '\u{30a1}','\u{30a3}','\u{30a5}','\u{30a7}','\u{30a9}','\u{30c3}','\u{30e3}','\u{30e5}','\u{30e7}','\u{30ee}','\u{30f5}','\u{30f6}'
-                                            ].contains(&*ch) {
-                                            if is_japanese_hiragana(*ch) { font_kana.size = font_main.size*0.8; } else { font_kana.size = font_main.size*0.9; }
-                                            pos = egui::pos2(x_offset+size.x/10.0, y_offset+dy/2.0-size.y/10.0-self.offset);
-                                        }
-                                        render_char_at(ui, *ch, pos, CharOrientation::Horizontal, &font_kana, *col);
-                                    }
-                                    else if *ch == '\u{4e28}'
-                                    {
-                                        let mut font = font_main.clone();
-                                        font.size = font_main.size*0.93;
-                                        render_char_at(ui, *ch, pos, CharOrientation::Horizontal, &font, *col);
-                                    }
-                                    else {
-                                        render_char_at(ui, *ch, pos, CharOrientation::Horizontal, &font_main, *col);
-                                    }
-                                }
-                                y_offset += dy;
-                            },
-                            (CharOrientation::Vertical,false) => {
-                                let dy = size.x*0.75;
-                                if f {
-                                    let pos = egui::pos2(x_offset+font_main.size/100.0, y_offset+dy/2.0-self.offset);
-                                    let mut font = font_main.clone();
-                                    font.size = font_main.size*0.9;
-                                    render_char_at(ui, *ch, pos, CharOrientation::Vertical, &font, *col);
-                                }
-                                y_offset += dy;
-                            },
-                        };
+                CharOrientation::Vertical => {
+                    if is_visible(y_offset+along-self.offset, glyph.size.x, f32::INFINITY) {
+                        paint_glyph(ui, glyph, egui::pos2(x_offset, y_offset+along-self.offset), &self.orientation, *color);
                     }
-                }
-            };
+                },
+            }
         }
-        
-        // 現在入力中のセグメント (PendingSegment) の表示
-        if self.status.segment < self.line.segments.len() as i32 {
-            let current_segment = &self.line.segments[self.status.segment as usize];
-            let text = match current_segment {
-                Segment::Plain { text } => text.chars().take(self.status.char_ as usize).collect::<String>(),
-                Segment::Annotated { base: _, reading } => reading.chars().take(self.status.char_ as usize).collect::<String>(),
+        // rubyの描画
+        for (index, ruby) in rubies.iter().enumerate() {
+            let Some(ruby) = ruby else {
+                continue;
+            };
+            let Some(start) = owners.iter().position(|owner| *owner == index) else {
+                continue;
             };
-            
-            let mut s = text;
-            if self.orientation == CharOrientation::Vertical {
-                s = s
-                    // // https://ja.wikipedia.org/wiki/CJK%E4%BA%92%E6%8F%9B%E5%BD%A2
-                    .replace('\u{2025}', "\u{fe30}")
-                    .replace('\u{2014}', "\u{fe31}")
-                    .replace('\u{2013}', "\u{fe32}")
-                    .replace('\u{005f}', "\u{fe33}")
-                    .replace('\u{0028}', "\u{fe35}")
-                    .replace('\u{0029}', "\u{fe36}")
-                    .replace('\u{007b}', "\u{fe37}")
-                    .replace('\u{007d}', "\u{fe38}")
-                    .replace('\u{3014}', "\u{fe39}")
-                    .replace('\u{3015}', "\u{fe3a}")
-                    .replace('\u{3010}', "\u{fe3b}")
-                    .replace('\u{3011}', "\u{fe3c}")
-                    .replace('\u{300a}', "\u{fe3d}")
-                    .replace('\u{300b}', "\u{fe3e}")
-                    .replace('\u{3008}', "\u{fe3f}")
-                    .replace('\u{3009}', "\u{fe40}")
-                    .replace('\u{300c}', "\u{fe41}")
-                    .replace('\u{300d}', "\u{fe42}")
-                    .replace('\u{300e}', "\u{fe43}")
-                    .replace('\u{300f}', "\u{fe44}")
-                    .replace('\u{005B}', "\u{fe47}")
-                    .replace('\u{005D}', "\u{fe48}")
-                    // https://ja.wikipedia.org/wiki/%E7%B8%A6%E6%9B%B8%E3%81%8D%E5%BD%A2
-                    .replace('\u{ff0c}', "\u{fe10}")
-                    .replace('\u{3001}', "\u{fe11}")
-                    .replace('\u{3002}', "\u{fe12}")
-                    .replace('\u{ff1a}', "\u{fe13}")
-                    .replace('\u{ff1b}', "\u{fe14}")
-                    .replace('\u{ff01}', "\u{fe15}")
-                    .replace('\u{ff1f}', "\u{fe16}")
-                    .replace('\u{3016}', "\u{fe17}")
-                    .replace('\u{3017}', "\u{fe18}")
-                    .replace('\u{2026}', "\u{fe19}")
-                    //
-                    .replace('\u{30fc}', "\u{4e28}");
+            let (row, along) = positions[start];
+            if !rows.is_shown(row) {
+                continue;
             }
+            let base = &glyphs[start..start+owners.iter().filter(|owner| **owner == index).count()];
+            let placement = RubyPlacement { start: rows.along(along), cross: rows.ruby_cross(row, ruby_space, base[0].size.x), offset: self.offset, max: f32::INFINITY };
+            paint_ruby(ui, ruby, &char_colors[index], placement, &self.orientation, &font_ruby);
+        }
+        // 以降はカーソルの位置から描く
+        let (mut x_offset, mut y_offset) = rows.origin(next.0);
+        match self.orientation {
+            CharOrientation::Horizontal => x_offset += next.1,
+            CharOrientation::Vertical => y_offset += next.1,
+        }
 
-            // 入力済み文字の表示（色付き）
-            for (i, ch) in s.chars().enumerate() {
-                let color = match &self.correctness.segments[self.status.segment as usize].chars[i] {
-                    TypingCorrectnessChar::Correct => correct_color,
-                    TypingCorrectnessChar::Incorrect => incorrect_color,
-                    TypingCorrectnessChar::Pending => pending_color,
-                };
-
-                let galley = ui.painter().layout_no_wrap(ch.to_string(), font_main.clone(), color);
-                let size = galley.size();
-
-                match (&self.orientation, is_japanese(ch)) {
-                    (CharOrientation::Horizontal, true) => {
-                        let dx = if is_japanese_kana(ch) { size.x*0.8 } else { size.x };
-                        let oy = if is_japanese_kana(ch) { if is_japanese_hiragana(ch) { size.y*0.03 } else { size.y*0.01 } } else { 0.0 };
-                        if is_japanese_kana(ch) && ch != '\u{30fc}' {
-                            let mut pos = egui::pos2(x_offset+dx/2.0-self.offset, y_offset+oy+ruby_space);
-                            if is_japanese_hiragana(ch) { font_kana.size = font_main.size*0.85; } else { font_kana.size = font_main.size*0.95; }
-                            if [
-                                '\u{3041}','\u{3043}','\u{3045}','\u{3047}','\u{3049}','\u{3063}','\u{3041}','\u{3083}','\u{3085}','\u{3087}','\u{308e}','\u{3095}','\u{3096}','\u{3041}',
-                                '\u{30a1}','\u{30a3}','\u{30a5}','\u{30a7}','\u{30a9}','\u{30c3}','\u{30e3}','\u{30e5}','\u{30e7}','\u{30ee}','\u{30f5}','\u{30f6}'
-                                ].contains(&ch) {
-                                if is_japanese_hiragana(ch) { font_kana.size = font_main.size*0.8; } else { font_kana.size = font_main.size*0.9; }
-                                pos = egui::pos2(x_offset+dx/2.0-self.offset-size.x/100.0, y_offset+oy+size.y/80.0+ruby_space);
-                            }
-                            render_char_at(ui, ch, pos, CharOrientation::Horizontal, &font_kana, color);
-                        }
-                        else if ch == '\u{30fc}'
-                        {
-                            let pos = egui::pos2(x_offset+dx/2.0-self.offset, y_offset+oy+ruby_space);
-                            let mut font = font_main.clone();
-                            font.size = font_main.size*0.8;
-                            render_char_at(ui, ch, pos, CharOrientation::Horizontal, &font, color);
-                        }
-                        else {
-                            let pos = egui::pos2(x_offset+dx/2.0-self.offset, y_offset+oy+ruby_space);
-                            render_char_at(ui, ch, pos, CharOrientation::Horizontal, &font_main, color);
-                        }
-                        x_offset += dx;
-                    },
-                    (CharOrientation::Horizontal,false) => {
-                        let dx = size.x*0.8;
-                        let pos = egui::pos2(x_offset+dx/2.0-self.offset, y_offset+size.y/20.0+ruby_space);
-                        let mut font = font_main.clone();
-                        font.size = font_main.size*0.85;
-                        render_char_at(ui, ch, pos, CharOrientation::Horizontal, &font, color);
-                        x_offset += dx;
-                    },
-                    (CharOrientation::Vertical,true) => {
-                        let dy = if is_japanese_kana(ch) { size.x*0.85 } else { size.x };
-                        let mut pos = egui::pos2(x_offset, y_offset+dy/2.0-self.offset);
-                        if is_japanese_kana(ch) {
-                            if is_japanese_hiragana(ch) { font_kana.size = font_main.size*0.85; } else { font_kana.size = font_main.size*0.95; }
-                            if [
-                                '\u{3041}','\u{3043}','\u{3045}','\u{3047}','\u{3049}','\u{3063}','\u{3041}','\u{3083}','\u{3085}','\u{3087}','\u{308e}','\u{3095}','\u{3096}','\u{3041}',
-                                '\u{30a1}','\u{30a3}','\u{30a5}','\u{30a7}','\u{30a9}','\u{30c3}','\u{30e3}','\u{30e5}','\u{30e7}','\u{30ee}','\u{30f5}','\u{30f6}'
-                                ].contains(&ch) {
-                                if is_japanese_hiragana(ch) { font_kana.size = font_main.size*0.8; } else { font_kana.size = font_main.size*0.9; }
-                                pos = egui::pos2(x_offset+size.x/10.0, y_offset+dy/2.0-size.y/10.0-self.offset);
-                            }
-                            render_char_at(ui, ch, pos, CharOrientation::Horizontal, &font_kana, color);
-                        }
-                        else if ch == '\u{4e28}'
-                        {
-                            let mut font = font_main.clone();
-                            font.size = font_main.size*0.93;
-                            render_char_at(ui, ch, pos, CharOrientation::Horizontal, &font, color);
-                        }
-                        else {
-                            render_char_at(ui, ch, pos, CharOrientation::Horizontal, &font_main, color);
-                        }
-                        y_offset += dy;
-                    },
-                    (CharOrientation::Vertical,false) => {
-                        let dy = size.x*0.75;
-                        let pos = egui::pos2(x_offset+font_main.size/100.0, y_offset+dy/2.0-self.offset);
-                        let mut font = font_main.clone();
-                        font.size = font_main.size*0.9;
-                        render_char_at(ui, ch, pos, CharOrientation::Vertical, &font, color);
-                        y_offset += dy;
-                    },
-                }
+        // カーソルの位置と未確定文字列の長さ (行の中での位置で動かし、表示するときにスクロールの分をずらす)
+        let unconfirmed_length = self.status.unconfirmed.iter().map(|ch| {
+            let size = char_galley(ui, *ch, &font_main).size();
+            match self.orientation {
+                CharOrientation::Horizontal => size.x*0.8,
+                CharOrientation::Vertical => size.x*0.75,
             }
+        }).sum::<f32>();
+        let (caret, caret_length) = if self.smooth {
+            let id = ui.id().with(("caret", self.status.line));
+            let ctx = ui.ctx().clone();
+            let x = ctx.animate_value_with_time(id.with("x"), x_offset-rect.left(), CARET_ANIMATION_TIME);
+            let y = ctx.animate_value_with_time(id.with("y"), y_offset-rect.top(), CARET_ANIMATION_TIME);
+            let length = ctx.animate_value_with_time(id.with("length"), unconfirmed_length, CARET_ANIMATION_TIME);
+            (rect.min+egui::vec2(x, y), length)
+        } else {
+            (egui::pos2(x_offset, y_offset), unconfirmed_length)
+        };
+        let caret = match self.orientation {
+            CharOrientation::Horizontal => caret-egui::vec2(self.offset, 0.0),
+            CharOrientation::Vertical => caret-egui::vec2(0.0, self.offset),
+        };
+
+        // ブロックのカーソルは未確定文字列の後ろに描く
+        if self.cursor && self.caret == CaretStyle::Block {
+            paint_caret(ui, self.caret, caret, caret_length, &self.orientation, font_main.size, cursor_color);
         }
 
         // 未確定文字列の表示
         for ch in &self.status.unconfirmed {
-            let galley = ui.painter().layout_no_wrap(ch.to_string(), font_main.clone(), pending_color);
-            let size = galley.size();
+            let size = char_galley(ui, *ch, &font_main).size();
 
             match &self.orientation {
                 CharOrientation::Horizontal => {
@@ -1327,37 +1181,13 @@ impl egui::Widget for RenderTypingLine {
         }
 
         // カーソルの表示
-        match &self.orientation {
-            CharOrientation::Horizontal => {
-                let cursor_width = 2.0;
-                let cursor_height = font_main.size;
-                ui.painter().rect_filled(
-                    egui::Rect::from_min_size(
-                        egui::pos2(x_offset-self.offset, y_offset),
-                        egui::vec2(cursor_width, cursor_height),
-                    ),
-                    0.0,
-                    cursor_color,
-                );
-            },
-            CharOrientation::Vertical => {
-                let cursor_width = 2.0;
-                let cursor_height = font_main.size;
-                ui.painter().rect_filled(
-                    egui::Rect::from_min_size(
-                        egui::pos2(x_offset-font_main.size*0.5, y_offset-self.offset),
-                        egui::vec2( cursor_height, cursor_width),
-                    ),
-                    0.0,
-                    cursor_color,
-                );
-            },
+        if self.cursor && self.caret != CaretStyle::Block {
+            paint_caret(ui, self.caret, caret, caret_length, &self.orientation, font_main.size, cursor_color);
         }
         
         // 誤入力文字の表示
         if let Some(ch) = self.status.last_wrong_keydown {
-            let galley = ui.painter().layout_no_wrap(ch.to_string(), font_main.clone(), pending_color);
-            let size = galley.size();
+            let size = char_galley(ui, ch, &font_main).size();
             match &self.orientation {
                 CharOrientation::Horizontal => {
                     let dx = size.x*0.8;
@@ -1379,6 +1209,7 @@ impl egui::Widget for RenderTypingLine {
             }
         }
         
+        ui.set_opacity(opacity);
         response
     }
 }
@@ -0,0 +1,185 @@
+// theme.rs
+// 画面の配色 (入力の正誤・カーソル・ルビ・背景など)
+// 組み込みの配色のほか、JSONファイルで書いた配色を読み込める (書いていない色は Dark と同じ)
+
+use egui::Color32;
+use serde::{Serialize, Deserialize};
+
+/// 色を "#rrggbb" / "#rrggbbaa" の文字列として読み書きする
+mod hex_color {
+    use egui::Color32;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(color: &Color32, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&color.to_hex())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Color32, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        Color32::from_hex(&text).map_err(|_| serde::de::Error::custom(format!("invalid color \"{}\"", text)))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Theme {
+    pub name: String,
+    /// ボタンなどを暗い配色にするか
+    pub dark: bool,
+    /// 問題文の文字
+    #[serde(with = "hex_color")]
+    pub text: Color32,
+    /// 問題文の読み
+    #[serde(with = "hex_color")]
+    pub ruby: Color32,
+    /// まだ確定していない文字 (入力途中のローマ字)
+    #[serde(with = "hex_color")]
+    pub pending: Color32,
+    /// 正しく入力した文字
+    #[serde(with = "hex_color")]
+    pub correct: Color32,
+    /// 誤りのあった文字
+    #[serde(with = "hex_color")]
+    pub incorrect: Color32,
+    /// 誤って押したキー
+    #[serde(with = "hex_color")]
+    pub wrong: Color32,
+    #[serde(with = "hex_color")]
+    pub cursor: Color32,
+    /// 画面の背景
+    #[serde(with = "hex_color")]
+    pub background: Color32,
+    /// 設定やエントリの一覧を表示する横のパネルの背景
+    #[serde(with = "hex_color")]
+    pub side_panel: Color32,
+    /// 問題の内容を表示する下のパネルの背景
+    #[serde(with = "hex_color")]
+    pub bottom_panel: Color32,
+    /// 画面に重ねる表示 (開始前の案内・FPS) の背景と文字
+    #[serde(with = "hex_color")]
+    pub overlay: Color32,
+    #[serde(with = "hex_color")]
+    pub overlay_text: Color32,
+    /// 注意を引く表示 (失敗・ミスの多い行・レイアウトの問題)
+    #[serde(with = "hex_color")]
+    pub warning: Color32,
+    /// 遅い行の強調
+    #[serde(with = "hex_color")]
+    pub highlight: Color32,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Theme::dark()
+    }
+}
+
+impl Theme {
+    pub fn dark() -> Self {
+        Theme {
+            name: "Dark".to_string(),
+            dark: true,
+            text: Color32::WHITE,
+            ruby: Color32::WHITE,
+            pending: Color32::from_rgb(0x99, 0x99, 0x99),
+            correct: Color32::from_rgb(0x90, 0x97, 0xff),
+            incorrect: Color32::from_rgb(0xff, 0x98, 0x98),
+            wrong: Color32::from_rgb(0xf5, 0x52, 0x52),
+            cursor: Color32::from_rgb(0, 92, 128),
+            background: Color32::from_rgb(6, 5, 10),
+            side_panel: Color32::from_rgb(6, 12, 22),
+            bottom_panel: Color32::from_rgb(6, 9, 15),
+            overlay: Color32::from_rgba_premultiplied(0, 0, 0, 230),
+            overlay_text: Color32::WHITE,
+            warning: Color32::from_rgb(0xff, 0x98, 0x98),
+            highlight: Color32::from_rgb(0xff, 0xc2, 0x7a),
+        }
+    }
+
+    pub fn light() -> Self {
+        Theme {
+            name: "Light".to_string(),
+            dark: false,
+            text: Color32::BLACK,
+            ruby: Color32::BLACK,
+            cursor: Color32::from_rgb(144, 209, 255),
+            background: Color32::from_rgb(243, 243, 253),
+            side_panel: Color32::from_rgb(237, 238, 222),
+            bottom_panel: Color32::from_rgb(237, 238, 222),
+            ..Theme::dark()
+        }
+    }
+
+    /// 黒地に彩度の高い色だけを使う
+    pub fn high_contrast() -> Self {
+        Theme {
+            name: "High Contrast".to_string(),
+            dark: true,
+            text: Color32::WHITE,
+            ruby: Color32::WHITE,
+            pending: Color32::from_rgb(0xc0, 0xc0, 0xc0),
+            correct: Color32::from_rgb(0x00, 0xff, 0xff),
+            incorrect: Color32::from_rgb(0xff, 0xff, 0x00),
+            wrong: Color32::from_rgb(0xff, 0x00, 0xff),
+            cursor: Color32::WHITE,
+            background: Color32::BLACK,
+            side_panel: Color32::BLACK,
+            bottom_panel: Color32::BLACK,
+            overlay: Color32::from_rgba_premultiplied(0, 0, 0, 245),
+            overlay_text: Color32::WHITE,
+            warning: Color32::from_rgb(0xff, 0xff, 0x00),
+            highlight: Color32::from_rgb(0x00, 0xff, 0xff),
+        }
+    }
+
+    /// 1型2色覚(赤)でも見分けやすい配色 (Okabe-Ito の青と黄)
+    pub fn protanopia() -> Self {
+        Theme {
+            name: "Protanopia".to_string(),
+            correct: Color32::from_rgb(0x56, 0xb4, 0xe9),
+            incorrect: Color32::from_rgb(0xf0, 0xe4, 0x42),
+            wrong: Color32::from_rgb(0xe6, 0x9f, 0x00),
+            warning: Color32::from_rgb(0xf0, 0xe4, 0x42),
+            highlight: Color32::from_rgb(0xcc, 0x79, 0xa7),
+            ..Theme::dark()
+        }
+    }
+
+    /// 2型2色覚(緑)でも見分けやすい配色 (Okabe-Ito の青と橙)
+    pub fn deuteranopia() -> Self {
+        Theme {
+            name: "Deuteranopia".to_string(),
+            correct: Color32::from_rgb(0x56, 0xb4, 0xe9),
+            incorrect: Color32::from_rgb(0xe6, 0x9f, 0x00),
+            wrong: Color32::from_rgb(0xd5, 0x5e, 0x00),
+            warning: Color32::from_rgb(0xe6, 0x9f, 0x00),
+            highlight: Color32::from_rgb(0xf0, 0xe4, 0x42),
+            ..Theme::dark()
+        }
+    }
+
+    /// ボタンなどの配色
+    pub fn visuals(&self) -> egui::Visuals {
+        if self.dark { egui::Visuals::dark() } else { egui::Visuals::light() }
+    }
+}
+
+/// 組み込みの配色
+pub fn builtin_themes() -> Vec<Theme> {
+    vec![Theme::dark(), Theme::light(), Theme::high_contrast(), Theme::protanopia(), Theme::deuteranopia()]
+}
+
+/// 配色ファイル(JSON)を読み込む
+/// 色は省略すると既定の配色のものになるが、名前は必須で、組み込みの配色と同じ名前は使えない
+/// (名前で保存・選択するので、組み込みの配色と区別できなくなる)
+pub fn parse_theme(json: &str) -> Result<Theme, String> {
+    let value = serde_json::from_str::<serde_json::Value>(json).map_err(|e| e.to_string())?;
+    if !value.get("name").is_some_and(|name| name.is_string()) {
+        return Err("missing field `name`".to_string());
+    }
+    let theme = serde_json::from_value::<Theme>(value).map_err(|e| e.to_string())?;
+    if builtin_themes().iter().any(|builtin| builtin.name == theme.name) {
+        return Err(format!("\"{}\" is the name of a built-in theme", theme.name));
+    }
+    Ok(theme)
+}
@@ -1,30 +1,34 @@
 // typing.rs
 
-use crate::model::{Model, TypingModel, ResultModel, TypingCorrectnessContent, TypingSession, TypingInput, TypingCorrectnessLine, TypingCorrectnessSegment, TypingCorrectnessChar, TypingMetrics};
+use std::collections::HashMap;
+use crate::model::{Model, TypingStartModel, TypingModel, ResultModel, TypingStatus, KeyboardRemapping, TypingScroll, TypingCorrectnessContent, TypingSession, TypingInput, TypingCorrectnessLine, TypingCorrectnessSegment, TypingCorrectnessChar, TypingMetrics, TextConvert, SpellingPreferences, SpeedUnit, RhythmMetrics, TimingEvent, TimingEventKind, TypingOptions, SegmentDisplay, Dictation, LineSelection, SessionOutcome, LineKeystrokes};
+use crate::rng::Rng;
 use crate::parser::{Content, Line, Segment};
 use crate::timestamp::now;
 
-pub fn key_input(mut model_: TypingModel, input: char) -> Model {
+/// 入力記録用の現在時刻 (UNIX時間, ミリ秒)
+pub fn current_time() -> f64 {
     #[cfg(target_arch = "wasm32")]
     let current_time = js_sys::Date::now();
     #[cfg(not(target_arch = "wasm32"))]
     let current_time = now();
+    current_time
+}
+
+/// 暗記モードで、隠したセグメントをヒントとして表示するまでのミス数
+pub const KARUTA_HINT_MISSES: i32 = 3;
+
+pub fn key_input(mut model_: TypingModel, input: char) -> Model {
+    let current_time = current_time();
+    // タイムアタックでは最後の行に入ったら次の周回を追加し、時間切れまで終わらないようにする
+    if model_.options.time_attack.is_some() && model_.status.line as usize + 1 >= model_.content.lines.len() {
+        append_round(&mut model_);
+    }
     let current_line = model_.status.line;
-    
-    // 新しいセッションを開始するかどうかを判断
-    let should_start_new_session = if model_.user_input.is_empty() {
-        true
-    } else {
-        let last_session = model_.user_input.last().unwrap();
-        if let Some(last_input) = last_session.inputs.last() {
-            // 前回の入力から1秒以上経過している場合は新しいセッション
-            (current_time - last_input.timestamp) > 1000.0
-        } else {
-            true
-        }
-    };
+    let current_segment = model_.status.segment;
 
-    if should_start_new_session {
+    // セッションは行の開始時と再開時に作られる
+    if model_.user_input.is_empty() {
         model_.user_input.push(TypingSession {
             line: current_line,
             inputs: Vec::new(),
@@ -32,7 +36,6 @@ pub fn key_input(mut model_: TypingModel, input: char) -> Model {
     }
 
     let current_session = model_.user_input.last_mut().unwrap();
-    let current_line = model_.status.line;
 
     let remaining_s = match &model_.content.lines[model_.status.line as usize].segments[model_.status.segment as usize] {
         Segment::Plain { text } => text,
@@ -42,7 +45,7 @@ pub fn key_input(mut model_: TypingModel, input: char) -> Model {
 
     let mut expect = Vec::new();
     for (key, values) in model_.layout.mapping.iter() {
-        for v in values {
+        for (rank, v) in ordered_spellings(values, model_.preferences.preferred.get(key)).into_iter().enumerate() {
             let mut flag = true;
             let start_index = model_.status.char_ as usize;
             for (i, c) in key.chars().enumerate() {
@@ -59,20 +62,26 @@ pub fn key_input(mut model_: TypingModel, input: char) -> Model {
             if !flag {
                 continue;
             }
+            // 入力途中の文字より短い綴りはもう候補にならない
+            if v.chars().count() <= model_.status.unconfirmed.len() {
+                continue;
+            }
             for i in 0..model_.status.unconfirmed.len() {
-                if model_.status.unconfirmed[i] != v.chars().nth(i).unwrap() {
+                if v.chars().nth(i) != Some(model_.status.unconfirmed[i]) {
                     flag = false;
                     break;
                 }
             }
             if flag {
-                expect.push((key,v.chars().collect::<Vec<char>>()));
+                expect.push((rank, key, v.chars().collect::<Vec<char>>()));
             }
         }
     }
+    // 優先される綴りから順に照合する
+    expect.sort_by_key(|(rank, _, _)| *rank);
     let mut is_correct = false;
     let mut is_finished = false;
-    for (key,e) in expect {
+    for (_, key, e) in expect {
         if e[model_.status.unconfirmed.len()] == input {
             is_correct = true;
             model_.status.last_wrong_keydown = None;
@@ -139,6 +148,12 @@ pub fn key_input(mut model_: TypingModel, input: char) -> Model {
         timestamp: current_time,
         is_correct,
     });
+    // 行の最初の打鍵で反応時間を記録する
+    if !model_.latencies.contains_key(&current_line) {
+        if let Some(latency) = measure_latency(&model_, current_line) {
+            model_.latencies.insert(current_line, latency);
+        }
+    }
 
     // line変更時に新しいセッションを開始
     if current_line != model_.status.line {
@@ -146,6 +161,10 @@ pub fn key_input(mut model_: TypingModel, input: char) -> Model {
             line: model_.status.line,
             inputs: Vec::new(),
         });
+        model_.timing_events.push(TimingEvent {
+            kind: if is_finished { TimingEventKind::Finish } else { TimingEventKind::LineStart(model_.status.line) },
+            timestamp: current_time,
+        });
     }
 
     if !is_correct {
@@ -154,26 +173,493 @@ pub fn key_input(mut model_: TypingModel, input: char) -> Model {
         let char_pos = model_.status.char_ as usize;
         let segment = &mut model_.typing_correctness.lines[model_.status.line as usize].segments[model_.status.segment as usize];
         segment.chars[char_pos] = TypingCorrectnessChar::Incorrect;
+        model_.status.segment_misses += 1;
+        // 暗記モードでは、ミスが続いたら隠したセグメントを見せる
+        let position = (model_.status.line, model_.status.segment);
+        let hidden = model_.options.karuta.is_some_and(|shown| position.1 as usize >= shown);
+        if hidden && model_.status.segment_misses >= KARUTA_HINT_MISSES && !model_.hints.contains(&position) {
+            model_.hints.push(position);
+        }
+    } else if (current_line, current_segment) != (model_.status.line, model_.status.segment) {
+        model_.status.segment_misses = 0;
     }
 
     if is_finished {
-        Model::Result(ResultModel {
-            typing_model: model_,
-        })
+        Model::Result(ResultModel::new(model_, SessionOutcome::Cleared))
+    } else if lives_remaining(&model_).is_some_and(|lives| lives < 0) {
+        // サバイバルの失敗
+        model_.timing_events.push(TimingEvent {
+            kind: TimingEventKind::Finish,
+            timestamp: current_time,
+        });
+        Model::Result(ResultModel::new(model_, SessionOutcome::Failed))
     } else {
         Model::Typing(model_)
     }
 }
 
+/// 仮名から綴りを引く表と、仮名の最大文字数
+/// 行ごとに何度も引くので、呼び出しごとに一度だけ作る
+struct LayoutIndex<'a> {
+    spellings: HashMap<&'a str, &'a Vec<String>>,
+    max_len: usize,
+}
+
+impl<'a> LayoutIndex<'a> {
+    fn new(layout: &'a TextConvert) -> Self {
+        let mut spellings: HashMap<&str, &Vec<String>> = HashMap::new();
+        let mut max_len = 0;
+        for (key, values) in layout.mapping.iter() {
+            spellings.entry(key.as_str()).or_insert(values);
+            max_len = max_len.max(key.chars().count());
+        }
+        LayoutIndex { spellings, max_len }
+    }
+
+    /// 各位置で最も長く一致する仮名を選び、優先される綴りに変換する
+    fn guide(&self, text: &str, preferences: &SpellingPreferences) -> String {
+        let chars = text.chars().collect::<Vec<char>>();
+        let mut guide = String::new();
+        let mut pos = 0;
+        while pos < chars.len() {
+            let mut matched = None;
+            for len in (1..=self.max_len.min(chars.len() - pos)).rev() {
+                let key = chars[pos..pos + len].iter().collect::<String>();
+                if let Some(spelling) = self.spellings.get(key.as_str()).and_then(|values| ordered_spellings(values, preferences.preferred.get(&key)).into_iter().find(|v| !v.is_empty())) {
+                    matched = Some((len, spelling));
+                    break;
+                }
+            }
+            match matched {
+                Some((len, spelling)) => {
+                    guide.push_str(spelling);
+                    pos += len;
+                },
+                None => {
+                    guide.push(chars[pos]);
+                    pos += 1;
+                },
+            }
+        }
+        guide
+    }
+
+    /// テキストの入力に最低限必要な打鍵数
+    /// 仮名の全ての区切り方(「っか」を"kka"と打つか"ltu"+"ka"と打つか等)から最短のものを求める
+    /// 入力できない文字が含まれる場合はNone
+    fn ideal_keystrokes(&self, text: &str) -> Option<i32> {
+        let chars = text.chars().collect::<Vec<char>>();
+        // dp[i]: i文字目から末尾までの最小打鍵数
+        let mut dp: Vec<Option<i32>> = vec![None; chars.len() + 1];
+        dp[chars.len()] = Some(0);
+        for pos in (0..chars.len()).rev() {
+            for len in 1..=self.max_len.min(chars.len() - pos) {
+                let rest = match dp[pos + len] {
+                    Some(rest) => rest,
+                    None => continue,
+                };
+                let key = chars[pos..pos + len].iter().collect::<String>();
+                let shortest = self.spellings.get(key.as_str())
+                    .and_then(|values| values.iter().filter(|v| !v.is_empty()).map(|v| v.chars().count() as i32).min());
+                if let Some(shortest) = shortest {
+                    let total = shortest + rest;
+                    if dp[pos].is_none_or(|best| total < best) {
+                        dp[pos] = Some(total);
+                    }
+                }
+            }
+        }
+        dp[0]
+    }
+}
+
+/// 綴りを優先順 (ユーザーの指定 → レイアウトの記述順) に並べる
+pub fn ordered_spellings<'a>(values: &'a [String], preferred: Option<&String>) -> Vec<&'a String> {
+    let mut ordered: Vec<&String> = values.iter().collect();
+    if let Some(index) = preferred.and_then(|p| ordered.iter().position(|v| *v == p)) {
+        let value = ordered.remove(index);
+        ordered.insert(0, value);
+    }
+    ordered
+}
+
+/// 文字列を優先される綴りのローマ字に変換する (ローマ字ガイド用)
+/// 各位置では最も長く一致する仮名を選ぶ
+pub fn romaji_guide(text: &str, layout: &TextConvert, preferences: &SpellingPreferences) -> String {
+    LayoutIndex::new(layout).guide(text, preferences)
+}
+
+/// 行ごとの、優先される綴りで入力した場合の打鍵数と最低限必要な打鍵数
+/// 仮名はセグメントを跨いで入力できないので、セグメントごとに求めて足す
+pub fn line_keystrokes(lines: &[Line], layout: &TextConvert, preferences: &SpellingPreferences) -> Vec<LineKeystrokes> {
+    let index = LayoutIndex::new(layout);
+    lines.iter()
+        .map(|line| {
+            let texts = line.segments.iter()
+                .map(|segment| match segment {
+                    Segment::Plain { text } => text,
+                    Segment::Annotated { base: _, reading } => reading,
+                });
+            LineKeystrokes {
+                guide: texts.clone().map(|text| index.guide(text, preferences).chars().count() as i32).sum(),
+                ideal: texts.map(|text| index.ideal_keystrokes(text)).sum(),
+            }
+        })
+        .collect()
+}
+
+/// 開始画面の状態からタイピングを始める
+pub fn start_typing(start: TypingStartModel) -> TypingModel {
+    let (selection, lines, pool, rng) = arrange_lines(&start.content, &start.options);
+    let selection_label = start.options.selection.describe(start.content.lines.len());
+    let source = start.content.clone();
+    let mut content = start.content;
+    content.lines = lines;
+    let keystrokes = line_keystrokes(&content.lines, &start.layout, &start.preferences);
+    TypingModel {
+        typing_correctness: create_typing_correctness_model(content.clone()),
+        content,
+        user_input: vec![TypingSession {
+            line: 0,
+            inputs: Vec::new(),
+        }],
+        timing_events: vec![TimingEvent {
+            kind: TimingEventKind::LineStart(0),
+            timestamp: current_time(),
+        }],
+        status: TypingStatus { line: 0, segment: 0, char_: 0, unconfirmed: Vec::new(), last_wrong_keydown: None, segment_misses: 0 },
+        available_contents: start.available_contents,
+        layout: start.layout,
+        preferences: start.preferences,
+        review: start.review,
+        options: start.options,
+        keyboard_remapping: KeyboardRemapping {
+            mapping: HashMap::new(),
+        },
+        scroll: TypingScroll {
+            scroll: start.scroll_max,
+            max: start.scroll_max,
+        },
+        repeat_mistakes: false,
+        hints: Vec::new(),
+        pool,
+        keystrokes,
+        latencies: HashMap::new(),
+        source,
+        selection,
+        selection_label,
+        rng,
+    }
+}
+
+/// 入力を誤った文字を含むセグメントを、一つずつ行にして集める (読みはそのまま)
+/// 同じセグメントは一度だけ
+pub fn mistake_lines(model: &TypingModel) -> Vec<Line> {
+    let mut lines: Vec<Line> = Vec::new();
+    for (line, correctness) in model.content.lines.iter().zip(model.typing_correctness.lines.iter()) {
+        for (segment, chars) in line.segments.iter().zip(correctness.segments.iter()) {
+            if !chars.chars.contains(&TypingCorrectnessChar::Incorrect) {
+                continue;
+            }
+            let is_duplicate = lines.iter().any(|l| match (&l.segments[0], segment) {
+                (Segment::Plain { text: a }, Segment::Plain { text: b }) => a == b,
+                (Segment::Annotated { base: a, reading: r }, Segment::Annotated { base: b, reading: s }) => a == b && r == s,
+                _ => false,
+            });
+            if !is_duplicate {
+                lines.push(Line { segments: vec![segment.clone()] });
+            }
+        }
+    }
+    lines
+}
+
+/// 出題する行を選んで並べる
+/// (選んだ行の元の問題での番号, 出題する順の行, タイムアタックで繰り返す行, この後の周回に使う乱数)
+/// 同じシードなら同じ行が同じ順に出題される
+fn arrange_lines(content: &Content, options: &TypingOptions) -> (Vec<usize>, Vec<Line>, Vec<Line>, Rng) {
+    let mut rng = Rng::new(options.selection.seed);
+    let selection = select_lines(content.lines.len(), &options.selection, &mut rng);
+    let pool = selection.iter().map(|index| content.lines[*index].clone()).collect::<Vec<Line>>();
+    let mut lines = pool.clone();
+    if options.time_attack.is_some_and(|t| t.shuffle) {
+        rng.shuffle(&mut lines);
+    }
+    (selection, lines, pool, rng)
+}
+
+/// 開始画面に表示する、最初に出題される行
+pub fn first_line(start: &TypingStartModel) -> Option<Line> {
+    arrange_lines(&start.content, &start.options).1.into_iter().next()
+}
+
+/// 出題した行の番号の記録 (1始まり。続いた番号は "3-7" のようにまとめる)
+/// 同じ記録の結果同士なら、同じ行を同じ順に入力している
+pub fn describe_selection(selection: &[usize]) -> String {
+    let mut parts: Vec<(usize, usize)> = Vec::new();
+    for &index in selection {
+        match parts.last_mut() {
+            Some((_, end)) if *end + 1 == index => *end = index,
+            _ => parts.push((index, index)),
+        }
+    }
+    parts.iter()
+        .map(|(start, end)| if start == end { format!("{}", start + 1) } else { format!("{}-{}", start + 1, end + 1) })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// 出題する行の番号を選ぶ
+/// 範囲に行が無い場合は全ての行
+pub fn select_lines(line_count: usize, selection: &LineSelection, rng: &mut Rng) -> Vec<usize> {
+    let (start, end) = selection.range
+        .map(|(start, end)| (start.min(line_count), end.min(line_count)))
+        .filter(|(start, end)| start < end)
+        .unwrap_or((0, line_count));
+    let mut indices = (start..end).collect::<Vec<usize>>();
+    if let Some(sample) = selection.sample {
+        rng.shuffle(&mut indices);
+        indices.truncate(sample.max(1));
+        if !selection.shuffle {
+            indices.sort();
+        }
+    } else if selection.shuffle {
+        rng.shuffle(&mut indices);
+    }
+    indices
+}
+
+impl ResultModel {
+    pub fn new(typing_model: TypingModel, outcome: SessionOutcome) -> Self {
+        let typed_lines = typing_model.content.lines.len().min(typing_model.status.line.max(0) as usize + 1);
+        let line_metrics = (0..typed_lines)
+            .map(|line| calculate_line_metrics(&typing_model, line as i32))
+            .collect();
+        ResultModel { typing_model, outcome, line_metrics }
+    }
+}
+
+impl LineSelection {
+    /// 結果に記録する説明 (同じ説明の結果同士なら同じ条件で比べられる)
+    pub fn describe(&self, line_count: usize) -> String {
+        let mut parts = Vec::new();
+        match self.range.filter(|(start, end)| start.min(&line_count) < end.min(&line_count)) {
+            Some((start, end)) => parts.push(format!("Lines {}-{}", start + 1, end.min(line_count))),
+            None => parts.push(format!("All {} lines", line_count)),
+        }
+        if let Some(sample) = self.sample {
+            parts.push(format!("{} random", sample.max(1)));
+        }
+        if self.shuffle {
+            parts.push("shuffled".to_string());
+        }
+        if self.shuffle || self.sample.is_some() {
+            parts.push(format!("seed {}", self.seed));
+        }
+        parts.join(", ")
+    }
+}
+
+/// タイムアタックで、問題の行をもう一周分追加する
+fn append_round(model: &mut TypingModel) {
+    let mut lines = model.pool.clone();
+    if model.options.time_attack.is_some_and(|t| t.shuffle) {
+        model.rng.shuffle(&mut lines);
+    }
+    let correctness = create_typing_correctness_model(Content {
+        title: model.content.title.clone(),
+        lines: lines.clone(),
+    });
+    model.typing_correctness.lines.extend(correctness.lines);
+    model.keystrokes.extend(line_keystrokes(&lines, &model.layout, &model.preferences));
+    model.content.lines.extend(lines);
+}
+
+/// サバイバルの残りライフ (サバイバルでなければNone)
+/// ミスが許される数を超えると負になる
+pub fn lives_remaining(model: &TypingModel) -> Option<i32> {
+    let survival = model.options.survival?;
+    let used = if survival.consecutive {
+        // 最後に続いている誤入力の数
+        model.user_input.iter().rev()
+            .flat_map(|session| session.inputs.iter().rev())
+            .take_while(|input| !input.is_correct)
+            .count() as i32
+    } else {
+        // 結果の "Mistyped" と同じく、続けた誤入力は1回と数える
+        count_inputs(model.user_input.iter()).1
+    };
+    Some(survival.lives - used)
+}
+
+/// 一時停止していた時間を除いた、開始からの経過時間 (ミリ秒)
+pub fn running_time(model: &TypingModel, now: f64) -> f64 {
+    let mut total = 0.0;
+    let mut since = None;
+    for event in &model.timing_events {
+        match event.kind {
+            TimingEventKind::LineStart(_) | TimingEventKind::Resume(_) => {
+                since.get_or_insert(event.timestamp);
+            },
+            TimingEventKind::Pause | TimingEventKind::Finish => {
+                if let Some(start) = since.take() {
+                    total += event.timestamp - start;
+                }
+            },
+        }
+    }
+    if let Some(start) = since {
+        total += now - start;
+    }
+    total
+}
+
+/// タイムアタックの残り時間 (ミリ秒, タイムアタックでなければNone)
+pub fn time_remaining(model: &TypingModel, now: f64) -> Option<f64> {
+    let time_attack = model.options.time_attack?;
+    Some((time_attack.duration - running_time(model, now)).max(0.0))
+}
+
+/// 行の各セグメントの表示方法
+/// revealedは入力済みなどで隠さなくてよいセグメント
+pub fn line_display(line: &Line, options: &TypingOptions, revealed: impl Fn(usize) -> bool) -> Vec<SegmentDisplay> {
+    (0..line.segments.len())
+        .map(|index| {
+            let hidden_by_karuta = options.karuta.is_some_and(|shown| index >= shown);
+            let hidden = hidden_by_karuta || options.dictation == Some(Dictation::Hidden);
+            if hidden && !revealed(index) {
+                SegmentDisplay::Hidden
+            } else if options.dictation == Some(Dictation::BaseOnly) {
+                SegmentDisplay::BaseOnly
+            } else {
+                SegmentDisplay::Full
+            }
+        })
+        .collect()
+}
+
+/// タイピング中の行の各セグメントの表示方法 (入力済みのセグメントとヒントは見せる)
+pub fn segment_display(model: &TypingModel, line: i32) -> Vec<SegmentDisplay> {
+    let status = &model.status;
+    line_display(&model.content.lines[line as usize], &model.options, |index| {
+        line < status.line
+            || (line == status.line && (index as i32) < status.segment)
+            || model.hints.contains(&(line, index as i32))
+    })
+}
+
+/// 暗記の成績 (隠したセグメントのうち、ヒント無しで入力できた割合)
+/// 暗記モードでない場合や、隠したセグメントをまだ入力していない場合はNone
+pub fn memorization_score(model: &TypingModel) -> Option<f64> {
+    let shown = model.options.karuta?;
+    let status = &model.status;
+    let finished = model.content.lines.iter().enumerate()
+        .flat_map(|(line, l)| (shown..l.segments.len()).map(move |segment| (line as i32, segment as i32)))
+        .filter(|(line, segment)| *line < status.line || (*line == status.line && *segment < status.segment))
+        .collect::<Vec<_>>();
+    if finished.is_empty() {
+        return None;
+    }
+    let recalled = finished.iter().filter(|position| !model.hints.contains(position)).count();
+    Some(recalled as f64 / finished.len() as f64)
+}
+
 // 一時停止から再開時の新しいセッション開始用の関数を追加
 pub fn start_new_session(mut typing_model: TypingModel) -> TypingModel {
     typing_model.user_input.push(TypingSession {
         line: typing_model.status.line,
         inputs: Vec::new(),
     });
+    typing_model.timing_events.push(TimingEvent {
+        kind: TimingEventKind::Resume(typing_model.status.line),
+        timestamp: current_time(),
+    });
+    typing_model
+}
+
+/// 一時停止・終了を記録する
+pub fn record_event(mut typing_model: TypingModel, kind: TimingEventKind) -> TypingModel {
+    typing_model.timing_events.push(TimingEvent {
+        kind,
+        timestamp: current_time(),
+    });
     typing_model
 }
 
+/// 行の最初の打鍵までの時間 (反応時間, ミリ秒)
+/// まだ打鍵していない行はNone
+pub fn line_latency(model: &TypingModel, line: i32) -> Option<f64> {
+    model.latencies.get(&line).copied()
+}
+
+/// 打鍵と開始・再開の記録から反応時間を求める
+fn measure_latency(model: &TypingModel, line: i32) -> Option<f64> {
+    let first_input = model.user_input.iter()
+        .filter(|session| session.line == line)
+        .flat_map(|session| session.inputs.first())
+        .map(|input| input.timestamp)
+        .reduce(f64::min)?;
+    // 最初の打鍵の直前に行が開始(再開)された時刻
+    // 一時停止中の時間が反応時間に含まれないよう、再開時点も行の開始とみなす
+    let started = model.timing_events.iter()
+        .filter(|event| matches!(event.kind, TimingEventKind::LineStart(l) | TimingEventKind::Resume(l) if l == line))
+        .filter(|event| event.timestamp <= first_input)
+        .map(|event| event.timestamp)
+        .reduce(f64::max)?;
+    Some(first_input - started)
+}
+
+/// 行ごとの時間の集計
+#[derive(Debug, Clone, Copy, Default)]
+struct LineTime {
+    elapsed: f64, // 経過時間
+    active: f64,  // 放置していた間隔を除いた時間
+    typing: f64,  // activeのうち、行の最初の打鍵以降の時間
+}
+
+/// 開始・一時停止・再開・終了の記録と打鍵から、行ごとの時間を集計する
+/// 一時停止中は数えず、AFK除外の閾値より長い間隔は有効時間に含めない
+fn account_time(model: &TypingModel) -> HashMap<i32, LineTime> {
+    enum Mark {
+        Start(i32),
+        Stop,
+        Input,
+    }
+    let mut marks = model.timing_events.iter()
+        .map(|event| (event.timestamp, match event.kind {
+            TimingEventKind::LineStart(line) | TimingEventKind::Resume(line) => Mark::Start(line),
+            TimingEventKind::Pause | TimingEventKind::Finish => Mark::Stop,
+        }))
+        .chain(model.user_input.iter().flat_map(|session| session.inputs.iter()).map(|input| (input.timestamp, Mark::Input)))
+        .collect::<Vec<_>>();
+    // 行の最後の打鍵と次の行の開始は同じ時刻に記録されるので、同じ時刻では打鍵を先に数える
+    // (後にすると、次の行の最初の打鍵までの待ち時間が打鍵中の時間に入ってしまう)
+    marks.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap().then_with(|| matches!(b.1, Mark::Input).cmp(&matches!(a.1, Mark::Input))));
+
+    let mut times: HashMap<i32, LineTime> = HashMap::new();
+    // 計測中の行, 直前の区切りの時刻, 開始後に打鍵があったか
+    let mut running: Option<(i32, f64, bool)> = None;
+    for (timestamp, mark) in marks {
+        if let Some((line, last, typed)) = running {
+            let gap = timestamp - last;
+            let time = times.entry(line).or_default();
+            time.elapsed += gap;
+            if model.options.afk_threshold.is_none_or(|threshold| gap <= threshold) {
+                time.active += gap;
+                if typed {
+                    time.typing += gap;
+                }
+            }
+        }
+        running = match mark {
+            Mark::Start(line) => Some((line, timestamp, false)),
+            Mark::Stop => None,
+            Mark::Input => running.map(|(line, _, _)| (line, timestamp, true)),
+        };
+    }
+    times
+}
+
 // typing正誤の記録をpending状態で新規作成する関数
 pub fn create_typing_correctness_model(content: Content) -> TypingCorrectnessContent {
     let mut lines = Vec::new();
@@ -203,9 +689,16 @@ impl TypingMetrics {
         TypingMetrics {
             miss_count: 0,
             type_count: 0,
+            elapsed_time: 0.0,
             total_time: 0.0,
+            typing_time: 0.0,
+            char_count: 0,
+            guide_count: 0,
+            ideal_count: 0,
             accuracy: 0.0,
             speed: 0.0,
+            efficiency: 0.0,
+            latency: 0.0,
         }
     }
 
@@ -214,42 +707,129 @@ impl TypingMetrics {
         if self.type_count > 0 {
             self.accuracy = 1.0 - (self.miss_count as f64 / self.type_count as f64);
         }
-        if self.total_time > 0.0 {
-            self.speed = (self.type_count as f64) / (self.total_time / 1000.0); // 秒あたりのタイプ数
+        if self.typing_time > 0.0 {
+            self.speed = (self.type_count as f64) / (self.typing_time / 1000.0); // 秒あたりのタイプ数
+        }
+    }
+
+    /// 経過時間1分あたりの数 (タイムアタックの成績用)
+    pub fn per_minute(&self, count: i32) -> f64 {
+        if self.elapsed_time > 0.0 {
+            count as f64 / (self.elapsed_time / 60000.0)
+        } else {
+            0.0
+        }
+    }
+
+    /// 指定した単位での速さ
+    pub fn speed_in(&self, unit: SpeedUnit) -> f64 {
+        let minutes = self.typing_time / 60000.0;
+        if minutes <= 0.0 {
+            return 0.0;
+        }
+        match unit {
+            SpeedUnit::KeysPerSecond => self.speed,
+            SpeedUnit::KeysPerMinute => self.type_count as f64 / minutes,
+            SpeedUnit::CharsPerMinute => self.char_count as f64 / minutes,
+            SpeedUnit::WordsPerMinute => self.char_count as f64 / 5.0 / minutes, // 5文字を1語とする
         }
     }
 }
 
-/// 特定の行のタイピング統計を計算
-pub fn calculate_line_metrics(model: &TypingModel, line: i32) -> TypingMetrics {
-    let mut metrics = TypingMetrics::new();
-    
-    // 指定された行のセッションを取得
-    let line_sessions: Vec<&TypingSession> = model.user_input.iter()
-        .filter(|session| session.line == line)
-        .collect();
+impl SessionOutcome {
+    pub fn label(&self) -> &'static str {
+        match self {
+            SessionOutcome::Cleared => "Cleared",
+            SessionOutcome::TimeUp => "Time Up",
+            SessionOutcome::Failed => "Failed",
+            SessionOutcome::Canceled => "Canceled",
+        }
+    }
+}
 
-    for session in line_sessions {
-        let mut consecutive_errors = 0;
-        
-        if let (Some(first), Some(last)) = (session.inputs.first(), session.inputs.last()) {
-            metrics.total_time += last.timestamp - first.timestamp;
+impl SpeedUnit {
+    pub const ALL: [SpeedUnit; 4] = [SpeedUnit::KeysPerSecond, SpeedUnit::KeysPerMinute, SpeedUnit::CharsPerMinute, SpeedUnit::WordsPerMinute];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            SpeedUnit::KeysPerSecond => "KPS",
+            SpeedUnit::KeysPerMinute => "KPM",
+            SpeedUnit::CharsPerMinute => "CPM",
+            SpeedUnit::WordsPerMinute => "WPM",
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            SpeedUnit::KeysPerSecond => "Keystrokes per second",
+            SpeedUnit::KeysPerMinute => "Keystrokes per minute",
+            SpeedUnit::CharsPerMinute => "Characters (kana) per minute",
+            SpeedUnit::WordsPerMinute => "Words per minute (5 characters = 1 word)",
         }
+    }
+}
+
+/// 行のうち入力を終えた文字数 (カーソルより前の文字)
+fn finished_chars(model: &TypingModel, line: i32) -> i32 {
+    let status = &model.status;
+    match model.typing_correctness.lines.get(line as usize) {
+        Some(correctness) if line < status.line => {
+            correctness.segments.iter().map(|segment| segment.chars.len() as i32).sum()
+        },
+        Some(correctness) if line == status.line => {
+            correctness.segments.iter().take(status.segment as usize).map(|segment| segment.chars.len() as i32).sum::<i32>() + status.char_
+        },
+        _ => 0,
+    }
+}
+
+/// 正解のタイプ数とミス数を数える
+/// 続けて誤入力した場合は、最初の1回だけをミスとする
+fn count_inputs<'a>(sessions: impl Iterator<Item = &'a TypingSession>) -> (i32, i32) {
+    let (mut type_count, mut miss_count) = (0, 0);
+    for session in sessions {
+        let mut consecutive_errors = 0;
 
         for input in &session.inputs {
             if input.is_correct {
-                metrics.type_count += 1;
+                type_count += 1;
                 consecutive_errors = 0;
             } else {
                 consecutive_errors += 1;
                 if consecutive_errors == 1 {  // 連続エラーの最初のみカウント
-                    metrics.miss_count += 1;
+                    miss_count += 1;
                 }
             }
         }
     }
+    (type_count, miss_count)
+}
+
+/// 特定の行のタイピング統計を計算
+pub fn calculate_line_metrics(model: &TypingModel, line: i32) -> TypingMetrics {
+    let mut metrics = TypingMetrics::new();
+    
+    // 指定された行のセッションを取得
+    let line_sessions: Vec<&TypingSession> = model.user_input.iter()
+        .filter(|session| session.line == line)
+        .collect();
 
+    (metrics.type_count, metrics.miss_count) = count_inputs(line_sessions.into_iter());
+
+    let keystrokes = model.keystrokes[line as usize];
+    metrics.guide_count = keystrokes.guide;
+    metrics.ideal_count = keystrokes.ideal.unwrap_or(0);
+    metrics.char_count = finished_chars(model, line);
+    metrics.latency = line_latency(model, line).unwrap_or(0.0);
+    let time = account_time(model).get(&line).copied().unwrap_or_default();
+    metrics.elapsed_time = time.elapsed;
+    metrics.total_time = time.active;
+    metrics.typing_time = time.typing;
     metrics.calculate();
+    // 入力を終えた行のみ効率を求める (入力できない文字を含む行は求めない)
+    if line < model.status.line && keystrokes.ideal.is_some() && metrics.type_count > 0 {
+        metrics.efficiency = metrics.ideal_count as f64 / metrics.type_count as f64;
+    }
     metrics
 }
 
@@ -257,26 +837,217 @@ pub fn calculate_line_metrics(model: &TypingModel, line: i32) -> TypingMetrics {
 pub fn calculate_total_metrics(model: &TypingModel) -> TypingMetrics {
     let mut metrics = TypingMetrics::new();
 
-    for session in &model.user_input {
-        let mut consecutive_errors = 0;
-        
-        if let (Some(first), Some(last)) = (session.inputs.first(), session.inputs.last()) {
-            metrics.total_time += last.timestamp - first.timestamp;
+    (metrics.type_count, metrics.miss_count) = count_inputs(model.user_input.iter());
+
+    // 入力を終えた行のみ
+    let finished_lines = &model.keystrokes[..(model.status.line as usize).min(model.keystrokes.len())];
+    metrics.guide_count = finished_lines.iter().map(|keystrokes| keystrokes.guide).sum();
+    // 効率は最小打鍵数を求められた行だけで比べる
+    metrics.ideal_count = finished_lines.iter().filter_map(|keystrokes| keystrokes.ideal).sum();
+    let finished_type_count = model.user_input.iter()
+        .filter(|session| finished_lines.get(session.line as usize).is_some_and(|keystrokes| keystrokes.ideal.is_some()))
+        .flat_map(|session| session.inputs.iter())
+        .filter(|input| input.is_correct)
+        .count();
+    if finished_type_count > 0 {
+        metrics.efficiency = metrics.ideal_count as f64 / finished_type_count as f64;
+    }
+    metrics.char_count = (0..=model.status.line).map(|line| finished_chars(model, line)).sum();
+    if !model.latencies.is_empty() {
+        metrics.latency = model.latencies.values().sum::<f64>() / model.latencies.len() as f64;
+    }
+    for time in account_time(model).values() {
+        metrics.elapsed_time += time.elapsed;
+        metrics.total_time += time.active;
+        metrics.typing_time += time.typing;
+    }
+    metrics.calculate();
+    metrics
+}
+
+/// 打鍵間隔からリズムの統計を計算
+/// 間隔はセッション内の連続した入力の間でのみ測る (行の切り替えや一時停止を挟んだ間隔は含めない)
+/// AFK除外の閾値より長い間隔も含めない
+fn calculate_rhythm<'a>(sessions: impl Iterator<Item = &'a TypingSession>, afk_threshold: Option<f64>) -> RhythmMetrics {
+    let mut metrics = RhythmMetrics::default();
+    let mut intervals = Vec::new();
+    let mut correct_times = Vec::new();
+    for session in sessions {
+        for pair in session.inputs.windows(2) {
+            let interval = pair[1].timestamp - pair[0].timestamp;
+            if afk_threshold.is_none_or(|threshold| interval <= threshold) {
+                intervals.push(interval);
+            }
         }
+        correct_times.extend(session.inputs.iter().filter(|input| input.is_correct).map(|input| input.timestamp));
+    }
 
-        for input in &session.inputs {
-            if input.is_correct {
-                metrics.type_count += 1;
-                consecutive_errors = 0;
-            } else {
-                consecutive_errors += 1;
-                if consecutive_errors == 1 {  // 連続エラーの最初のみカウント
-                    metrics.miss_count += 1;
+    if !intervals.is_empty() {
+        intervals.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let percentile = |p: f64| intervals[((intervals.len() - 1) as f64 * p).round() as usize];
+        let mean = intervals.iter().sum::<f64>() / intervals.len() as f64;
+        metrics.median_interval = percentile(0.5);
+        metrics.p90_interval = percentile(0.9);
+        metrics.std_dev = (intervals.iter().map(|i| (i - mean).powi(2)).sum::<f64>() / intervals.len() as f64).sqrt();
+        metrics.longest_pause = *intervals.last().unwrap();
+    }
+
+    // 10秒間の窓に入る正解打鍵の間隔の数の最大値 (N打鍵の間隔はN-1)
+    let window = 10000.0;
+    correct_times.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    if let (Some(first), Some(last)) = (correct_times.first(), correct_times.last()) {
+        let span = last - first;
+        if span >= window {
+            let mut best = 0;
+            let mut start = 0;
+            for end in 0..correct_times.len() {
+                while correct_times[end] - correct_times[start] > window {
+                    start += 1;
                 }
+                best = best.max(end - start);
             }
+            metrics.burst_speed = best as f64 / (window / 1000.0);
+        } else if span >= MIN_BURST_SPAN {
+            // 全体が10秒に満たない場合は実際の時間で割る (短すぎると数打鍵で極端な値になるので出さない)
+            metrics.burst_speed = (correct_times.len() - 1) as f64 / (span / 1000.0);
         }
     }
-
-    metrics.calculate();
     metrics
-}
\ No newline at end of file
+}
+
+/// 瞬間速度を出すのに必要な最短の打鍵時間 (ミリ秒)
+const MIN_BURST_SPAN: f64 = 2000.0;
+
+impl RhythmMetrics {
+    /// 瞬間速度を指定した単位で表す
+    /// 文字数の単位では、`metrics` の打鍵数と文字数の比で打鍵数を文字数に直す
+    pub fn burst_speed_in(&self, unit: SpeedUnit, metrics: &TypingMetrics) -> f64 {
+        let chars_per_key = if metrics.type_count > 0 { metrics.char_count as f64 / metrics.type_count as f64 } else { 0.0 };
+        match unit {
+            SpeedUnit::KeysPerSecond => self.burst_speed,
+            SpeedUnit::KeysPerMinute => self.burst_speed * 60.0,
+            SpeedUnit::CharsPerMinute => self.burst_speed * 60.0 * chars_per_key,
+            SpeedUnit::WordsPerMinute => self.burst_speed * 60.0 * chars_per_key / 5.0, // 5文字を1語とする
+        }
+    }
+}
+
+/// 特定の行の打鍵リズムを計算
+pub fn calculate_line_rhythm(model: &TypingModel, line: i32) -> RhythmMetrics {
+    calculate_rhythm(model.user_input.iter().filter(|session| session.line == line), model.options.afk_threshold)
+}
+
+/// 全体の打鍵リズムを計算
+pub fn calculate_total_rhythm(model: &TypingModel) -> RhythmMetrics {
+    calculate_rhythm(model.user_input.iter(), model.options.afk_threshold)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::parse_layout;
+    use crate::parser::parse_problem;
+    use crate::model::ReviewSchedule;
+
+    fn start(layout: &str, problem: &str) -> TypingModel {
+        let content = parse_problem(problem);
+        start_typing(TypingStartModel {
+            content: content.clone(),
+            available_contents: vec![content],
+            layout: parse_layout(layout).unwrap(),
+            preferences: SpellingPreferences::default(),
+            review: ReviewSchedule::default(),
+            options: TypingOptions::default(),
+            scroll_max: 0.0,
+        })
+    }
+
+    fn type_keys(mut model: TypingModel, keys: &str) -> Model {
+        for key in keys.chars() {
+            match key_input(model, key) {
+                Model::Typing(next) => model = next,
+                other => return other,
+            }
+        }
+        Model::Typing(model)
+    }
+
+    #[test]
+    fn shadowed_spelling_is_skipped() {
+        // 「nn」を優先していると「n」は確定しないが、続けて入力しても止まらない
+        let model = start(r#"{"ん":["nn","n"],"あ":["a"]}"#, "#title test\nんあ");
+        match type_keys(model, "na") {
+            Model::Typing(model) => {
+                assert_eq!(model.status.char_, 0);
+                assert_eq!(model.status.unconfirmed, vec!['n']);
+            },
+            _ => panic!("typing should continue"),
+        }
+    }
+
+    #[test]
+    fn ideal_keystrokes_take_the_shortest_split() {
+        let layout = parse_layout(r#"{"っ":["ltu"],"か":["ka"],"っか":["kka"]}"#).unwrap();
+        let index = LayoutIndex::new(&layout);
+        assert_eq!(index.ideal_keystrokes("っか"), Some(3));
+        assert_eq!(index.ideal_keystrokes("っ"), Some(3));
+        assert_eq!(index.ideal_keystrokes("かa"), None);
+    }
+
+    #[test]
+    fn untypeable_lines_have_no_ideal_keystrokes() {
+        let model = start(r#"{"か":["ka"]}"#, "#title test\nか\nかX");
+        assert_eq!(model.keystrokes[1].guide, 3);
+        assert_eq!(model.keystrokes[1].ideal, None);
+        let model = match type_keys(model, "kxa") {
+            Model::Typing(model) => model,
+            _ => panic!("typing should continue"),
+        };
+        let metrics = calculate_total_metrics(&model);
+        assert_eq!(metrics.ideal_count, 2);
+        assert_eq!(metrics.guide_count, 2);
+        assert_eq!(metrics.efficiency, 1.0);
+    }
+
+    #[test]
+    fn preview_and_record_follow_the_selection() {
+        let content = parse_problem("#title test\nあ\nい\nう\nえ\nお");
+        let options = TypingOptions {
+            selection: LineSelection { range: Some((1, 5)), sample: None, shuffle: true, seed: 7 },
+            ..Default::default()
+        };
+        let start = TypingStartModel {
+            content: content.clone(),
+            available_contents: vec![content],
+            layout: parse_layout("{}").unwrap(),
+            preferences: SpellingPreferences::default(),
+            review: ReviewSchedule::default(),
+            options,
+            scroll_max: 0.0,
+        };
+        let first = first_line(&start).unwrap().to_string();
+        let model = start_typing(start);
+        assert_eq!(first, model.content.lines[0].to_string());
+        assert!(model.selection.iter().all(|index| (1..5).contains(index)));
+        assert_eq!(describe_selection(&[0, 1, 2, 5, 4]), "1-3,6,5");
+    }
+
+    #[test]
+    fn lives_count_misses_like_the_result() {
+        let mut model = start(r#"{"か":["ka"]}"#, "#title test\nか");
+        model.options.survival = Some(crate::model::Survival { lives: 1, consecutive: false });
+        match type_keys(model, "xxxxx") {
+            Model::Typing(model) => {
+                assert_eq!(calculate_total_metrics(&model).miss_count, 1);
+                assert_eq!(lives_remaining(&model), Some(0));
+            },
+            _ => panic!("one run of wrong keys is a single miss"),
+        }
+    }
+
+    #[test]
+    fn empty_spelling_is_skipped() {
+        let model = start(r#"{"ちぃ":["tyi","","cyi"]}"#, "#title test\nちぃ");
+        assert!(matches!(type_keys(model, "cyi"), Model::Result(_)));
+    }
+}
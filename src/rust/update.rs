@@ -1,10 +1,10 @@
 // update.rs
 
 // resource manager
-use std::collections::HashMap;
-use crate::model::{Model, MenuModel, TypingStartModel, TypingModel, PauseModel, ResultModel, TypingStatus, TextConvert, ErrorMsg, KeyboardRemapping, TypingScroll,TypingSession};
-use crate::msg::{Msg, MenuMsg, TypingStartMsg, TypingMsg, PauseMsg, ResultMsg};
-use crate::parser::{parse_problem, Content};
+use crate::model::{Model, MenuModel, TypingStartModel, TypingModel, PauseModel, ResultModel, LayoutEditorModel, TextConvert, ErrorMsg, TypingScroll, TimingEventKind, TypingOptions, LineSelection, SessionOutcome};
+use crate::msg::{Msg, MenuMsg, TypingStartMsg, TypingMsg, PauseMsg, ResultMsg, LayoutEditorMsg};
+use crate::layout::detect_conflicts;
+use crate::parser::{parse_problem, Content, Line, Segment};
 use crate::typing;
 use crate::typing::key_input;
 
@@ -20,6 +20,28 @@ macro_rules! jsvalue {
     }};
 }
 
+/// 終了したタイピングの成績を復習の予定に反映する
+fn record_review(mut typing_model: TypingModel) -> TypingModel {
+    let mut review = std::mem::take(&mut typing_model.review);
+    review.record(&typing_model, typing::current_time());
+    typing_model.review = review;
+    typing_model
+}
+
+/// 終えたタイピングから、`lines` だけを練習する問題を用意する
+/// 別の問題なので行の選び方は引き継がない
+fn drill_start(finished: TypingModel, title: Line, lines: Vec<Line>) -> TypingStartModel {
+    TypingStartModel {
+        content: Content { title, lines },
+        layout: finished.layout,
+        preferences: finished.preferences,
+        review: finished.review,
+        options: TypingOptions { selection: LineSelection::default(), ..finished.options },
+        available_contents: finished.available_contents,
+        scroll_max: finished.scroll.max,
+    }
+}
+
 pub fn update(model: Model, msg: Msg) -> Model {
     let updated_model = match (model, msg) {
         (Model::Menu(_menu_model), Msg::Menu(menu_msg)) => {
@@ -34,10 +56,53 @@ pub fn update(model: Model, msg: Msg) -> Model {
                     Model::TypingStart(TypingStartModel {
                         content: _menu_model.available_contents[_menu_model.selecting].clone(),
                         layout: _menu_model.layout,
+                        preferences: _menu_model.preferences,
+                        review: _menu_model.review,
+                        // 別の問題なので行の選び方は引き継がない
+                        options: TypingOptions { selection: LineSelection::default(), .._menu_model.options },
                         available_contents: _menu_model.available_contents,
                         scroll_max: 0.0,
                     })
                 },
+                MenuMsg::StartReview => {
+                    let lines = _menu_model.review.due_lines(&_menu_model.available_contents, typing::current_time());
+                    if lines.is_empty() {
+                        Model::Menu(_menu_model)
+                    } else {
+                        Model::TypingStart(TypingStartModel {
+                            content: Content {
+                                title: Line { segments: vec![Segment::Plain { text: "Review".to_string() }] },
+                                lines,
+                            },
+                            layout: _menu_model.layout,
+                            preferences: _menu_model.preferences,
+                            review: _menu_model.review,
+                            // 別の問題なので行の選び方は引き継がない
+                            options: TypingOptions { selection: LineSelection::default(), .._menu_model.options },
+                            available_contents: _menu_model.available_contents,
+                            scroll_max: 0.0,
+                        })
+                    }
+                },
+                MenuMsg::SetOptions(options) => {
+                    Model::Menu(MenuModel {
+                        options,
+                        .._menu_model
+                    })
+                },
+                MenuMsg::OpenLayoutEditor => {
+                    Model::LayoutEditor(LayoutEditorModel {
+                        options: _menu_model.options,
+                        conflicts: detect_conflicts(&_menu_model.layout, &_menu_model.preferences),
+                        original_layout: _menu_model.layout.clone(),
+                        layout: _menu_model.layout,
+                        original_preferences: _menu_model.preferences.clone(),
+                        preferences: _menu_model.preferences,
+                        review: _menu_model.review,
+                        available_contents: _menu_model.available_contents,
+                        selecting: 0,
+                    })
+                },
                 MenuMsg::AddContent(file_content) => {
                     let content = parse_problem(&file_content);
                     let mut new_contents = _menu_model.available_contents;
@@ -52,33 +117,25 @@ pub fn update(model: Model, msg: Msg) -> Model {
         (Model::TypingStart(_typing_start_model), Msg::TypingStart(typing_start_msg)) => {
             match typing_start_msg {
                 TypingStartMsg::StartTyping => {
-                    Model::Typing(TypingModel {
-                        content: _typing_start_model.clone().content,
-                        typing_correctness: typing::create_typing_correctness_model(_typing_start_model.content),
-                        user_input: vec![TypingSession {
-                            line: 0,
-                            inputs: Vec::new(),
-                        }],
-                        status: TypingStatus { line: 0, segment: 0, char_: 0, unconfirmed: Vec::new(), last_wrong_keydown: None },
-                        available_contents: _typing_start_model.available_contents,
-                        layout: _typing_start_model.layout,
-                        keyboard_remapping: KeyboardRemapping {
-                            mapping: HashMap::new(),
-                        },
-                        scroll: TypingScroll {
-                            scroll: _typing_start_model.scroll_max,
-                            max: _typing_start_model.scroll_max,
-                        },
-                    })
+                    Model::Typing(typing::start_typing(_typing_start_model))
                 },
                 TypingStartMsg::Cancel => {
                     Model::Menu(MenuModel {
                         available_contents: _typing_start_model.available_contents,
                         selecting: 0,
                         layout: _typing_start_model.layout,
+                        preferences: _typing_start_model.preferences,
+                        review: _typing_start_model.review,
+                        options: _typing_start_model.options,
                         error_messages: vec![],
                     })
                 },
+                TypingStartMsg::SetOptions(options) => {
+                    Model::TypingStart(TypingStartModel {
+                        options,
+                        .._typing_start_model
+                    })
+                },
                 TypingStartMsg::ScrollMax(max) => {
                     Model::TypingStart(TypingStartModel {
                         scroll_max: max,
@@ -90,11 +147,39 @@ pub fn update(model: Model, msg: Msg) -> Model {
         (Model::Typing(typing_model), Msg::Typing(typing_msg)) => {
             match typing_msg {
                 TypingMsg::KeyInput(input) => {
-                    key_input(typing_model,input)
+                    match key_input(typing_model,input) {
+                        Model::Result(result_model) => {
+                            let finished = record_review(result_model.typing_model);
+                            // 誤りのあった部分だけでもう一度
+                            let lines = if finished.repeat_mistakes { typing::mistake_lines(&finished) } else { Vec::new() };
+                            if lines.is_empty() || result_model.outcome != SessionOutcome::Cleared {
+                                // 復習の予定を記録しただけなので、行ごとの成績は集計し直さない
+                                Model::Result(ResultModel {
+                                    typing_model: finished,
+                                    ..result_model
+                                })
+                            } else {
+                                let title = finished.content.title.clone();
+                                Model::Typing(TypingModel {
+                                    repeat_mistakes: true,
+                                    ..typing::start_typing(drill_start(finished, title, lines))
+                                })
+                            }
+                        },
+                        m => m,
+                    }
+                },
+                TypingMsg::Tick => {
+                    // タイムアタックの時間切れ
+                    if typing::time_remaining(&typing_model, typing::current_time()) == Some(0.0) {
+                        Model::Result(ResultModel::new(record_review(typing::record_event(typing_model, TimingEventKind::Finish)), SessionOutcome::TimeUp))
+                    } else {
+                        Model::Typing(typing_model)
+                    }
                 },
                 TypingMsg::Pause => {
                     Model::Pause(PauseModel {
-                        typing_model,
+                        typing_model: typing::record_event(typing_model, TimingEventKind::Pause),
                     })
                 },
                 TypingMsg::ScrollTo(input,max) => {
@@ -115,9 +200,7 @@ pub fn update(model: Model, msg: Msg) -> Model {
                     Model::Typing(typing::start_new_session(pause_model.typing_model))
                 },
                 PauseMsg::Cancel => {
-                    Model::Result(ResultModel {
-                        typing_model: pause_model.typing_model,
-                    })
+                    Model::Result(ResultModel::new(record_review(typing::record_event(pause_model.typing_model, TimingEventKind::Finish)), SessionOutcome::Canceled))
                 },
             }
         },
@@ -128,18 +211,145 @@ pub fn update(model: Model, msg: Msg) -> Model {
                         available_contents: _result_model.typing_model.available_contents,
                         selecting: 0,
                         layout: _result_model.typing_model.layout,
+                        preferences: _result_model.typing_model.preferences,
+                        review: _result_model.typing_model.review,
+                        options: _result_model.typing_model.options,
                         error_messages: vec![],
                     })
                 },
                 ResultMsg::Retry => {
+                    // 選んだ後の行から選び直すと絞り込みが重なるので、選ぶ前の問題から同じ条件で選び直す
                     Model::TypingStart(TypingStartModel {
-                        content: _result_model.typing_model.content,
+                        content: _result_model.typing_model.source,
                         layout: _result_model.typing_model.layout,
+                        preferences: _result_model.typing_model.preferences,
+                        review: _result_model.typing_model.review,
+                        options: _result_model.typing_model.options,
                         available_contents: _result_model.typing_model.available_contents,
                         scroll_max: 0.0,
                     })
                 },
+                ResultMsg::DrillMistakes => {
+                    // 誤りのあった部分を集めて、すぐに始める
+                    let lines = typing::mistake_lines(&_result_model.typing_model);
+                    if lines.is_empty() {
+                        Model::Result(_result_model)
+                    } else {
+                        let finished = _result_model.typing_model;
+                        let mut title = finished.content.title.segments.clone();
+                        title.push(Segment::Plain { text: " (Mistakes)".to_string() });
+                        let mut start = drill_start(finished, Line { segments: title }, lines);
+                        // 誤りが無くなるまで繰り返すので、タイムアタックにはしない
+                        start.options.time_attack = None;
+                        Model::Typing(TypingModel {
+                            repeat_mistakes: true,
+                            ..typing::start_typing(start)
+                        })
+                    }
+                },
+                ResultMsg::DrillLine(line) => {
+                    // その行だけの問題を作って練習する
+                    match _result_model.typing_model.content.lines.get(line).cloned() {
+                        Some(target) => {
+                            let mut title = _result_model.typing_model.content.title.segments.clone();
+                            title.push(Segment::Plain { text: format!(" ({})", line + 1) });
+                            Model::TypingStart(TypingStartModel {
+                                scroll_max: 0.0,
+                                ..drill_start(_result_model.typing_model, Line { segments: title }, vec![target])
+                            })
+                        },
+                        None => Model::Result(_result_model),
+                    }
+                },
+            }
+        },
+        (Model::LayoutEditor(mut editor_model), Msg::LayoutEditor(editor_msg)) => {
+            let selecting = editor_model.selecting;
+            match editor_msg {
+                LayoutEditorMsg::Select(index) => {
+                    if index < editor_model.layout.mapping.len() {
+                        editor_model.selecting = index;
+                    }
+                },
+                LayoutEditorMsg::AddEntry(key) => {
+                    // 既に存在する仮名なら、そのエントリを選択するだけ
+                    if let Some(index) = editor_model.layout.mapping.iter().position(|(k, _)| *k == key) {
+                        editor_model.selecting = index;
+                    } else if !key.is_empty() {
+                        editor_model.layout.mapping.push((key, Vec::new()));
+                        editor_model.selecting = editor_model.layout.mapping.len() - 1;
+                    }
+                },
+                LayoutEditorMsg::RemoveEntry => {
+                    if selecting < editor_model.layout.mapping.len() {
+                        editor_model.layout.mapping.remove(selecting);
+                        editor_model.selecting = selecting.min(editor_model.layout.mapping.len().saturating_sub(1));
+                    }
+                },
+                LayoutEditorMsg::AddSpelling(spelling) => {
+                    if let Some((_, values)) = editor_model.layout.mapping.get_mut(selecting) {
+                        if !spelling.is_empty() && !values.contains(&spelling) {
+                            values.push(spelling);
+                        }
+                    }
+                },
+                LayoutEditorMsg::RemoveSpelling(index) => {
+                    if let Some((_, values)) = editor_model.layout.mapping.get_mut(selecting) {
+                        if index < values.len() {
+                            values.remove(index);
+                        }
+                    }
+                },
+                LayoutEditorMsg::MoveSpellingUp(index) => {
+                    if let Some((_, values)) = editor_model.layout.mapping.get_mut(selecting) {
+                        if index > 0 && index < values.len() {
+                            values.swap(index - 1, index);
+                        }
+                    }
+                },
+                LayoutEditorMsg::MoveSpellingDown(index) => {
+                    if let Some((_, values)) = editor_model.layout.mapping.get_mut(selecting) {
+                        if index + 1 < values.len() {
+                            values.swap(index, index + 1);
+                        }
+                    }
+                },
+                LayoutEditorMsg::SetPreferred(index) => {
+                    if let Some((key, values)) = editor_model.layout.mapping.get(selecting) {
+                        match index.and_then(|i| values.get(i)) {
+                            Some(value) => { editor_model.preferences.preferred.insert(key.clone(), value.clone()); },
+                            None => { editor_model.preferences.preferred.remove(key); },
+                        }
+                    }
+                },
+                LayoutEditorMsg::Apply if editor_model.conflicts.iter().any(|c| c.is_blocking()) => {
+                    // 入力中に候補を照合できなくなるので使わせない
+                },
+                LayoutEditorMsg::Apply => {
+                    return Model::Menu(MenuModel {
+                        available_contents: editor_model.available_contents,
+                        selecting: 0,
+                        layout: editor_model.layout,
+                        preferences: editor_model.preferences,
+                        review: editor_model.review,
+                        options: editor_model.options,
+                        error_messages: vec![],
+                    });
+                },
+                LayoutEditorMsg::Cancel => {
+                    return Model::Menu(MenuModel {
+                        available_contents: editor_model.available_contents,
+                        selecting: 0,
+                        layout: editor_model.original_layout,
+                        preferences: editor_model.original_preferences,
+                        review: editor_model.review,
+                        options: editor_model.options,
+                        error_messages: vec![],
+                    });
+                },
             }
+            editor_model.conflicts = detect_conflicts(&editor_model.layout, &editor_model.preferences);
+            Model::LayoutEditor(editor_model)
         },
         (m, _) => m,
     };